@@ -1,8 +1,7 @@
-use multicast_socket::MulticastSocket;
-use std::net::SocketAddrV4;
+use multicast_socket::{groups, MulticastSocket};
 
 fn main() {
-    let mdns_multicast_address = SocketAddrV4::new([224, 0, 0, 251].into(), 5353);
+    let mdns_multicast_address = groups::mdns();
 
     // Validate that building with options works with the public API
     let with_options = MulticastSocket::with_options(
@@ -0,0 +1,27 @@
+use multicast_socket::ping::MulticastPing;
+use multicast_socket::MulticastSocket;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+
+fn main() {
+    let multicast_address = SocketAddrV4::new([239, 255, 42, 99].into(), 4242);
+    let socket = MulticastSocket::all_interfaces(multicast_address)
+        .expect("could not create and bind socket");
+
+    // Run it on several hosts: each one answers the others' probes while pinging
+    let mut ping = MulticastPing::new(&socket);
+    loop {
+        let reports = ping
+            .measure(5, Duration::from_millis(400))
+            .expect("could not ping");
+        for report in reports {
+            println!("{}: {} echoes", report.interface, report.echoes.len());
+            for rtt in report.rtt() {
+                println!(
+                    "  {} in {:?} (min {:?}, max {:?}), jitter {:?}, {} lost",
+                    rtt.responder, rtt.mean, rtt.min, rtt.max, rtt.jitter, rtt.lost
+                );
+            }
+        }
+    }
+}
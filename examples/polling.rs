@@ -0,0 +1,36 @@
+use std::time::Duration;
+
+use multicast_socket::{groups, MulticastSocket};
+use polling::{Event, Events, Poller};
+
+fn main() {
+    let socket =
+        MulticastSocket::all_interfaces(groups::mdns()).expect("could not create and bind socket");
+
+    let poller = Poller::new().expect("could not create poller");
+    // Level triggered: wakes up while datagrams are queued, re-armed after each wait
+    unsafe { poller.add(&socket, Event::readable(0)) }.expect("could not register socket");
+
+    socket
+        .broadcast(&[1, 2])
+        .expect("could not broadcast message to ips being listened");
+
+    let mut events = Events::new();
+    loop {
+        events.clear();
+        poller
+            .wait(&mut events, Some(Duration::from_secs(1)))
+            .expect("could not wait");
+
+        for _ in events.iter() {
+            // A wakeup may only carry datagrams dropped by the filters, so don't block
+            for message in socket.drain().expect("could not receive") {
+                dbg!(&message.interface);
+                dbg!(&message.origin_address);
+            }
+            poller
+                .modify(&socket, Event::readable(0))
+                .expect("could not re-arm socket");
+        }
+    }
+}
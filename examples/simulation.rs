@@ -0,0 +1,40 @@
+use multicast_socket::groups;
+use multicast_socket::sim::{LinkConfig, SimNetwork};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+fn main() {
+    let mdns_multicast_address = groups::mdns();
+
+    // Same seed, same losses and same ordering on every run
+    let network = SimNetwork::new(42);
+    let lan = network.add_link(LinkConfig {
+        latency: Duration::from_millis(2),
+        jitter: Duration::from_millis(5),
+        loss: 0.2,
+    });
+
+    let alice = Ipv4Addr::new(192, 168, 0, 10);
+    let bob = Ipv4Addr::new(192, 168, 0, 20);
+    network.attach(lan, alice).unwrap();
+    network.attach(lan, bob).unwrap();
+
+    let options = || multicast_socket::MulticastOptions {
+        loopback: false,
+        ..Default::default()
+    };
+    let alice = network
+        .socket(mdns_multicast_address, vec![alice], options())
+        .unwrap();
+    let bob = network
+        .socket(mdns_multicast_address, vec![bob], options())
+        .unwrap();
+
+    for i in 0..10u8 {
+        alice.broadcast(&[i]).unwrap();
+    }
+
+    while let Ok(message) = bob.receive() {
+        println!("{:?} at {:?}", message.data, network.now());
+    }
+}
@@ -0,0 +1,59 @@
+/* C bindings of multicast-socket, built with the `ffi` feature, see src/ffi.rs */
+
+#ifndef MULTICAST_SOCKET_H
+#define MULTICAST_SOCKET_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define MULTICAST_SOCKET_OK 0
+/* Nothing arrived within the read timeout */
+#define MULTICAST_SOCKET_TIMEOUT -1
+#define MULTICAST_SOCKET_ERROR -2
+
+#define MULTICAST_SOCKET_INTERFACE_DEFAULT 0
+#define MULTICAST_SOCKET_INTERFACE_IP 1
+#define MULTICAST_SOCKET_INTERFACE_INDEX 2
+
+typedef struct MulticastSocket MulticastSocket;
+
+/* Addresses are in host byte order, as ntohl returns them */
+typedef struct MulticastSocketInterface {
+    uint32_t kind;
+    uint32_t value;
+} MulticastSocketInterface;
+
+typedef struct MulticastSocketMessage {
+    size_t len;
+    bool truncated;
+    uint32_t origin_address;
+    uint16_t origin_port;
+    MulticastSocketInterface interface;
+    bool has_packet_info;
+    uint32_t destination;
+} MulticastSocketMessage;
+
+/* Joins on every interface when interface_count is 0, returns NULL on failure */
+MulticastSocket *multicast_socket_new(uint32_t group, uint16_t port, const uint32_t *interfaces,
+                                      size_t interface_count, uint32_t read_timeout_ms);
+int multicast_socket_receive(const MulticastSocket *socket, uint8_t *buffer, size_t buffer_len,
+                             MulticastSocketMessage *message);
+int multicast_socket_send(const MulticastSocket *socket, const uint8_t *data, size_t len,
+                          MulticastSocketInterface interface);
+int multicast_socket_broadcast(const MulticastSocket *socket, const uint8_t *data, size_t len);
+void multicast_socket_destroy(MulticastSocket *socket);
+
+/* Errors are kept per thread, like errno */
+size_t multicast_socket_last_error(char *buffer, size_t buffer_len);
+int multicast_socket_last_os_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,201 @@
+//! Coalescing small sends into batches, to cut the syscalls of chatty publishers. On Linux each
+//! batch goes out with one `sendmmsg` call per run of datagrams on the same interface, elsewhere
+//! one datagram at a time.
+//!
+//! A batch is sent once it holds `max_packets` datagrams, or `max_delay` after its first one was
+//! queued, from a thread of the `SendBatcher`, which bounds the latency batching adds.
+
+use std::io;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::{Interface, MulticastSocket};
+
+struct State {
+    batch: Vec<(Vec<u8>, Interface)>,
+    // When the first datagram of the batch was queued
+    started: Option<Instant>,
+    closed: bool,
+    // First send error not reported yet
+    error: Option<io::Error>,
+}
+
+struct Shared {
+    socket: Arc<MulticastSocket>,
+    max_packets: usize,
+    max_delay: Duration,
+    state: Mutex<State>,
+    queued: Condvar,
+    // Held while a batch is sent, so batches go out in the order they were queued
+    sending: Mutex<()>,
+}
+
+/// Queues datagrams for `MulticastSocket::batched`. Dropping it sends what is still queued.
+pub struct SendBatcher {
+    shared: Arc<Shared>,
+    flusher: Option<JoinHandle<()>>,
+}
+
+impl MulticastSocket {
+    /// Sends the datagrams queued on the returned batcher in batches of up to `max_packets`,
+    /// each sent at the latest `max_delay` after its first datagram was queued. They go out
+    /// through the socket itself, even with `dedicated_send_sockets`.
+    /// Fails with `InvalidInput` when `max_packets` is 0.
+    pub fn batched(
+        self: Arc<Self>,
+        max_packets: usize,
+        max_delay: Duration,
+    ) -> io::Result<SendBatcher> {
+        if max_packets == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a batch needs room for at least one datagram",
+            ));
+        }
+        let shared = Arc::new(Shared {
+            socket: self,
+            max_packets,
+            max_delay,
+            state: Mutex::new(State {
+                batch: Vec::with_capacity(max_packets),
+                started: None,
+                closed: false,
+                error: None,
+            }),
+            queued: Condvar::new(),
+            sending: Mutex::new(()),
+        });
+
+        let thread_shared = shared.clone();
+        let flusher = thread::Builder::new()
+            .name("multicast-batch".to_owned())
+            .spawn(move || thread_shared.flush_batches())?;
+        Ok(SendBatcher {
+            shared,
+            flusher: Some(flusher),
+        })
+    }
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn flush_batches(&self) {
+        loop {
+            // Waits for a full or due batch without holding `sending`, which `flush` takes first
+            let mut state = self.state();
+            loop {
+                match state.started {
+                    None if state.closed => return,
+                    None => state = self.queued.wait(state).unwrap_or_else(|e| e.into_inner()),
+                    Some(_) if state.closed || state.batch.len() >= self.max_packets => break,
+                    Some(started) => {
+                        let wait =
+                            (started + self.max_delay).saturating_duration_since(Instant::now());
+                        if wait.is_zero() {
+                            break;
+                        }
+                        state = self
+                            .queued
+                            .wait_timeout(state, wait)
+                            .unwrap_or_else(|e| e.into_inner())
+                            .0;
+                    }
+                }
+            }
+            drop(state);
+
+            // Empty when `flush` took the batch in between
+            let _sending = self.sending.lock().unwrap_or_else(|e| e.into_inner());
+            let batch = self.take_batch(&mut self.state());
+            self.send(&batch);
+        }
+    }
+
+    /// Up to `max_packets` datagrams. The ones left over stay due when the batch was, so none
+    /// waits longer than `max_delay`.
+    fn take_batch(&self, state: &mut State) -> Vec<(Vec<u8>, Interface)> {
+        if state.batch.len() > self.max_packets {
+            let rest = state.batch.split_off(self.max_packets);
+            return mem::replace(&mut state.batch, rest);
+        }
+        state.started = None;
+        mem::replace(&mut state.batch, Vec::with_capacity(self.max_packets))
+    }
+
+    fn send(&self, batch: &[(Vec<u8>, Interface)]) {
+        if batch.is_empty() {
+            return;
+        }
+        if let Err(e) = self.socket.send_batch(batch) {
+            self.state().error.get_or_insert(e);
+        }
+    }
+
+    fn take_error(&self) -> io::Result<()> {
+        match self.state().error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl SendBatcher {
+    /// Queues a copy of `buf` to send to the group out of `interface` with the next batch.
+    /// Fails with the error of a datagram sent earlier, if one failed meanwhile.
+    pub fn send(&self, buf: &[u8], interface: &Interface) -> io::Result<()> {
+        let mut state = self.shared.state();
+        if let Some(e) = state.error.take() {
+            return Err(e);
+        }
+        state.batch.push((buf.to_vec(), *interface));
+        let len = state.batch.len();
+        if len == 1 {
+            state.started = Some(Instant::now());
+        }
+        if len == 1 || len >= self.shared.max_packets {
+            self.shared.queued.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Sends the queued datagrams right away, waiting for them and the batches sent before, and
+    /// failing with the first error of any of them
+    pub fn flush(&self) -> io::Result<()> {
+        let _sending = self
+            .shared
+            .sending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        loop {
+            let batch = self.shared.take_batch(&mut self.shared.state());
+            if batch.is_empty() {
+                return self.shared.take_error();
+            }
+            self.shared.send(&batch);
+        }
+    }
+
+    /// Datagrams queued for the next batch
+    pub fn pending(&self) -> usize {
+        self.shared.state().batch.len()
+    }
+
+    pub fn socket(&self) -> &Arc<MulticastSocket> {
+        &self.shared.socket
+    }
+}
+
+impl Drop for SendBatcher {
+    fn drop(&mut self) {
+        self.shared.state().closed = true;
+        self.shared.queued.notify_one();
+        if let Some(flusher) = self.flusher.take() {
+            let _ = flusher.join();
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Bridging a group between the interfaces of a dual-homed host, so discovery crosses VLANs or
+//! subnets without a PIM router. Each datagram received on a bridged interface is sent again
+//! out of the other bridged ones, from the socket that joined them all.
+//!
+//! Two things keep datagrams from going around in loops. Forwarded copies go out with the TTL
+//! they arrived with minus one, and the ones that arrived with a TTL of 1 aren't forwarded, as
+//! a router would. And the bridge remembers the copies it sent for `BridgeOptions::echo_window`,
+//! by origin and payload, dropping them when they come back through multicast loopback or a
+//! link the interfaces share.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::dedup::Deduplicator;
+use crate::socket::is_timeout;
+use crate::{interface_index, Interface, MulticastSocket, SendOptions};
+
+#[derive(Debug, Clone)]
+pub struct BridgeOptions {
+    /// Interfaces bridged with each other, all the joined ones when empty
+    pub interfaces: Vec<Ipv4Addr>,
+    /// How long the copies sent are recognized when they come back
+    pub echo_window: Duration,
+}
+
+impl Default for BridgeOptions {
+    fn default() -> Self {
+        BridgeOptions {
+            interfaces: Vec::new(),
+            echo_window: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Datagrams handled since the bridge started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BridgeStats {
+    /// Copies sent, once per interface they went out of
+    pub forwarded: u64,
+    /// Not forwarded as they arrived with a TTL of 1, or without a TTL
+    pub expired: u64,
+    /// Copies sent by the bridge that came back
+    pub echoes: u64,
+    /// Received on an interface that isn't bridged
+    pub not_bridged: u64,
+    /// Sends that failed, which don't stop the bridge
+    pub send_errors: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    forwarded: AtomicU64,
+    expired: AtomicU64,
+    echoes: AtomicU64,
+    not_bridged: AtomicU64,
+    send_errors: AtomicU64,
+}
+
+/// Stops the thread started by `MulticastSocket::bridge`
+pub struct BridgeHandle {
+    stop: Arc<AtomicBool>,
+    worker: JoinHandle<io::Result<()>>,
+    counters: Arc<Counters>,
+}
+
+impl MulticastSocket {
+    /// Forwards the group between the interfaces of `options`, on a thread, ending on
+    /// `shutdown` or on a receive error other than a timeout.
+    ///
+    /// The socket needs `receive_ttl`, for the TTL of the forwarded copies, and fails with
+    /// `InvalidInput` without it or with fewer than two interfaces to bridge, and with
+    /// `AddrNotAvailable` for an interface it didn't join.
+    pub fn bridge(self: Arc<Self>, options: BridgeOptions) -> io::Result<BridgeHandle> {
+        if !self.options.receive_ttl {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "bridging needs `receive_ttl`, to decrement the TTL of forwarded datagrams",
+            ));
+        }
+        let joined = self.interfaces();
+        let addresses = if options.interfaces.is_empty() {
+            joined.clone()
+        } else {
+            options.interfaces.clone()
+        };
+        if addresses.len() < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a bridge needs at least two interfaces",
+            ));
+        }
+        // By index as well, which is how received datagrams report their interface
+        let bridged = addresses
+            .into_iter()
+            .map(|address| {
+                if !joined.contains(&address) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        format!("interface {} isn't joined, so it can't be bridged", address),
+                    ));
+                }
+                Ok((address, interface_index(address)?))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let counters = Arc::new(Counters::default());
+        let (stop_worker, worker_counters) = (stop.clone(), counters.clone());
+        let worker = thread::Builder::new()
+            .name("multicast-bridge".to_owned())
+            .spawn(move || {
+                let mut sent = Deduplicator::new(options.echo_window);
+                while !stop_worker.load(Ordering::Relaxed) {
+                    let message = match self.receive() {
+                        Ok(message) => message,
+                        Err(e) if is_timeout(&e) => continue,
+                        Err(e) => return Err(e),
+                    };
+                    let arrived_on = bridged.iter().position(|(address, index)| {
+                        message.interface == Interface::Ip(*address)
+                            || message.interface == Interface::Index(*index)
+                    });
+                    let counter = match (arrived_on, message.ttl) {
+                        (None, _) => &worker_counters.not_bridged,
+                        _ if sent.was_seen(&message.origin_address, &message.data) => {
+                            &worker_counters.echoes
+                        }
+                        (Some(_), None | Some(0..=1)) => &worker_counters.expired,
+                        (Some(arrived_on), Some(ttl)) => {
+                            for (i, (address, _)) in bridged.iter().enumerate() {
+                                if i == arrived_on {
+                                    continue;
+                                }
+                                sent.is_duplicate(
+                                    &SocketAddrV4::new(*address, self.local_port),
+                                    &message.data,
+                                );
+                                let send_options = SendOptions {
+                                    interface: Interface::Ip(*address),
+                                    ttl: Some(ttl - 1),
+                                    ..Default::default()
+                                };
+                                let counter = match self.send_with(&message.data, &send_options) {
+                                    Ok(_) => &worker_counters.forwarded,
+                                    Err(_) => &worker_counters.send_errors,
+                                };
+                                counter.fetch_add(1, Ordering::Relaxed);
+                            }
+                            continue;
+                        }
+                    };
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(())
+            })?;
+
+        Ok(BridgeHandle {
+            stop,
+            worker,
+            counters,
+        })
+    }
+}
+
+impl BridgeHandle {
+    pub fn stats(&self) -> BridgeStats {
+        BridgeStats {
+            forwarded: self.counters.forwarded.load(Ordering::Relaxed),
+            expired: self.counters.expired.load(Ordering::Relaxed),
+            echoes: self.counters.echoes.load(Ordering::Relaxed),
+            not_bridged: self.counters.not_bridged.load(Ordering::Relaxed),
+            send_errors: self.counters.send_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Asks the thread to stop and waits for it, which takes up to the read timeout.
+    /// Returns the error that ended it earlier, if any.
+    pub fn shutdown(self) -> io::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        match self.worker.join() {
+            Ok(ended) => ended,
+            Err(_) => Err(io::Error::other("bridge thread panicked")),
+        }
+    }
+
+    /// Whether the thread ended, on error
+    pub fn is_finished(&self) -> bool {
+        self.worker.is_finished()
+    }
+}
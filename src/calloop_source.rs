@@ -0,0 +1,78 @@
+//! `calloop` event source feeding the datagrams of a socket into the main loop.
+
+use std::io;
+use std::sync::Arc;
+
+use calloop::generic::Generic;
+use calloop::{EventSource, Interest, Mode, Poll, PostAction, Readiness, Token, TokenFactory};
+
+use crate::{Message, MulticastSocket};
+
+/// Calls back with every datagram the socket receives. Readiness is level triggered, and the
+/// queued datagrams are drained on each wakeup without blocking the loop.
+///
+/// Receive errors, like ICMP errors queued with `receive_errors`, don't end the loop. They are
+/// counted in `MulticastSocket::stats`, and the source goes on with the next wakeup.
+pub struct MulticastSource {
+    inner: Generic<Arc<MulticastSocket>>,
+}
+
+impl MulticastSource {
+    /// The socket stays usable for sending through the other `Arc` clones
+    pub fn new(socket: Arc<MulticastSocket>) -> Self {
+        MulticastSource {
+            inner: Generic::new(socket, Interest::READ, Mode::Level),
+        }
+    }
+
+    pub fn socket(&self) -> &Arc<MulticastSocket> {
+        self.inner.get_ref()
+    }
+}
+
+impl EventSource for MulticastSource {
+    type Event = Message;
+    type Metadata = ();
+    type Ret = ();
+    type Error = io::Error;
+
+    fn process_events<F>(
+        &mut self,
+        readiness: Readiness,
+        token: Token,
+        mut callback: F,
+    ) -> io::Result<PostAction>
+    where
+        F: FnMut(Message, &mut ()),
+    {
+        self.inner.process_events(readiness, token, |_, socket| {
+            // Counted in `stats`, a failed receive only skips the rest of this wakeup
+            if let Ok(messages) = socket.drain() {
+                for message in messages {
+                    callback(message, &mut ());
+                }
+            }
+            Ok(PostAction::Continue)
+        })
+    }
+
+    fn register(
+        &mut self,
+        poll: &mut Poll,
+        token_factory: &mut TokenFactory,
+    ) -> calloop::Result<()> {
+        self.inner.register(poll, token_factory)
+    }
+
+    fn reregister(
+        &mut self,
+        poll: &mut Poll,
+        token_factory: &mut TokenFactory,
+    ) -> calloop::Result<()> {
+        self.inner.reregister(poll, token_factory)
+    }
+
+    fn unregister(&mut self, poll: &mut Poll) -> calloop::Result<()> {
+        self.inner.unregister(poll)
+    }
+}
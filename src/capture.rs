@@ -0,0 +1,341 @@
+//! Mirrors the datagrams of a socket into a pcapng file, see `MulticastOptions::capture`.
+//!
+//! The OS hands over payloads only, so each one is written behind made up IPv4 and UDP
+//! headers, with the addresses, ports, TTL and TOS known for it. A TTL or TOS that isn't known
+//! is 0, as with sends and without `receive_ttl` or `receive_tos`. Every `Interface` the socket
+//! receives or sends on shows up as an interface of the capture, named after it.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::Interface;
+
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+/// Raw IPv4 packets, without link layer
+const LINKTYPE_IPV4: u16 = 228;
+const OPT_END: u16 = 0;
+const IF_NAME: u16 = 2;
+const EPB_FLAGS: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Received,
+    Sent,
+}
+
+/// What is known about a datagram, to fill its headers
+pub(crate) struct Datagram<'a> {
+    pub(crate) direction: Direction,
+    pub(crate) data: &'a [u8],
+    pub(crate) interface: Interface,
+    pub(crate) source: SocketAddrV4,
+    pub(crate) destination: SocketAddrV4,
+    pub(crate) ttl: Option<u8>,
+    pub(crate) tos: Option<u8>,
+    pub(crate) time: Option<SystemTime>,
+}
+
+struct State {
+    writer: Box<dyn Write + Send>,
+    // Interfaces by the id of their description block
+    interfaces: Vec<Interface>,
+    error: Option<io::Error>,
+}
+
+/// pcapng writer shared by the sockets capturing into it
+pub struct PcapngCapture {
+    state: Mutex<State>,
+}
+
+impl PcapngCapture {
+    /// Starts a capture on `writer`, writing the section header right away
+    pub fn new(writer: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut writer: Box<dyn Write + Send> = Box::new(writer);
+        let mut body = Vec::with_capacity(16);
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // Section length not known in advance
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut writer, SECTION_HEADER, &body)?;
+
+        Ok(PcapngCapture {
+            state: Mutex::new(State {
+                writer,
+                interfaces: Vec::new(),
+                error: None,
+            }),
+        })
+    }
+
+    /// Starts a capture into a new file at `path`, replacing any file there
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes out buffered blocks. Fails with the first error writing the capture, which
+    /// doesn't fail the sends and receives being captured.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut state = self.state();
+        if let Some(e) = state.error.take() {
+            return Err(e);
+        }
+        state.writer.flush()
+    }
+
+    pub(crate) fn record(&self, datagram: &Datagram<'_>) {
+        let mut state = self.state();
+        if state.error.is_some() {
+            return;
+        }
+        if let Err(e) = write_datagram(&mut state, datagram) {
+            state.error = Some(e);
+        }
+    }
+}
+
+fn write_datagram(state: &mut State, datagram: &Datagram<'_>) -> io::Result<()> {
+    let id = match state
+        .interfaces
+        .iter()
+        .position(|i| *i == datagram.interface)
+    {
+        Some(id) => id,
+        None => {
+            write_interface(&mut state.writer, &datagram.interface)?;
+            state.interfaces.push(datagram.interface);
+            state.interfaces.len() - 1
+        }
+    };
+
+    let packet = packet(datagram);
+    let micros = datagram
+        .time
+        .unwrap_or_else(SystemTime::now)
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64);
+    let mut body = Vec::with_capacity(packet.len() + 40);
+    body.extend_from_slice(&(id as u32).to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&packet);
+    pad(&mut body);
+    let direction: u32 = match datagram.direction {
+        Direction::Received => 1,
+        Direction::Sent => 2,
+    };
+    option(&mut body, EPB_FLAGS, &direction.to_le_bytes());
+    option(&mut body, OPT_END, &[]);
+    write_block(&mut state.writer, ENHANCED_PACKET, &body)
+}
+
+fn write_interface(writer: &mut impl Write, interface: &Interface) -> io::Result<()> {
+    let name = match interface {
+        Interface::Default => "default".to_owned(),
+        Interface::Ip(address) => address.to_string(),
+        Interface::Index(index) => format!("index {}", index),
+    };
+    let mut body = Vec::with_capacity(32);
+    body.extend_from_slice(&LINKTYPE_IPV4.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // No snap length limit
+    body.extend_from_slice(&0u32.to_le_bytes());
+    option(&mut body, IF_NAME, name.as_bytes());
+    option(&mut body, OPT_END, &[]);
+    write_block(writer, INTERFACE_DESCRIPTION, &body)
+}
+
+/// The datagram behind IPv4 and UDP headers, without UDP checksum as IPv4 allows
+fn packet(datagram: &Datagram<'_>) -> Vec<u8> {
+    let udp_len = (8 + datagram.data.len()).min(usize::from(u16::MAX) - 20) as u16;
+    let total_len = udp_len + 20;
+    let mut packet = Vec::with_capacity(usize::from(total_len));
+    packet.extend_from_slice(&[0x45, datagram.tos.unwrap_or(0)]);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    // Identification, flags and fragment offset
+    packet.extend_from_slice(&[0; 4]);
+    packet.extend_from_slice(&[datagram.ttl.unwrap_or(0), 17, 0, 0]);
+    packet.extend_from_slice(&datagram.source.ip().octets());
+    packet.extend_from_slice(&datagram.destination.ip().octets());
+    let checksum = ipv4_checksum(&packet);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    packet.extend_from_slice(&datagram.source.port().to_be_bytes());
+    packet.extend_from_slice(&datagram.destination.port().to_be_bytes());
+    packet.extend_from_slice(&udp_len.to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&datagram.data[..usize::from(udp_len) - 8]);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+fn write_block(writer: &mut impl Write, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let total = (body.len() + 12) as u32;
+    let mut block = Vec::with_capacity(total as usize);
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&total.to_le_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&total.to_le_bytes());
+    writer.write_all(&block)
+}
+
+/// Source of the datagrams a socket sends out of `interface`, unspecified when the OS picks it
+pub(crate) fn sent_from(interface: &Interface, port: u16) -> SocketAddrV4 {
+    match interface {
+        Interface::Ip(address) => SocketAddrV4::new(*address, port),
+        _ => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+    }
+
+    // Type and body of each block, checking the trailing length repeats the leading one
+    fn blocks(mut buf: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        while !buf.is_empty() {
+            let total = u32_at(buf, 4) as usize;
+            assert_eq!(total % 4, 0);
+            assert_eq!(u32_at(buf, total - 4) as usize, total);
+            blocks.push((u32_at(buf, 0), buf[8..total - 4].to_vec()));
+            buf = &buf[total..];
+        }
+        blocks
+    }
+
+    fn datagram(data: &[u8], interface: Interface) -> Datagram<'_> {
+        Datagram {
+            direction: Direction::Received,
+            data,
+            interface,
+            source: SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 40000),
+            destination: SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353),
+            ttl: Some(255),
+            tos: None,
+            time: Some(UNIX_EPOCH + std::time::Duration::from_micros(0x1_0000_0002)),
+        }
+    }
+
+    #[test]
+    fn checksums_an_ipv4_header() {
+        let mut header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(ipv4_checksum(&header), 0xb861);
+        header[10..12].copy_from_slice(&0xb861u16.to_be_bytes());
+        assert_eq!(ipv4_checksum(&header), 0);
+    }
+
+    #[test]
+    fn writes_an_interface_once_before_its_packets() {
+        let buf = Shared::default();
+        let capture = PcapngCapture::new(buf.clone()).unwrap();
+        let interface = Interface::Ip(Ipv4Addr::new(192, 0, 2, 2));
+        capture.record(&datagram(b"hello", interface));
+        capture.record(&datagram(b"again", interface));
+        capture.record(&datagram(b"other", Interface::Index(3)));
+        capture.flush().unwrap();
+
+        let written = buf.0.lock().unwrap().clone();
+        let blocks = blocks(&written);
+        let types: Vec<u32> = blocks.iter().map(|(block_type, _)| *block_type).collect();
+        assert_eq!(
+            types,
+            [
+                SECTION_HEADER,
+                INTERFACE_DESCRIPTION,
+                ENHANCED_PACKET,
+                ENHANCED_PACKET,
+                INTERFACE_DESCRIPTION,
+                ENHANCED_PACKET
+            ]
+        );
+        assert_eq!(u32_at(&blocks[0].1, 0), BYTE_ORDER_MAGIC);
+
+        let packet = &blocks[2].1;
+        // Interface id, timestamp halves and lengths
+        assert_eq!(u32_at(packet, 0), 0);
+        assert_eq!((u32_at(packet, 4), u32_at(packet, 8)), (1, 2));
+        assert_eq!(u32_at(packet, 12), 20 + 8 + 5);
+        assert_eq!(u32_at(&blocks[5].1, 0), 1);
+    }
+
+    #[test]
+    fn builds_valid_ipv4_and_udp_headers() {
+        let packet = packet(&datagram(b"hello", Interface::Default));
+        assert_eq!(packet.len(), 20 + 8 + 5);
+        assert_eq!(u16::from_be_bytes([packet[2], packet[3]]), 33);
+        assert_eq!((packet[8], packet[9]), (255, 17));
+        assert_eq!(ipv4_checksum(&packet[..20]), 0);
+        assert_eq!(&packet[12..16], &[192, 0, 2, 1]);
+        assert_eq!(&packet[16..20], &[224, 0, 0, 251]);
+        assert_eq!(u16::from_be_bytes([packet[20], packet[21]]), 40000);
+        assert_eq!(u16::from_be_bytes([packet[22], packet[23]]), 5353);
+        assert_eq!(u16::from_be_bytes([packet[24], packet[25]]), 13);
+        assert_eq!(&packet[28..], b"hello");
+    }
+
+    #[test]
+    fn truncates_payloads_beyond_the_ipv4_limit() {
+        let data = vec![0; 70_000];
+        let packet = packet(&datagram(&data, Interface::Default));
+        assert_eq!(packet.len(), usize::from(u16::MAX));
+        assert_eq!(u16::from_be_bytes([packet[2], packet[3]]), u16::MAX);
+    }
+}
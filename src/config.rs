@@ -0,0 +1,183 @@
+//! Socket setup loaded from configuration files, with the `serde` feature.
+//!
+//! `MulticastConfig` holds the group, the interfaces to join on and the `MulticastOptions`,
+//! all deserializable, e.g. from TOML:
+//!
+//! ```toml
+//! group = "239.255.0.1"
+//! port = 5000
+//! interfaces = { networks = ["10.20.0.0/16"] }
+//!
+//! [options]
+//! read_timeout = "250ms"
+//! dedup_window = "2s"
+//! ```
+//!
+//! Missing options keep their defaults. Durations are numbers of seconds, or strings with a
+//! unit among `ns`, `us`, `ms`, `s`, `m` and `h`. The options that can't come from a file, the
+//! steering program, the capture and `configure`, are left unset.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+
+use crate::{Interface, Ipv4Cidr, MulticastOptions, MulticastSocket};
+
+/// Interfaces `MulticastSocket::from_config` joins on
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterfaceSelector {
+    /// Every interface, see `MulticastSocket::all_interfaces_with_options`
+    #[default]
+    All,
+    /// The interface carrying the default route, see `MulticastSocket::on_default_interface`
+    DefaultRoute,
+    /// The interfaces with these addresses
+    Addresses(Vec<Ipv4Addr>),
+    /// The interfaces with an address within these ranges, see `MulticastSocket::in_networks`
+    Networks(Vec<Ipv4Cidr>),
+}
+
+/// Everything `MulticastSocket::from_config` needs to create a socket
+#[derive(Clone, Deserialize)]
+pub struct MulticastConfig {
+    pub group: Ipv4Addr,
+    pub port: u16,
+    #[serde(default)]
+    pub interfaces: InterfaceSelector,
+    #[serde(default)]
+    pub options: MulticastOptions,
+}
+
+impl MulticastConfig {
+    pub fn multicast_address(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.group, self.port)
+    }
+}
+
+impl MulticastSocket {
+    /// Joins the group of `config` on the interfaces it selects, with its options
+    pub fn from_config(config: &MulticastConfig) -> io::Result<Self> {
+        let multicast_address = config.multicast_address();
+        let options = config.options.clone();
+        match &config.interfaces {
+            #[cfg(feature = "enumerate")]
+            InterfaceSelector::All => {
+                MulticastSocket::all_interfaces_with_options(multicast_address, options)
+            }
+            #[cfg(feature = "enumerate")]
+            InterfaceSelector::Networks(networks) => {
+                MulticastSocket::in_networks(multicast_address, networks, options)
+            }
+            #[cfg(not(feature = "enumerate"))]
+            InterfaceSelector::All | InterfaceSelector::Networks(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "selecting interfaces by listing them needs the `enumerate` feature",
+            )),
+            InterfaceSelector::DefaultRoute => {
+                let interface = crate::default_route_interface()?;
+                let socket =
+                    MulticastSocket::with_options(multicast_address, vec![interface], options)?;
+                socket.set_default_send_interface(&Interface::Ip(interface))?;
+                Ok(socket)
+            }
+            InterfaceSelector::Addresses(addresses) => {
+                MulticastSocket::with_options(multicast_address, addresses.clone(), options)
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Ipv4Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let range = String::deserialize(deserializer)?;
+        range.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDuration {
+    Seconds(f64),
+    Text(String),
+}
+
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (value, unit) = text.split_at(split);
+    let value: f64 = value
+        .parse()
+        .map_err(|_| format!("{:?} is not a duration like \"250ms\"", text))?;
+    let unit = match unit.trim() {
+        "ns" => 1e-9,
+        "us" | "µs" => 1e-6,
+        "ms" => 1e-3,
+        "" | "s" => 1.0,
+        "m" | "min" => 60.0,
+        "h" => 3600.0,
+        unit => return Err(format!("unknown unit {:?} of duration {:?}", unit, text)),
+    };
+    Duration::try_from_secs_f64(value * unit).map_err(|e| e.to_string())
+}
+
+/// Duration as a number of seconds or a string with a unit
+pub(crate) fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    match RawDuration::deserialize(deserializer)? {
+        RawDuration::Seconds(seconds) => {
+            Duration::try_from_secs_f64(seconds).map_err(de::Error::custom)
+        }
+        RawDuration::Text(text) => parse_duration(&text).map_err(de::Error::custom),
+    }
+}
+
+/// Same as `duration`, for optional ones
+pub(crate) fn option_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    struct Wrapper(Duration);
+
+    impl<'de> Deserialize<'de> for Wrapper {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            duration(deserializer).map(Wrapper)
+        }
+    }
+
+    Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|w| w.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations_with_units() {
+        let cases = [
+            ("250ms", Duration::from_millis(250)),
+            ("1.5s", Duration::from_millis(1500)),
+            ("2", Duration::from_secs(2)),
+            (" 10 ms ", Duration::from_millis(10)),
+            ("100us", Duration::from_micros(100)),
+            ("100µs", Duration::from_micros(100)),
+            ("5ns", Duration::from_nanos(5)),
+            ("2m", Duration::from_secs(120)),
+            ("1min", Duration::from_secs(60)),
+            ("1h", Duration::from_secs(3600)),
+        ];
+        for (text, duration) in cases {
+            assert_eq!(parse_duration(text), Ok(duration), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_durations() {
+        for text in ["", "ms", "fast", "-1s", "1e3", "10 parsecs", "1.2.3s"] {
+            assert!(parse_duration(text).is_err(), "{:?}", text);
+        }
+    }
+}
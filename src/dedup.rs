@@ -0,0 +1,98 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+/// Remembers which datagrams were seen within a time window, identified by a hash of their
+/// origin and payload, to drop the copies delivered once per joined interface.
+pub(crate) struct Deduplicator {
+    window: Duration,
+    seen: HashSet<u64>,
+    // Insertion order, to forget hashes once they leave the window
+    expiry: VecDeque<(Instant, u64)>,
+}
+
+impl Deduplicator {
+    pub(crate) fn new(window: Duration) -> Self {
+        Deduplicator {
+            window,
+            seen: HashSet::new(),
+            expiry: VecDeque::new(),
+        }
+    }
+
+    /// Returns true when the datagram was already seen within the window
+    pub(crate) fn is_duplicate(&mut self, origin: &SocketAddrV4, data: &[u8]) -> bool {
+        let now = Instant::now();
+        self.expire(now);
+        let hash = hash(origin, data);
+        if self.seen.insert(hash) {
+            self.expiry.push_back((now, hash));
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Same as `is_duplicate`, without remembering the datagram when it wasn't seen
+    #[cfg(any(target_os = "linux", target_os = "android", windows))]
+    pub(crate) fn was_seen(&mut self, origin: &SocketAddrV4, data: &[u8]) -> bool {
+        self.expire(Instant::now());
+        self.seen.contains(&hash(origin, data))
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((at, hash)) = self.expiry.front() {
+            if now.duration_since(*at) < self.window {
+                break;
+            }
+            self.seen.remove(hash);
+            self.expiry.pop_front();
+        }
+    }
+}
+
+fn hash(origin: &SocketAddrV4, data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    origin.hash(&mut hasher);
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn origin(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), port)
+    }
+
+    #[test]
+    fn drops_copies_from_the_same_origin() {
+        let mut dedup = Deduplicator::new(Duration::from_secs(60));
+        assert!(!dedup.is_duplicate(&origin(5353), b"announce"));
+        assert!(dedup.is_duplicate(&origin(5353), b"announce"));
+        assert!(!dedup.is_duplicate(&origin(5354), b"announce"));
+        assert!(!dedup.is_duplicate(&origin(5353), b"goodbye"));
+    }
+
+    #[test]
+    fn forgets_datagrams_out_of_the_window() {
+        let mut dedup = Deduplicator::new(Duration::ZERO);
+        assert!(!dedup.is_duplicate(&origin(5353), b"announce"));
+        assert!(!dedup.is_duplicate(&origin(5353), b"announce"));
+        // Only the last one is remembered
+        assert_eq!((dedup.seen.len(), dedup.expiry.len()), (1, 1));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", windows))]
+    #[test]
+    fn checks_without_remembering() {
+        let mut dedup = Deduplicator::new(Duration::from_secs(60));
+        assert!(!dedup.was_seen(&origin(5353), b"announce"));
+        assert!(!dedup.is_duplicate(&origin(5353), b"announce"));
+        assert!(dedup.was_seen(&origin(5353), b"announce"));
+    }
+}
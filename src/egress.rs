@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::socket::local_port;
+use crate::{set_send_options, MulticastOptions};
+
+/// Sockets connected to the group, one per interface with `IP_MULTICAST_IF` fixed, created on
+/// first use. Sends don't contend on the state of the main socket and can run in parallel.
+pub(crate) struct SendSockets {
+    // The options shaping outgoing datagrams are applied to each socket as to the main one
+    options: MulticastOptions,
+    // Unset once `open` created them all, sends out of other interfaces fail then
+    lazy: bool,
+    // Socket and its local port, to recognize its datagrams
+    sockets: Mutex<HashMap<Ipv4Addr, (Arc<Socket>, u16)>>,
+}
+
+impl SendSockets {
+    pub(crate) fn new(options: &MulticastOptions) -> Self {
+        SendSockets {
+            options: options.clone(),
+            lazy: true,
+            sockets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates the sockets of all `interfaces` up front, instead of on first use
+    pub(crate) fn open(
+        &mut self,
+        main: &Socket,
+        multicast_address: SocketAddrV4,
+        interfaces: &[Ipv4Addr],
+    ) -> io::Result<()> {
+        for interface in interfaces {
+            let socket = self.create(main, multicast_address, *interface)?;
+            let port = local_port(&socket)?;
+            let sockets = self.sockets.get_mut().unwrap_or_else(|e| e.into_inner());
+            sockets.insert(*interface, (Arc::new(socket), port));
+        }
+        self.lazy = false;
+        Ok(())
+    }
+
+    /// Sends out of `interface`, with the loopback, TTL and write timeout of `main`
+    pub(crate) fn send(
+        &self,
+        main: &Socket,
+        multicast_address: SocketAddrV4,
+        interface: Ipv4Addr,
+        buf: &[u8],
+    ) -> io::Result<usize> {
+        let socket = {
+            let mut sockets = self.sockets.lock().unwrap_or_else(|e| e.into_inner());
+            match sockets.get(&interface) {
+                Some((socket, _)) => socket.clone(),
+                None if !self.lazy => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        format!("no send socket was opened for {}", interface),
+                    ))
+                }
+                None => {
+                    let socket = Arc::new(self.create(main, multicast_address, interface)?);
+                    let port = local_port(&socket)?;
+                    sockets.insert(interface, (socket.clone(), port));
+                    socket
+                }
+            }
+        };
+        socket.send(buf)
+    }
+
+    /// Connects to another group, reopening the sockets created so far
+    pub(crate) fn set_group(
+        &mut self,
+        main: &Socket,
+        multicast_address: SocketAddrV4,
+    ) -> io::Result<()> {
+        let sockets = self.sockets.get_mut().unwrap_or_else(|e| e.into_inner());
+        let interfaces: Vec<_> = sockets.drain().map(|(interface, _)| interface).collect();
+        if self.lazy {
+            return Ok(());
+        }
+        self.open(main, multicast_address, &interfaces)
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let sockets = self.sockets.lock().unwrap_or_else(|e| e.into_inner());
+        for (socket, _) in sockets.values() {
+            socket.set_write_timeout(timeout)?;
+        }
+        Ok(())
+    }
+
+    /// Whether `port` is the local port of one of the sockets
+    pub(crate) fn owns_port(&self, port: u16) -> bool {
+        let sockets = self.sockets.lock().unwrap_or_else(|e| e.into_inner());
+        sockets.values().any(|(_, p)| *p == port)
+    }
+
+    fn create(
+        &self,
+        main: &Socket,
+        multicast_address: SocketAddrV4,
+        interface: Ipv4Addr,
+    ) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+        socket.set_multicast_if_v4(&interface)?;
+        socket.set_multicast_loop_v4(main.multicast_loop_v4()?)?;
+        socket.set_multicast_ttl_v4(main.multicast_ttl_v4()?)?;
+        socket.set_write_timeout(main.write_timeout()?)?;
+        set_send_options(&socket, &self.options)?;
+        if let Some(configure) = &self.options.configure {
+            configure(&socket)?;
+        }
+        socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0).into())?;
+        socket.connect(&SocketAddr::from(multicast_address).into())?;
+        Ok(socket)
+    }
+}
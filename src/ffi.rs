@@ -0,0 +1,271 @@
+//! C bindings, declared in `include/multicast_socket.h`.
+//!
+//! Addresses cross the boundary as `uint32_t` in host byte order, as `ntohl` returns them.
+//! Failing calls return `MULTICAST_SOCKET_ERROR` and keep the error for
+//! `multicast_socket_last_error` and `multicast_socket_last_os_error`, per thread like `errno`.
+
+use std::cell::RefCell;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+use std::slice;
+use std::time::Duration;
+
+use crate::socket::is_timeout;
+use crate::{Interface, MessageFlags, MulticastOptions, MulticastSocket};
+
+pub const MULTICAST_SOCKET_OK: c_int = 0;
+/// Nothing arrived within the read timeout
+pub const MULTICAST_SOCKET_TIMEOUT: c_int = -1;
+pub const MULTICAST_SOCKET_ERROR: c_int = -2;
+
+pub const MULTICAST_SOCKET_INTERFACE_DEFAULT: u32 = 0;
+pub const MULTICAST_SOCKET_INTERFACE_IP: u32 = 1;
+pub const MULTICAST_SOCKET_INTERFACE_INDEX: u32 = 2;
+
+/// `Interface`, with `value` the address or the index as `kind` says
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MulticastSocketInterface {
+    pub kind: u32,
+    pub value: u32,
+}
+
+/// `Message`, with the data copied to the buffer passed to `multicast_socket_receive`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MulticastSocketMessage {
+    /// Bytes copied to the buffer
+    pub len: usize,
+    /// Whether the datagram was longer than the buffer, or the one of the socket
+    pub truncated: bool,
+    pub origin_address: u32,
+    pub origin_port: u16,
+    pub interface: MulticastSocketInterface,
+    /// Whether the OS delivered the packet info, `destination` is 0 otherwise
+    pub has_packet_info: bool,
+    pub destination: u32,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<io::Error>> = const { RefCell::new(None) };
+}
+
+fn fail(e: io::Error) -> c_int {
+    let code = if is_timeout(&e) {
+        MULTICAST_SOCKET_TIMEOUT
+    } else {
+        MULTICAST_SOCKET_ERROR
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(e));
+    code
+}
+
+fn to_interface(interface: MulticastSocketInterface) -> io::Result<Interface> {
+    match interface.kind {
+        MULTICAST_SOCKET_INTERFACE_DEFAULT => Ok(Interface::Default),
+        MULTICAST_SOCKET_INTERFACE_IP => Ok(Interface::Ip(Ipv4Addr::from(interface.value))),
+        MULTICAST_SOCKET_INTERFACE_INDEX => Ok(Interface::Index(interface.value)),
+        kind => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown interface kind {}", kind),
+        )),
+    }
+}
+
+fn from_interface(interface: &Interface) -> MulticastSocketInterface {
+    match *interface {
+        Interface::Default => MulticastSocketInterface {
+            kind: MULTICAST_SOCKET_INTERFACE_DEFAULT,
+            value: 0,
+        },
+        Interface::Ip(address) => MulticastSocketInterface {
+            kind: MULTICAST_SOCKET_INTERFACE_IP,
+            value: u32::from(address),
+        },
+        Interface::Index(index) => MulticastSocketInterface {
+            kind: MULTICAST_SOCKET_INTERFACE_INDEX,
+            value: index,
+        },
+    }
+}
+
+// Null pointers are fine with a zero length, as C passes them for empty buffers
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(data, len)
+    }
+}
+
+/// Joins `group`:`port` on the `interface_count` addresses at `interfaces`, or on every
+/// interface when `interface_count` is 0 and the `enumerate` feature is on. A
+/// `read_timeout_ms` of 0 keeps the default. Returns null on failure.
+///
+/// # Safety
+///
+/// `interfaces` must point to `interface_count` addresses.
+#[no_mangle]
+pub unsafe extern "C" fn multicast_socket_new(
+    group: u32,
+    port: u16,
+    interfaces: *const u32,
+    interface_count: usize,
+    read_timeout_ms: u32,
+) -> *mut MulticastSocket {
+    let multicast_address = SocketAddrV4::new(Ipv4Addr::from(group), port);
+    let mut options = MulticastOptions::default();
+    if read_timeout_ms > 0 {
+        options.read_timeout = Duration::from_millis(u64::from(read_timeout_ms));
+    }
+
+    let interfaces: io::Result<Vec<Ipv4Addr>> = if interface_count == 0 {
+        #[cfg(feature = "enumerate")]
+        let all = crate::all_ipv4_interfaces();
+        #[cfg(not(feature = "enumerate"))]
+        let all = Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no interfaces given, listing them needs the `enumerate` feature",
+        ));
+        all
+    } else {
+        Ok(slice::from_raw_parts(interfaces, interface_count)
+            .iter()
+            .map(|&address| Ipv4Addr::from(address))
+            .collect())
+    };
+
+    match interfaces.and_then(|interfaces| {
+        MulticastSocket::with_options(multicast_address, interfaces, options)
+    }) {
+        Ok(socket) => Box::into_raw(Box::new(socket)),
+        Err(e) => {
+            fail(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Waits for a datagram, copying up to `buffer_len` bytes of it to `buffer`
+///
+/// # Safety
+///
+/// `socket` must come from `multicast_socket_new`, `buffer` must point to `buffer_len`
+/// writable bytes, and `message` to a writable `MulticastSocketMessage`.
+#[no_mangle]
+pub unsafe extern "C" fn multicast_socket_receive(
+    socket: *const MulticastSocket,
+    buffer: *mut u8,
+    buffer_len: usize,
+    message: *mut MulticastSocketMessage,
+) -> c_int {
+    let received = match (*socket).receive() {
+        Ok(received) => received,
+        Err(e) => return fail(e),
+    };
+
+    let len = received.data.len().min(buffer_len);
+    if len > 0 {
+        ptr::copy_nonoverlapping(received.data.as_ptr(), buffer, len);
+    }
+    *message = MulticastSocketMessage {
+        len,
+        truncated: received.data.len() > buffer_len
+            || received.flags.contains(MessageFlags::TRUNCATED),
+        origin_address: u32::from(*received.origin_address.ip()),
+        origin_port: received.origin_address.port(),
+        interface: from_interface(&received.interface),
+        has_packet_info: received.packet_info.is_some(),
+        destination: received
+            .packet_info
+            .map_or(0, |info| u32::from(info.destination)),
+    };
+    MULTICAST_SOCKET_OK
+}
+
+/// Sends `len` bytes to the group out of `interface`
+///
+/// # Safety
+///
+/// `socket` must come from `multicast_socket_new` and `data` must point to `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn multicast_socket_send(
+    socket: *const MulticastSocket,
+    data: *const u8,
+    len: usize,
+    interface: MulticastSocketInterface,
+) -> c_int {
+    let result =
+        to_interface(interface).and_then(|interface| (*socket).send(bytes(data, len), &interface));
+    match result {
+        Ok(_) => MULTICAST_SOCKET_OK,
+        Err(e) => fail(e),
+    }
+}
+
+/// Sends `len` bytes to the group out of every interface
+///
+/// # Safety
+///
+/// `socket` must come from `multicast_socket_new` and `data` must point to `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn multicast_socket_broadcast(
+    socket: *const MulticastSocket,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    match (*socket).broadcast(bytes(data, len)) {
+        Ok(()) => MULTICAST_SOCKET_OK,
+        Err(e) => fail(e),
+    }
+}
+
+/// Leaves the group and closes the socket. Null is ignored.
+///
+/// # Safety
+///
+/// `socket` must come from `multicast_socket_new`, and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn multicast_socket_destroy(socket: *mut MulticastSocket) {
+    if !socket.is_null() {
+        drop(Box::from_raw(socket));
+    }
+}
+
+/// Writes the message of the last error of this thread to `buffer`, NUL terminated and
+/// truncated to `buffer_len`. Returns the length of the whole message, 0 without error.
+///
+/// # Safety
+///
+/// `buffer` must point to `buffer_len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn multicast_socket_last_error(
+    buffer: *mut c_char,
+    buffer_len: usize,
+) -> usize {
+    let message = LAST_ERROR.with(|last| last.borrow().as_ref().map(|e| e.to_string()));
+    let message = match message {
+        Some(message) => message,
+        None => return 0,
+    };
+    if buffer_len > 0 {
+        let len = message.len().min(buffer_len - 1);
+        ptr::copy_nonoverlapping(message.as_ptr() as *const c_char, buffer, len);
+        *buffer.add(len) = 0;
+    }
+    message.len()
+}
+
+/// OS error code of the last error of this thread, `errno` or the WSA error, 0 for errors
+/// raised by this crate
+#[no_mangle]
+pub extern "C" fn multicast_socket_last_os_error() -> c_int {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .and_then(|e| e.raw_os_error())
+            .unwrap_or(0)
+    })
+}
@@ -0,0 +1,398 @@
+//! Application level fragmentation, for payloads larger than the path MTU.
+//!
+//! IP fragmentation of multicast is unreliable on many networks, so `Fragmenter` splits a payload
+//! into numbered datagrams that fit in one packet each, and `Reassembler` puts them back together
+//! on the receiving side, giving up on payloads whose fragments don't all arrive in time.
+//!
+//! Every fragment starts with a 12 bytes header: `MCFG` magic, payload id (u32), fragment index
+//! (u16) and fragment count (u16), all big endian. The framing can't be told apart from a
+//! payload of the application starting with the magic, so both ends of a group have to use it,
+//! or none.
+//!
+//! The headers come from the network, so a `Reassembler` only buffers the fragments it
+//! received, and bounds how many payloads and bytes it holds, dropping the oldest payloads first
+//! once either limit is reached.
+
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{Interface, Message, MulticastSocket};
+
+const MAGIC: &[u8; 4] = b"MCFG";
+pub const HEADER_LEN: usize = 12;
+
+pub struct Fragmenter {
+    max_datagram: usize,
+    next_id: u32,
+}
+
+impl Fragmenter {
+    /// `max_datagram` is the largest datagram to send, header included,
+    /// e.g. the interface MTU minus the 28 bytes of IPv4 and UDP headers
+    pub fn new(max_datagram: usize) -> Self {
+        // Avoids reusing the ids of a previous run right away
+        let next_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        Fragmenter {
+            max_datagram,
+            next_id,
+        }
+    }
+
+    /// Splits `payload` into framed datagrams
+    pub fn split(&mut self, payload: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        if self.max_datagram <= HEADER_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram size does not leave room for the fragment header",
+            ));
+        }
+        let mut chunks: Vec<&[u8]> = payload.chunks(self.max_datagram - HEADER_LEN).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+        let count = chunks.len();
+        if count > usize::from(u16::MAX) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "payload needs more than 65535 fragments",
+            ));
+        }
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let fragments = chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, data)| {
+                let mut fragment = Vec::with_capacity(HEADER_LEN + data.len());
+                fragment.extend_from_slice(MAGIC);
+                fragment.extend_from_slice(&id.to_be_bytes());
+                fragment.extend_from_slice(&(index as u16).to_be_bytes());
+                fragment.extend_from_slice(&(count as u16).to_be_bytes());
+                fragment.extend_from_slice(data);
+                fragment
+            })
+            .collect();
+        Ok(fragments)
+    }
+
+    /// Splits `payload` and sends every fragment out of `interface`
+    pub fn send(
+        &mut self,
+        socket: &MulticastSocket,
+        payload: &[u8],
+        interface: &Interface,
+    ) -> io::Result<()> {
+        for fragment in self.split(payload)? {
+            socket.send(&fragment, interface)?;
+        }
+        Ok(())
+    }
+}
+
+/// Payloads reassembled at once by default
+pub const DEFAULT_MAX_PARTIALS: usize = 64;
+/// Bytes of fragments buffered by default
+pub const DEFAULT_MAX_BUFFERED: usize = 4 * 1024 * 1024;
+
+struct Partial {
+    started: Instant,
+    count: usize,
+    fragments: BTreeMap<usize, Vec<u8>>,
+    buffered: usize,
+}
+
+pub struct Reassembler {
+    timeout: Duration,
+    max_partials: usize,
+    max_buffered: usize,
+    partials: HashMap<(SocketAddrV4, u32), Partial>,
+    // Bytes of fragments held by all the partials
+    buffered: usize,
+}
+
+impl Reassembler {
+    /// Payloads still missing fragments `timeout` after the first one arrived are dropped
+    pub fn new(timeout: Duration) -> Self {
+        Self::with_limits(timeout, DEFAULT_MAX_PARTIALS, DEFAULT_MAX_BUFFERED)
+    }
+
+    /// Same as `new`, holding at most `max_partials` payloads being reassembled and
+    /// `max_buffered` bytes of their fragments
+    pub fn with_limits(timeout: Duration, max_partials: usize, max_buffered: usize) -> Self {
+        Reassembler {
+            timeout,
+            max_partials,
+            max_buffered,
+            partials: HashMap::new(),
+            buffered: 0,
+        }
+    }
+
+    /// Feeds a received datagram. Returns the whole payload once its last fragment arrives,
+    /// keeping the metadata of that fragment. Datagrams without fragment framing are passed
+    /// through unchanged, but one of the application starting with the magic is taken for a
+    /// fragment.
+    pub fn push(&mut self, mut message: Message) -> Option<Message> {
+        self.expire();
+
+        let data = &message.data;
+        if data.len() < HEADER_LEN || &data[0..4] != MAGIC {
+            return Some(message);
+        }
+        let id = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        let index = usize::from(u16::from_be_bytes([data[8], data[9]]));
+        let count = usize::from(u16::from_be_bytes([data[10], data[11]]));
+        if index >= count {
+            return None;
+        }
+        let chunk = &data[HEADER_LEN..];
+
+        let key = (message.origin_address, id);
+        if !self.partials.contains_key(&key) {
+            if count == 1 {
+                message.data.drain(..HEADER_LEN);
+                return Some(message);
+            }
+            self.make_room(None, chunk.len())?;
+            self.partials.insert(
+                key,
+                Partial {
+                    started: Instant::now(),
+                    count,
+                    fragments: BTreeMap::new(),
+                    buffered: 0,
+                },
+            );
+        } else {
+            self.make_room(Some(&key), chunk.len())?;
+        }
+        let partial = self.partials.get_mut(&key)?;
+        if partial.count != count {
+            return None;
+        }
+        // Duplicates, e.g. received on several interfaces, are ignored
+        if let Entry::Vacant(entry) = partial.fragments.entry(index) {
+            entry.insert(chunk.to_vec());
+            partial.buffered += chunk.len();
+            self.buffered += chunk.len();
+        }
+        if partial.fragments.len() < partial.count {
+            return None;
+        }
+
+        let partial = self.remove(&key)?;
+        message.data = partial.fragments.into_values().flatten().collect();
+        Some(message)
+    }
+
+    /// Drops the oldest payloads other than the one of `adding_to` until `bytes` more fit, and
+    /// a new payload without `adding_to`. `None` when they don't fit even alone.
+    fn make_room(&mut self, adding_to: Option<&(SocketAddrV4, u32)>, bytes: usize) -> Option<()> {
+        let partials = usize::from(adding_to.is_none());
+        let kept = adding_to.and_then(|key| self.partials.get(key));
+        if partials > self.max_partials
+            || bytes + kept.map_or(0, |partial| partial.buffered) > self.max_buffered
+        {
+            return None;
+        }
+        while self.partials.len() + partials > self.max_partials
+            || self.buffered + bytes > self.max_buffered
+        {
+            let oldest = *self
+                .partials
+                .iter()
+                .filter(|(key, _)| Some(*key) != adding_to)
+                .min_by_key(|(_, partial)| partial.started)?
+                .0;
+            self.remove(&oldest);
+        }
+        Some(())
+    }
+
+    fn remove(&mut self, key: &(SocketAddrV4, u32)) -> Option<Partial> {
+        let partial = self.partials.remove(key)?;
+        self.buffered -= partial.buffered;
+        Some(partial)
+    }
+
+    /// Payloads being reassembled
+    pub fn pending(&self) -> usize {
+        self.partials.len()
+    }
+
+    /// Bytes of fragments buffered
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Drops the payloads that timed out. Called by `push`, exposed for idle periods.
+    pub fn expire(&mut self) {
+        let timeout = self.timeout;
+        let buffered = &mut self.buffered;
+        self.partials.retain(|_, partial| {
+            let keep = partial.started.elapsed() < timeout;
+            if !keep {
+                *buffered -= partial.buffered;
+            }
+            keep
+        });
+    }
+
+    /// Receives until a whole payload is available
+    pub fn receive(&mut self, socket: &MulticastSocket) -> io::Result<Message> {
+        loop {
+            if let Some(message) = self.push(socket.receive()?) {
+                return Ok(message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageFlags;
+    use std::net::Ipv4Addr;
+    use std::thread;
+
+    fn message(data: Vec<u8>) -> Message {
+        Message {
+            data,
+            origin_address: SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 5000),
+            interface: Interface::Default,
+            packet_info: None,
+            original_destination: None,
+            ttl: None,
+            tos: None,
+            received_at: None,
+            received_instant: None,
+            flags: MessageFlags::MULTICAST,
+        }
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn splits_and_reassembles() {
+        let mut fragmenter = Fragmenter::new(HEADER_LEN + 10);
+        let fragments = fragmenter.split(&payload(35)).unwrap();
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments.iter().all(|f| f.len() <= HEADER_LEN + 10));
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(1));
+        let (last, first) = fragments.split_last().unwrap();
+        for fragment in first {
+            assert!(reassembler.push(message(fragment.clone())).is_none());
+        }
+        let whole = reassembler.push(message(last.clone())).unwrap();
+        assert_eq!(whole.data, payload(35));
+        assert_eq!(reassembler.pending(), 0);
+        assert_eq!(reassembler.buffered(), 0);
+    }
+
+    #[test]
+    fn reassembles_out_of_order_and_ignores_duplicates() {
+        let mut fragmenter = Fragmenter::new(HEADER_LEN + 4);
+        let fragments = fragmenter.split(&payload(10)).unwrap();
+        let mut reassembler = Reassembler::new(Duration::from_secs(1));
+        assert!(reassembler.push(message(fragments[2].clone())).is_none());
+        assert!(reassembler.push(message(fragments[0].clone())).is_none());
+        assert!(reassembler.push(message(fragments[0].clone())).is_none());
+        assert!(reassembler.push(message(fragments[2].clone())).is_none());
+        let whole = reassembler.push(message(fragments[1].clone())).unwrap();
+        assert_eq!(whole.data, payload(10));
+    }
+
+    #[test]
+    fn single_fragment_and_unframed_datagrams() {
+        let mut fragmenter = Fragmenter::new(100);
+        let fragments = fragmenter.split(b"small").unwrap();
+        assert_eq!(fragments.len(), 1);
+        let mut reassembler = Reassembler::new(Duration::from_secs(1));
+        assert_eq!(
+            reassembler
+                .push(message(fragments[0].clone()))
+                .unwrap()
+                .data,
+            b"small"
+        );
+        assert_eq!(
+            reassembler.push(message(b"plain".to_vec())).unwrap().data,
+            b"plain"
+        );
+    }
+
+    #[test]
+    fn drops_payloads_missing_fragments_after_the_timeout() {
+        let mut fragmenter = Fragmenter::new(HEADER_LEN + 4);
+        let fragments = fragmenter.split(&payload(8)).unwrap();
+        let mut reassembler = Reassembler::new(Duration::from_millis(20));
+        assert!(reassembler.push(message(fragments[0].clone())).is_none());
+        thread::sleep(Duration::from_millis(30));
+        reassembler.expire();
+        assert_eq!(reassembler.pending(), 0);
+        assert_eq!(reassembler.buffered(), 0);
+        assert!(reassembler.push(message(fragments[1].clone())).is_none());
+    }
+
+    #[test]
+    fn spoofed_count_allocates_only_what_arrived() {
+        let mut fragment = Vec::from(&MAGIC[..]);
+        fragment.extend_from_slice(&7u32.to_be_bytes());
+        fragment.extend_from_slice(&0u16.to_be_bytes());
+        fragment.extend_from_slice(&u16::MAX.to_be_bytes());
+        fragment.extend_from_slice(b"data");
+        let mut reassembler = Reassembler::new(Duration::from_secs(1));
+        assert!(reassembler.push(message(fragment)).is_none());
+        assert_eq!(reassembler.buffered(), 4);
+    }
+
+    #[test]
+    fn evicts_the_oldest_payloads_beyond_the_partials_limit() {
+        let mut fragmenter = Fragmenter::new(HEADER_LEN + 4);
+        let mut reassembler = Reassembler::with_limits(Duration::from_secs(1), 2, 1000);
+        let payloads: Vec<_> = (0..3)
+            .map(|_| fragmenter.split(&payload(8)).unwrap())
+            .collect();
+        for fragments in &payloads {
+            assert!(reassembler.push(message(fragments[0].clone())).is_none());
+        }
+        assert_eq!(reassembler.pending(), 2);
+        assert!(reassembler.push(message(payloads[0][1].clone())).is_none());
+        let whole = reassembler.push(message(payloads[2][1].clone())).unwrap();
+        assert_eq!(whole.data, payload(8));
+    }
+
+    #[test]
+    fn evicts_the_oldest_payloads_beyond_the_bytes_limit() {
+        let mut fragmenter = Fragmenter::new(HEADER_LEN + 4);
+        let mut reassembler = Reassembler::with_limits(Duration::from_secs(1), 10, 8);
+        let payloads: Vec<_> = (0..3)
+            .map(|_| fragmenter.split(&payload(8)).unwrap())
+            .collect();
+        for fragments in &payloads {
+            assert!(reassembler.push(message(fragments[0].clone())).is_none());
+            assert!(reassembler.buffered() <= 8);
+        }
+        assert_eq!(reassembler.pending(), 2);
+        // Completing the second evicts the third, not itself
+        let whole = reassembler.push(message(payloads[1][1].clone())).unwrap();
+        assert_eq!(whole.data, payload(8));
+        assert_eq!(reassembler.pending(), 0);
+        assert_eq!(reassembler.buffered(), 0);
+
+        let too_large = Fragmenter::new(HEADER_LEN + 9).split(&payload(18)).unwrap();
+        assert!(reassembler.push(message(too_large[0].clone())).is_none());
+        assert_eq!(reassembler.pending(), 0);
+    }
+}
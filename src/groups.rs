@@ -0,0 +1,144 @@
+//! Well-known multicast groups and the ports their protocols use.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+/// Every host on the local network segment
+pub const ALL_HOSTS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 1);
+/// Every router on the local network segment
+pub const ALL_ROUTERS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 2);
+/// Destination of IGMPv3 membership reports
+pub const IGMPV3_ROUTERS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 22);
+
+/// Multicast DNS, RFC 6762
+pub const MDNS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_PORT: u16 = 5353;
+
+/// Link-Local Multicast Name Resolution, RFC 4795
+pub const LLMNR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 252);
+pub const LLMNR_PORT: u16 = 5355;
+
+/// Simple Service Discovery Protocol, used by UPnP
+pub const SSDP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+pub const SSDP_PORT: u16 = 1900;
+
+/// WS-Discovery, sharing its group with SSDP
+pub const WS_DISCOVERY: Ipv4Addr = SSDP;
+pub const WS_DISCOVERY_PORT: u16 = 3702;
+
+/// Session Announcement Protocol, RFC 2974, global scope
+pub const SAP: Ipv4Addr = Ipv4Addr::new(224, 2, 127, 254);
+pub const SAP_PORT: u16 = 9875;
+
+/// Precision Time Protocol, all messages except peer delay
+pub const PTP_PRIMARY: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 129);
+/// Precision Time Protocol peer delay messages
+pub const PTP_PDELAY: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 107);
+pub const PTP_EVENT_PORT: u16 = 319;
+pub const PTP_GENERAL_PORT: u16 = 320;
+
+/// Network Time Protocol
+pub const NTP: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 1);
+pub const NTP_PORT: u16 = 123;
+
+/// Group and port of mDNS, ready to pass to the constructors
+pub fn mdns() -> SocketAddrV4 {
+    SocketAddrV4::new(MDNS, MDNS_PORT)
+}
+
+/// Group and port of LLMNR
+pub fn llmnr() -> SocketAddrV4 {
+    SocketAddrV4::new(LLMNR, LLMNR_PORT)
+}
+
+/// Group and port of SSDP
+pub fn ssdp() -> SocketAddrV4 {
+    SocketAddrV4::new(SSDP, SSDP_PORT)
+}
+
+/// Group and port of SAP
+pub fn sap() -> SocketAddrV4 {
+    SocketAddrV4::new(SAP, SAP_PORT)
+}
+
+/// How far datagrams to a group travel, by the ranges of RFC 5771 and RFC 2365
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// `224.0.0.0/24`, never forwarded by routers
+    LinkLocal,
+    /// `232.0.0.0/8`, source-specific multicast, RFC 4607
+    SourceSpecific,
+    /// `233.0.0.0/8` up to `233.251.255.255`, static groups of an autonomous system, RFC 3180
+    Glop { autonomous_system: u16 },
+    /// `239.192.0.0/14`, kept within an organization
+    OrganizationLocal,
+    /// `239.255.0.0/16`, kept within a site
+    SiteLocal,
+    /// The rest of `239.0.0.0/8`, scoped as the administrators configured their routers
+    AdministrativelyScoped,
+    /// Any other group
+    Global,
+}
+
+/// Scope of `group`, `None` for addresses that aren't multicast
+pub fn scope(group: Ipv4Addr) -> Option<Scope> {
+    let octets = group.octets();
+    let scope = match octets {
+        _ if !group.is_multicast() => return None,
+        [224, 0, 0, _] => Scope::LinkLocal,
+        [232, ..] => Scope::SourceSpecific,
+        [233, high, low, _] if high <= 251 => Scope::Glop {
+            autonomous_system: u16::from_be_bytes([high, low]),
+        },
+        [239, 192..=195, ..] => Scope::OrganizationLocal,
+        [239, 255, ..] => Scope::SiteLocal,
+        [239, ..] => Scope::AdministrativelyScoped,
+        _ => Scope::Global,
+    };
+    Some(scope)
+}
+
+/// Organization-local group derived from `name`, the same for every host, skipping the groups
+/// of `taken`. `None` only once the whole `239.192.0.0/14` is taken. The groups of that range
+/// have distinct Ethernet addresses, so hosts of different groups don't receive each other's
+/// traffic through the hash filter of their NIC.
+pub fn organization_local_group(name: &str, taken: &[Ipv4Addr]) -> Option<Ipv4Addr> {
+    const BASE: u32 = 0xef_c0_00_00;
+    const SIZE: u32 = 1 << 18;
+    // FNV-1a, unlike the hasher of std it stays the same across Rust releases
+    let hash = name.bytes().fold(0x811c_9dc5u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    });
+    (0..SIZE)
+        .map(|probe| Ipv4Addr::from(BASE + (hash.wrapping_add(probe) % SIZE)))
+        .find(|group| !taken.contains(group))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_of_the_rfc_ranges() {
+        let scope_of = |a, b, c, d| scope(Ipv4Addr::new(a, b, c, d));
+        assert_eq!(scope_of(224, 0, 0, 251), Some(Scope::LinkLocal));
+        assert_eq!(scope_of(232, 1, 2, 3), Some(Scope::SourceSpecific));
+        assert_eq!(
+            scope_of(233, 1, 2, 3),
+            Some(Scope::Glop {
+                autonomous_system: 0x0102
+            })
+        );
+        assert_eq!(scope_of(233, 252, 0, 1), Some(Scope::Global));
+        assert_eq!(scope_of(239, 192, 0, 1), Some(Scope::OrganizationLocal));
+        assert_eq!(scope_of(239, 195, 255, 255), Some(Scope::OrganizationLocal));
+        assert_eq!(
+            scope_of(239, 196, 0, 1),
+            Some(Scope::AdministrativelyScoped)
+        );
+        assert_eq!(scope_of(239, 255, 255, 250), Some(Scope::SiteLocal));
+        assert_eq!(scope_of(239, 1, 1, 1), Some(Scope::AdministrativelyScoped));
+        assert_eq!(scope(SAP), Some(Scope::Global));
+        assert_eq!(scope_of(10, 0, 0, 1), None);
+        assert_eq!(scope_of(240, 0, 0, 1), None);
+    }
+}
@@ -0,0 +1,116 @@
+//! Trace logging of payloads, with the `trace-payloads` feature, for debugging garbled
+//! datagrams in the field. Each one is logged at trace level on the `multicast_socket::payload`
+//! target, with its first `MAX_DUMPED` bytes as hex and ASCII.
+
+use std::fmt::Write;
+use std::net::SocketAddrV4;
+
+use crate::capture::Direction;
+use crate::Interface;
+
+/// Bytes of each payload dumped, the rest is only counted
+const MAX_DUMPED: usize = 256;
+const TARGET: &str = "multicast_socket::payload";
+
+/// Logs `data`, received from or sent to `peer` on `interface`
+pub(crate) fn trace(direction: Direction, peer: &SocketAddrV4, interface: &Interface, data: &[u8]) {
+    if !log::log_enabled!(target: TARGET, log::Level::Trace) {
+        return;
+    }
+    let place = match direction {
+        Direction::Received => "from",
+        Direction::Sent => "to",
+    };
+    log::trace!(
+        target: TARGET,
+        "{:?} {} bytes {} {} on {:?}\n{}",
+        direction,
+        data.len(),
+        place,
+        peer,
+        interface,
+        dump(data)
+    );
+}
+
+/// 16 bytes a line, as `hexdump -C` does, followed by how many bytes were left out
+fn dump(data: &[u8]) -> String {
+    let dumped = &data[..data.len().min(MAX_DUMPED)];
+    let mut out = String::with_capacity(dumped.len() * 5);
+    for (line, chunk) in dumped.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", line * 16);
+        for i in 0..16 {
+            match chunk.get(i) {
+                Some(byte) => {
+                    let _ = write!(out, " {:02x}", byte);
+                }
+                None => out.push_str("   "),
+            }
+            if i == 7 {
+                out.push(' ');
+            }
+        }
+        out.push_str("  |");
+        out.extend(chunk.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    if data.len() > dumped.len() {
+        let _ = writeln!(out, "... {} more bytes", data.len() - dumped.len());
+    }
+    out.pop();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dumps_lines_of_16_bytes_with_their_offset() {
+        let dump = dump(b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789");
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(
+            lines[0],
+            "00000000  41 42 43 44 45 46 47 48  49 4a 4b 4c 4d 4e 4f 50  |ABCDEFGHIJKLMNOP|"
+        );
+        assert_eq!(
+            lines[1],
+            "00000010  51 52 53 54 55 56 57 58  59 5a 30 31 32 33 34 35  |QRSTUVWXYZ012345|"
+        );
+        assert!(lines[2].starts_with("00000020  36 37 38 39 "));
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn pads_a_short_last_line_to_align_the_ascii_column() {
+        let dump = dump(b"0123456789abcdefHi\n");
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines[1].find('|'), lines[0].find('|'));
+        assert_eq!(lines[1].len(), lines[0].len() - 13);
+        assert!(lines[1].starts_with("00000010  48 69 0a "));
+        assert!(lines[1].ends_with("  |Hi.|"));
+    }
+
+    #[test]
+    fn shows_non_printable_bytes_as_dots() {
+        assert!(dump(&[0x00, b'a', b' ', 0x7f, 0xff, b'~']).ends_with("|.a ..~|"));
+    }
+
+    #[test]
+    fn counts_the_bytes_beyond_the_dumped_ones() {
+        let exact = dump(&[0; MAX_DUMPED]);
+        assert_eq!(exact.lines().count(), MAX_DUMPED / 16);
+        assert!(!exact.contains("more bytes"));
+
+        let longer = dump(&[0; MAX_DUMPED + 10]);
+        assert_eq!(longer.lines().count(), MAX_DUMPED / 16 + 1);
+        assert_eq!(longer.lines().last(), Some("... 10 more bytes"));
+        assert!(!longer.ends_with('\n'));
+    }
+}
@@ -0,0 +1,365 @@
+//! Observing IGMP on the wire, to tell whether a querier exists and memberships get reported,
+//! the usual suspects when multicast stops after a few minutes behind IGMP snooping switches.
+//!
+//! `IgmpObserver` reads queries and reports from a raw socket, which takes `CAP_NET_RAW` or
+//! root. Reports sent to other groups reach it only while the host is a member of them, see
+//! `watch_group`. It joins `224.0.0.22` to see IGMPv3 reports, which the host reports in turn.
+
+use std::io;
+use std::io::IoSliceMut;
+use std::net::Ipv4Addr;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd};
+use std::time::Duration;
+
+use nix::sys::socket as sock;
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Destination of IGMPv3 reports, RFC 3376
+const IGMPV3_REPORTS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 22);
+
+const MEMBERSHIP_QUERY: u8 = 0x11;
+const V1_MEMBERSHIP_REPORT: u8 = 0x12;
+const V2_MEMBERSHIP_REPORT: u8 = 0x16;
+const V2_LEAVE_GROUP: u8 = 0x17;
+const V3_MEMBERSHIP_REPORT: u8 = 0x22;
+
+/// IGMP message seen on one of the observed interfaces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IgmpEvent {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub interface_index: u32,
+    pub message: IgmpMessage,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IgmpMessage {
+    /// General query when `group` is `0.0.0.0`, sent by the querier of the link
+    Query {
+        version: u8,
+        group: Ipv4Addr,
+        max_response: Duration,
+        /// Sources of an IGMPv3 group-and-source specific query
+        sources: Vec<Ipv4Addr>,
+    },
+    /// IGMPv1 or IGMPv2 membership report
+    Report { version: u8, group: Ipv4Addr },
+    /// IGMPv2 leave
+    Leave { group: Ipv4Addr },
+    /// IGMPv3 membership report
+    ReportV3 { records: Vec<GroupRecord> },
+}
+
+/// Group record of an IGMPv3 report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupRecord {
+    /// Record type of RFC 3376 section 4.2.12, e.g. 2 for `MODE_IS_EXCLUDE`
+    pub record_type: u8,
+    pub group: Ipv4Addr,
+    pub sources: Vec<Ipv4Addr>,
+}
+
+pub struct IgmpObserver {
+    socket: Socket,
+    // Index and address of each observed interface
+    interfaces: Vec<(u32, Ipv4Addr)>,
+}
+
+impl IgmpObserver {
+    /// Observes IGMP on the interfaces with these addresses, `receive` waiting up to `read_timeout`
+    pub fn new(interfaces: Vec<Ipv4Addr>, read_timeout: Duration) -> io::Result<Self> {
+        let socket = Socket::new(
+            Domain::ipv4(),
+            Type::raw(),
+            Some(Protocol::from(libc::IPPROTO_IGMP)),
+        )?;
+        socket.set_read_timeout(Some(read_timeout))?;
+        sock::setsockopt(&borrow_fd(&socket), sock::sockopt::Ipv4PacketInfo, &true)?;
+
+        let mut indexed = Vec::with_capacity(interfaces.len());
+        for interface in interfaces {
+            indexed.push((crate::interface_index(interface)?, interface));
+            socket.join_multicast_v4(&IGMPV3_REPORTS, &interface)?;
+        }
+
+        Ok(IgmpObserver {
+            socket,
+            interfaces: indexed,
+        })
+    }
+
+    /// Joins `group` on the observed interfaces, so the reports of other hosts for it arrive
+    pub fn watch_group(&self, group: Ipv4Addr) -> io::Result<()> {
+        for (_, interface) in &self.interfaces {
+            match self.socket.join_multicast_v4(&group, interface) {
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the next IGMP message on one of the observed interfaces, skipping malformed
+    /// ones. Fails with `WouldBlock` once the read timeout elapsed.
+    pub fn receive(&self) -> io::Result<IgmpEvent> {
+        let mut buffer = [0; 1500];
+        loop {
+            let mut control_buffer = nix::cmsg_space!(libc::in_pktinfo);
+            let mut iov = [IoSliceMut::new(&mut buffer)];
+            let message = sock::recvmsg::<sock::SockaddrIn>(
+                self.socket.as_raw_fd(),
+                &mut iov,
+                Some(&mut control_buffer),
+                sock::MsgFlags::empty(),
+            )?;
+
+            let mut interface_index = None;
+            for cmsg in message.cmsgs()? {
+                if let sock::ControlMessageOwned::Ipv4PacketInfo(pktinfo) = cmsg {
+                    interface_index = Some(crate::unix::packet_info_index(&pktinfo));
+                }
+            }
+            let bytes = message.bytes;
+
+            let interface_index = match interface_index {
+                Some(index) if self.interfaces.iter().any(|(i, _)| *i == index) => index,
+                _ => continue,
+            };
+            if let Some((source, destination, message)) = parse_packet(&buffer[..bytes]) {
+                return Ok(IgmpEvent {
+                    source,
+                    destination,
+                    interface_index,
+                    message,
+                });
+            }
+        }
+    }
+}
+
+impl AsRawFd for IgmpObserver {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl AsFd for IgmpObserver {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        borrow_fd(&self.socket)
+    }
+}
+
+// socket2 predates `AsFd`, but the descriptor lives as long as the socket
+fn borrow_fd(socket: &Socket) -> BorrowedFd<'_> {
+    unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) }
+}
+
+/// Source, destination and message of an IPv4 packet carrying IGMP, raw sockets see the IP header
+fn parse_packet(packet: &[u8]) -> Option<(Ipv4Addr, Ipv4Addr, IgmpMessage)> {
+    let header_len = usize::from(packet.first()? & 0x0f) * 4;
+    if header_len < 20 || packet.len() < header_len {
+        return None;
+    }
+    let source = address(packet, 12)?;
+    let destination = address(packet, 16)?;
+    Some((source, destination, parse_igmp(&packet[header_len..])?))
+}
+
+fn parse_igmp(igmp: &[u8]) -> Option<IgmpMessage> {
+    if igmp.len() < 8 {
+        return None;
+    }
+    let group = address(igmp, 4)?;
+    match igmp[0] {
+        MEMBERSHIP_QUERY if igmp.len() >= 12 => {
+            let count = usize::from(u16::from_be_bytes([igmp[10], igmp[11]]));
+            let sources = (0..count)
+                .map(|i| address(igmp, 12 + 4 * i))
+                .collect::<Option<_>>()?;
+            Some(IgmpMessage::Query {
+                version: 3,
+                group,
+                max_response: tenths(decode_code(igmp[1])),
+                sources,
+            })
+        }
+        // IGMPv1 queries leave the maximum response time at 0, it's 10 seconds for them
+        MEMBERSHIP_QUERY if igmp[1] == 0 => Some(IgmpMessage::Query {
+            version: 1,
+            group,
+            max_response: Duration::from_secs(10),
+            sources: Vec::new(),
+        }),
+        MEMBERSHIP_QUERY => Some(IgmpMessage::Query {
+            version: 2,
+            group,
+            max_response: tenths(u32::from(igmp[1])),
+            sources: Vec::new(),
+        }),
+        V1_MEMBERSHIP_REPORT => Some(IgmpMessage::Report { version: 1, group }),
+        V2_MEMBERSHIP_REPORT => Some(IgmpMessage::Report { version: 2, group }),
+        V2_LEAVE_GROUP => Some(IgmpMessage::Leave { group }),
+        V3_MEMBERSHIP_REPORT => {
+            // Sized as the records parse, the count is only as good as the packet
+            let count = usize::from(u16::from_be_bytes([igmp[6], igmp[7]]));
+            let mut records = Vec::new();
+            let mut at = 8;
+            for _ in 0..count {
+                let header = igmp.get(at..at + 8)?;
+                let auxiliary_len = usize::from(header[1]) * 4;
+                let sources_count = usize::from(u16::from_be_bytes([header[2], header[3]]));
+                let sources = (0..sources_count)
+                    .map(|i| address(igmp, at + 8 + 4 * i))
+                    .collect::<Option<_>>()?;
+                records.push(GroupRecord {
+                    record_type: header[0],
+                    group: address(header, 4)?,
+                    sources,
+                });
+                at += 8 + 4 * sources_count + auxiliary_len;
+            }
+            Some(IgmpMessage::ReportV3 { records })
+        }
+        _ => None,
+    }
+}
+
+/// Maximum response code of an IGMPv3 query in tenths of seconds, RFC 3376 section 4.1.1
+fn decode_code(code: u8) -> u32 {
+    if code < 128 {
+        return u32::from(code);
+    }
+    let mantissa = u32::from(code & 0x0f) | 0x10;
+    let exponent = u32::from((code >> 4) & 0x07);
+    mantissa << (exponent + 3)
+}
+
+fn tenths(tenths: u32) -> Duration {
+    Duration::from_millis(u64::from(tenths) * 100)
+}
+
+fn address(buf: &[u8], at: usize) -> Option<Ipv4Addr> {
+    let octets = buf.get(at..at + 4)?;
+    Some(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_response_codes() {
+        assert_eq!(decode_code(0), 0);
+        assert_eq!(decode_code(100), 100);
+        assert_eq!(decode_code(127), 127);
+        // Exponent 0, mantissa 0: 0x10 << 3
+        assert_eq!(decode_code(0x80), 128);
+        assert_eq!(decode_code(0xff), 31744);
+    }
+
+    #[test]
+    fn parses_queries_of_each_version() {
+        let v1 = [0x11, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            parse_igmp(&v1),
+            Some(IgmpMessage::Query {
+                version: 1,
+                group: Ipv4Addr::UNSPECIFIED,
+                max_response: Duration::from_secs(10),
+                sources: Vec::new(),
+            })
+        );
+
+        let v2 = [0x11, 100, 0, 0, 239, 1, 2, 3];
+        assert_eq!(
+            parse_igmp(&v2),
+            Some(IgmpMessage::Query {
+                version: 2,
+                group: Ipv4Addr::new(239, 1, 2, 3),
+                max_response: Duration::from_secs(10),
+                sources: Vec::new(),
+            })
+        );
+
+        let v3 = [
+            0x11, 0x80, 0, 0, 239, 1, 2, 3, 0, 125, 0, 2, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        assert_eq!(
+            parse_igmp(&v3),
+            Some(IgmpMessage::Query {
+                version: 3,
+                group: Ipv4Addr::new(239, 1, 2, 3),
+                max_response: Duration::from_millis(12800),
+                sources: vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)],
+            })
+        );
+    }
+
+    #[test]
+    fn parses_reports_and_leaves() {
+        let group = Ipv4Addr::new(239, 1, 2, 3);
+        assert_eq!(
+            parse_igmp(&[0x12, 0, 0, 0, 239, 1, 2, 3]),
+            Some(IgmpMessage::Report { version: 1, group })
+        );
+        assert_eq!(
+            parse_igmp(&[0x16, 0, 0, 0, 239, 1, 2, 3]),
+            Some(IgmpMessage::Report { version: 2, group })
+        );
+        assert_eq!(
+            parse_igmp(&[0x17, 0, 0, 0, 239, 1, 2, 3]),
+            Some(IgmpMessage::Leave { group })
+        );
+        assert_eq!(parse_igmp(&[0x42, 0, 0, 0, 239, 1, 2, 3]), None);
+        assert_eq!(parse_igmp(&[0x16, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn parses_v3_reports_skipping_auxiliary_data() {
+        let report = [
+            0x22, 0, 0, 0, 0, 0, 0, 2, // Two records
+            4, 1, 0, 1, 239, 1, 2, 3, 10, 0, 0, 1, 0xaa, 0xbb, 0xcc,
+            0xdd, // One word auxiliary
+            2, 0, 0, 0, 239, 4, 5, 6,
+        ];
+        assert_eq!(
+            parse_igmp(&report),
+            Some(IgmpMessage::ReportV3 {
+                records: vec![
+                    GroupRecord {
+                        record_type: 4,
+                        group: Ipv4Addr::new(239, 1, 2, 3),
+                        sources: vec![Ipv4Addr::new(10, 0, 0, 1)],
+                    },
+                    GroupRecord {
+                        record_type: 2,
+                        group: Ipv4Addr::new(239, 4, 5, 6),
+                        sources: Vec::new(),
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_counts_beyond_the_packet() {
+        let report = [0x22, 0, 0, 0, 0, 0, 0xff, 0xff, 2, 0, 0, 0, 239, 4, 5, 6];
+        assert_eq!(parse_igmp(&report), None);
+        let query = [0x11, 10, 0, 0, 239, 1, 2, 3, 0, 125, 0xff, 0xff];
+        assert_eq!(parse_igmp(&query), None);
+    }
+
+    #[test]
+    fn parses_the_ip_header_with_options() {
+        let mut packet = vec![0x46, 0, 0, 32, 0, 0, 0, 0, 1, 2, 0, 0];
+        packet.extend_from_slice(&[192, 0, 2, 1, 224, 0, 0, 1]);
+        // Router alert
+        packet.extend_from_slice(&[0x94, 0x04, 0, 0]);
+        packet.extend_from_slice(&[0x11, 100, 0, 0, 0, 0, 0, 0]);
+        let (source, destination, message) = parse_packet(&packet).unwrap();
+        assert_eq!(source, Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(destination, Ipv4Addr::new(224, 0, 0, 1));
+        assert!(matches!(message, IgmpMessage::Query { version: 2, .. }));
+        assert_eq!(parse_packet(&packet[..22]), None);
+    }
+}
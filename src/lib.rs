@@ -1,4 +1,5 @@
-use std::time::Duration;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime};
 
 #[cfg(windows)]
 mod win;
@@ -10,18 +11,754 @@ mod unix;
 #[cfg(not(windows))]
 pub use unix::*;
 
+mod dedup;
+mod egress;
+#[cfg(feature = "trace-payloads")]
+mod hexdump;
+mod membership;
+mod readiness;
+mod socket;
+mod stats;
+#[cfg(feature = "metrics")]
+mod telemetry;
+
+pub use socket::{default_route_interface, directed_broadcast};
+
+pub mod batch;
+#[cfg(any(target_os = "linux", target_os = "android", windows))]
+pub mod bridge;
+#[cfg(all(feature = "calloop", not(windows)))]
+pub mod calloop_source;
+pub mod capture;
+#[cfg(feature = "serde")]
+pub mod config;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fragment;
+pub mod groups;
+#[cfg(not(windows))]
+pub mod igmp;
+pub mod pacing;
+pub mod per_interface;
+pub mod ping;
+pub mod pool;
+pub mod receiver;
+pub mod relay;
+pub mod replay;
+pub mod schedule;
+#[cfg(feature = "tokio")]
+pub mod send_queue;
+#[cfg(feature = "simulation")]
+pub mod sim;
+#[cfg(feature = "smoltcp")]
+pub mod smoltcp_socket;
+pub mod socket_set;
+
+/// What `IP_PKTINFO` tells about a received datagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketInfo {
+    pub interface_index: u32,
+    /// Local address the datagram was routed to, to answer from the matching address.
+    /// Not reported on Windows.
+    pub local_address: Option<std::net::Ipv4Addr>,
+    /// Destination address of the IP header, usually the group
+    pub destination: std::net::Ipv4Addr,
+}
+
+/// Delivery anomalies and kinds of a received datagram, see `Message::flags`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct MessageFlags(u8);
+
+impl MessageFlags {
+    /// The datagram was longer than the receive buffer, the rest is lost
+    pub const TRUNCATED: MessageFlags = MessageFlags(1);
+    /// Control messages didn't fit in `MulticastOptions::control_buffer_size`, so the packet
+    /// info or other ancillary data may be missing
+    pub const CONTROL_TRUNCATED: MessageFlags = MessageFlags(1 << 1);
+    /// Sent to a broadcast address, the limited or a directed one
+    pub const BROADCAST: MessageFlags = MessageFlags(1 << 2);
+    /// Sent to a multicast address
+    pub const MULTICAST: MessageFlags = MessageFlags(1 << 3);
+
+    pub const fn empty() -> Self {
+        MessageFlags(0)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn contains(self, other: MessageFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for MessageFlags {
+    type Output = MessageFlags;
+
+    fn bitor(self, other: MessageFlags) -> MessageFlags {
+        MessageFlags(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for MessageFlags {
+    fn bitor_assign(&mut self, other: MessageFlags) {
+        self.0 |= other.0;
+    }
+}
+
+/// A received datagram borrowing the receive buffer of the socket, see `receive_ref`
+#[derive(Debug)]
+pub struct MessageRef<'a> {
+    pub data: &'a [u8],
+    pub origin_address: std::net::SocketAddrV4,
+    pub interface: Interface,
+    pub packet_info: Option<PacketInfo>,
+    pub original_destination: Option<std::net::SocketAddrV4>,
+    pub ttl: Option<u8>,
+    pub tos: Option<u8>,
+    pub received_at: Option<SystemTime>,
+    pub received_instant: Option<Instant>,
+    pub flags: MessageFlags,
+}
+
+impl MessageRef<'_> {
+    /// Copies the data out, to keep the datagram past the next receive
+    pub fn into_owned(self) -> Message {
+        Message {
+            data: self.data.to_vec(),
+            origin_address: self.origin_address,
+            interface: self.interface,
+            packet_info: self.packet_info,
+            original_destination: self.original_destination,
+            ttl: self.ttl,
+            tos: self.tos,
+            received_at: self.received_at,
+            received_instant: self.received_instant,
+            flags: self.flags,
+        }
+    }
+
+    /// Differentiated services codepoint, the upper 6 bits of `tos`
+    pub fn dscp(&self) -> Option<u8> {
+        self.tos.map(|tos| tos >> 2)
+    }
+
+    /// ECN codepoint, the lower 2 bits of `tos`
+    pub fn ecn(&self) -> Option<Ecn> {
+        self.tos.map(Ecn::from_tos)
+    }
+
+    /// Same datagram with `data` instead, to release the buffer while the rest is kept
+    pub(crate) fn with_data(self, data: &[u8]) -> MessageRef<'_> {
+        MessageRef {
+            data,
+            origin_address: self.origin_address,
+            interface: self.interface,
+            packet_info: self.packet_info,
+            original_destination: self.original_destination,
+            ttl: self.ttl,
+            tos: self.tos,
+            received_at: self.received_at,
+            received_instant: self.received_instant,
+            flags: self.flags,
+        }
+    }
+}
+
+/// Explicit congestion notification codepoints of RFC 3168, the lower 2 bits of the TOS byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ecn {
+    NotEct = 0b00,
+    Ect1 = 0b01,
+    Ect0 = 0b10,
+    /// Congestion experienced, set by a router instead of dropping the datagram
+    Ce = 0b11,
+}
+
+impl Ecn {
+    pub fn from_tos(tos: u8) -> Self {
+        match tos & 0b11 {
+            0b00 => Ecn::NotEct,
+            0b01 => Ecn::Ect1,
+            0b10 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
+}
+
+/// Parameters of a single datagram sent with `MulticastSocket::send_with`, the defaults
+/// sending like `send` on the default interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendOptions {
+    pub interface: Interface,
+    /// TTL of this datagram instead of the multicast TTL of the socket, 1 unless set. Sent along
+    /// as a control message on Linux, switched on the socket around the send elsewhere.
+    pub ttl: Option<u8>,
+    /// TOS byte of this datagram, sent along as a control message
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub tos: Option<u8>,
+    /// ECN codepoint of this datagram, replacing the lower bits of `tos`, and sent with a DSCP
+    /// of 0 without it
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub ecn: Option<Ecn>,
+    /// Multicast loopback of this datagram, switched on the socket around the send
+    pub loopback: Option<bool>,
+    /// Sets `MSG_DONTROUTE`, sending only to hosts on the link of the interface
+    pub dont_route: bool,
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        SendOptions {
+            interface: Interface::Default,
+            ttl: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            tos: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            ecn: None,
+            loopback: None,
+            dont_route: false,
+        }
+    }
+}
+
+/// Clock of the receive timestamps, see `MulticastOptions::receive_timestamps`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum TimestampClock {
+    /// Wall clock time of `SO_TIMESTAMPNS` in `Message::received_at`, for logging events
+    Realtime,
+    /// Also maps the kernel timestamp to an `Instant` in `Message::received_instant`, to
+    /// measure latency against `Instant::now()`. The mapping reads both clocks on receive, so a
+    /// wall clock step while the datagram was queued shifts it.
+    Monotonic,
+}
+
+/// Path MTU discovery modes of `IP_MTU_DISCOVER`, see `ip(7)`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum MtuDiscovery {
+    /// Never sets the don't fragment bit, letting routers fragment, for legacy networks
+    Dont,
+    /// Uses the path MTU when known, fragmenting otherwise
+    Want,
+    /// Always sets the don't fragment bit. Larger datagrams fail with `EMSGSIZE`, and routers
+    /// report a smaller path MTU, see `MulticastOptions::receive_errors`.
+    Do,
+    /// Sets the don't fragment bit ignoring the path MTU, to probe it
+    Probe,
+}
+
+/// How the kernel spreads datagrams over the sockets sharing the port with `SO_REUSEPORT`,
+/// instead of by a hash of the whole 4-tuple. Sockets are numbered in the order they were bound,
+/// the program attached last applies to all of them.
+///
+/// Linux only steers unicast datagrams this way, multicast and broadcast ones are still
+/// delivered to every socket, so this matters for sockets bound to `0.0.0.0` receiving both.
+#[cfg(target_os = "linux")]
+#[derive(Clone)]
+pub enum ReuseportSteering {
+    /// By source address, modulo the number of sockets, keeping each sender on one socket
+    SourceAddress { sockets: u32 },
+    /// By the CPU handling the datagram, modulo the number of sockets
+    Cpu { sockets: u32 },
+    /// Classic BPF program returning the index of the socket, `SO_ATTACH_REUSEPORT_CBPF`
+    Program(Vec<libc::sock_filter>),
+    /// Descriptor of a loaded eBPF program, `SO_ATTACH_REUSEPORT_EBPF`
+    Ebpf(std::os::unix::io::RawFd),
+}
+
+/// Retries of sends failing transiently, see `MulticastOptions::send_retry`
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct SendRetry {
+    /// Attempts after the first one
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each one after
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "config::duration"))]
+    pub backoff: Duration,
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "config::duration"))]
+    pub max_backoff: Duration,
+}
+
+impl SendRetry {
+    /// Delay before retry number `retry`, counting from 0
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.backoff
+            .saturating_mul(1 << retry.min(31))
+            .min(self.max_backoff)
+    }
+}
+
+/// Memberships as seen by the kernel, see `MulticastSocket::health`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Health {
+    /// Pairs of group and interface the kernel still holds
+    pub held: Vec<(Ipv4Addr, Ipv4Addr)>,
+    /// Pairs joined by the socket the kernel lost, nothing arrives through them anymore
+    pub lost: Vec<(Ipv4Addr, Ipv4Addr)>,
+}
+
+impl Health {
+    pub fn is_healthy(&self) -> bool {
+        self.lost.is_empty()
+    }
+}
+
+/// Receive buffer of a socket next to the system limit, see `MulticastSocket::receive_buffer`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceiveBuffer {
+    /// Size asked for with `MulticastOptions::receive_buffer_size`
+    pub requested: Option<usize>,
+    /// Size reported by the kernel. Linux doubles the size it granted, for its bookkeeping.
+    pub effective: usize,
+    /// System-wide maximum, see `max_receive_buffer`
+    pub max: Option<usize>,
+    /// Whether the kernel granted less than requested
+    pub clamped: bool,
+    /// Command raising the maximum so the requested size is granted, when it was clamped
+    pub raise_with: Option<String>,
+}
+
+/// Range of IPv4 addresses such as `10.20.0.0/16`, see `MulticastSocket::in_networks`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ipv4Cidr {
+    network: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    /// Range of the `prefix_len` leading bits of `address`, the others are cleared.
+    /// Fails with `InvalidInput` for prefixes longer than 32 bits.
+    pub fn new(address: Ipv4Addr, prefix_len: u8) -> std::io::Result<Self> {
+        if prefix_len > 32 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("prefix length {} is longer than 32 bits", prefix_len),
+            ));
+        }
+        let mask = Self::mask_of(prefix_len);
+        Ok(Ipv4Cidr {
+            network: Ipv4Addr::from(u32::from(address) & mask),
+            prefix_len,
+        })
+    }
+
+    fn mask_of(prefix_len: u8) -> u32 {
+        u32::MAX
+            .checked_shl(32 - u32::from(prefix_len))
+            .unwrap_or(0)
+    }
+
+    pub fn network(&self) -> Ipv4Addr {
+        self.network
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        u32::from(address) & Self::mask_of(self.prefix_len) == u32::from(self.network)
+    }
+}
+
+/// Parses `address/prefix_len`, a bare address being a `/32`
+impl std::str::FromStr for Ipv4Cidr {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> std::io::Result<Self> {
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{:?} is not an IPv4 range like 10.20.0.0/16", s),
+            )
+        };
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, prefix_len.parse().map_err(|_| invalid())?),
+            None => (s, 32),
+        };
+        Ipv4Cidr::new(address.parse().map_err(|_| invalid())?, prefix_len)
+    }
+}
+
+impl std::fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Snapshot of the counters of a socket, see `MulticastSocket::stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Datagrams returned by the receive calls
+    pub received: u64,
+    pub received_bytes: u64,
+    pub sent: u64,
+    pub sent_bytes: u64,
+    /// Datagrams dropped as own, with `MulticastOptions::ignore_own_packets`
+    pub own_packets: u64,
+    /// Datagrams dropped as duplicates, with `MulticastOptions::dedup_window`
+    pub duplicates: u64,
+    /// Datagrams dropped for their destination, not the group or an accepted broadcast
+    pub filtered: u64,
+    /// Failed receives, besides the read timeout
+    pub receive_errors: u64,
+    /// Sends that failed, after the retries
+    pub send_errors: u64,
+    /// Most datagrams waiting at once in the channel of `spawn_tokio_receiver`. The channel of
+    /// `spawn_receiver` is unbounded and handed out as is, so it never fills up and isn't tracked.
+    pub receive_queue_high_water: u64,
+    /// Time the receiving task spent blocked on a full channel, or dropping datagrams
+    /// with `Overflow::DropNewest`
+    pub receive_queue_full: Duration,
+    /// Datagrams dropped on a full channel, with `Overflow::DropNewest`
+    pub receive_queue_dropped: u64,
+    /// Most datagrams waiting at once in the queue of `spawn_tokio_sender`
+    pub send_queue_high_water: u64,
+    /// Time producers waited for room in the full queue of `spawn_tokio_sender`
+    pub send_queue_full: Duration,
+}
+
+/// Callback receiving the socket during construction, see `MulticastOptions::configure`
+pub type ConfigureSocket =
+    std::sync::Arc<dyn Fn(&socket2::Socket) -> std::io::Result<()> + Send + Sync>;
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
 pub struct MulticastOptions {
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "config::duration"))]
     pub read_timeout: Duration,
+    /// Sends waiting longer for room in the send buffer fail with `WouldBlock` on unix and
+    /// `TimedOut` on Windows, instead of blocking on a congested interface
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "config::option_duration"))]
+    pub write_timeout: Option<Duration>,
     pub loopback: bool,
-    pub buffer_size: usize,
+    /// Keeps datagrams on this host, for IPC between processes: the multicast TTL is set to 0,
+    /// so nothing leaves through the interfaces, and loopback is enabled regardless of `loopback`
+    pub host_local: bool,
+    /// Size of the receive buffer, longer datagrams are truncated.
+    /// Defaults to the largest MTU of the joined interfaces.
+    pub buffer_size: Option<usize>,
+    /// Size of the buffer for ancillary data, such as the packet info. Defaults to what the
+    /// packet info takes, raise it when `configure` enables more control messages. Datagrams
+    /// whose control messages didn't fit carry `MessageFlags::CONTROL_TRUNCATED`.
+    pub control_buffer_size: Option<usize>,
+    /// Sets `SO_RCVBUF`, the kernel queue of datagrams waiting to be received, to ride out
+    /// bursts. The kernel may clamp it to a system-wide maximum, see `receive_buffer`.
+    pub receive_buffer_size: Option<usize>,
+    /// Sets the IP router alert option on outgoing datagrams, so routers look at them closely.
+    /// Receiving router alert packets addressed to routers takes a raw socket instead.
+    pub router_alert: bool,
+    /// Sets `SO_DONTROUTE`, so datagrams only reach directly connected networks and never go
+    /// through a gateway. Without any route, sends with `Interface::Default` fail with
+    /// `ENETUNREACH` unless `set_default_send_interface` pins one. Windows may ignore it.
+    pub dont_route: bool,
+    /// Sets `IP_FREEBIND`, so the socket can be created before the addresses of its interfaces
+    /// exist, e.g. while DHCP is still running. Joining on such an interface is skipped,
+    /// `rejoin_all` or `rejoin_interval` join it once the address shows up.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub freebind: bool,
+    /// Sets `SO_MARK` on outgoing datagrams, for policy routing and nftables rules.
+    /// Needs `CAP_NET_ADMIN`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub mark: Option<u32>,
+    /// Sets `IP_RECVORIGDSTADDR`, reporting the destination of datagrams before a TPROXY or
+    /// NAT redirection in `Message::original_destination`, unlike the packet info
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub original_destination: bool,
+    /// Sets `IP_RECVTTL`, reporting the TTL the datagrams arrived with in `Message::ttl`,
+    /// e.g. to only accept them from the link with a TTL of 255
+    #[cfg(any(target_os = "linux", target_os = "android", windows))]
+    pub receive_ttl: bool,
+    /// Sets `IP_RECVTOS`, reporting the TOS byte of the datagrams in `Message::tos`, split by
+    /// `Message::dscp` and `Message::ecn`
+    #[cfg(any(target_os = "linux", target_os = "android", windows))]
+    pub receive_tos: bool,
+    /// Sends `Interface::Default` datagrams out of the interface the routing table names for
+    /// the group, looked up with `route_interface` at construction, instead of leaving the
+    /// choice to the kernel per datagram. See `MulticastSocket::routed_interface`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub route_default_interface: bool,
+    /// Sets `SO_TIMESTAMPNS`, reporting when the kernel received the datagrams
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub receive_timestamps: Option<TimestampClock>,
+    /// Sets `IP_RECVERR`, queueing ICMP errors and local send failures about sent datagrams,
+    /// such as unreachable destinations or a too small path MTU, for `receive_error`.
+    /// A `receive` may then fail once with the error of the latest notification.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub receive_errors: bool,
+    /// Sets `IP_MTU_DISCOVER`, whether datagrams carry the don't fragment bit
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub mtu_discovery: Option<MtuDiscovery>,
+    /// Binds the socket to a Linux VRF device, confining it to that routing domain.
+    /// The interfaces should be enslaved to the VRF, see `vrf_ipv4_interfaces`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub vrf: Option<String>,
+    /// Sets `SO_REUSEADDR`, and `SO_REUSEPORT` on unix, so several sockets can listen to the group.
+    /// Without it binding fails with `AddrInUse` when another socket has the port, to detect a
+    /// running instance. On Windows `SO_EXCLUSIVEADDRUSE` is set instead, so it can't be taken over.
+    pub reuse_address: bool,
+    /// Attaches a program picking the socket of each datagram among those sharing the port,
+    /// needs `reuse_address`
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub reuseport_steering: Option<ReuseportSteering>,
+    /// Binds to `0.0.0.0` instead of the group address on Linux, as done on the other platforms,
+    /// for applications expecting it. Datagrams not addressed to the group, such as unicast,
+    /// are then dropped while receiving, on all platforms.
+    pub bind_unspecified: bool,
+    /// Sets `SO_BROADCAST` for `send_broadcast`, and receives datagrams sent to the limited
+    /// broadcast address `255.255.255.255` on the port besides the group, binding to `0.0.0.0`.
+    /// Directed broadcasts to the subnets of the interfaces are received as well. Those are told
+    /// apart by the destination of their packet info, unicast is dropped still.
+    pub broadcast: bool,
+    /// Binds to `0.0.0.0` on an ephemeral port instead of the port of the group, for sockets
+    /// mostly sending to the group and receiving unicast replies. Datagrams sent to the group
+    /// are not received then. The chosen port is returned by `local_addr`.
+    pub ephemeral_port: bool,
+    /// Sends with `Interface::Ip`, as `broadcast` does, go through sockets dedicated to each
+    /// interface, created on first use with `IP_MULTICAST_IF` fixed, so they can run in parallel.
+    /// They are bound to ephemeral ports and get the loopback and TTL of the socket, the options
+    /// shaping outgoing datagrams, `router_alert`, `dont_route` and on Linux `mark`, `vrf` and
+    /// `mtu_discovery`, and `configure`.
+    pub dedicated_send_sockets: bool,
+    /// Drops datagrams whose origin and payload were already received within this window,
+    /// such as the copies delivered once per interface when several share a broadcast domain
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "config::option_duration"))]
+    pub dedup_window: Option<Duration>,
+    /// Drops datagrams sent by this socket, while keeping loopback enabled for other local
+    /// consumers. Those come from one of the joined interfaces, on the port the socket is bound to
+    /// or the one of a dedicated send socket.
+    ///
+    /// Bound to the port of the group, which other local sockets share with `reuse_address`, the
+    /// datagrams they send to the group are dropped as well, as they come from the same address
+    /// and port. Only with `ephemeral_port` does the socket send from a port of its own, so only
+    /// its own datagrams are dropped.
+    pub ignore_own_packets: bool,
+    /// Drops datagrams whose source address is outside the subnets of the interface they came
+    /// in on, misrouted or spoofed ones, but also the ones of hosts without an address yet.
+    /// The subnets are looked up at construction, datagrams without packet info are kept.
+    pub same_subnet_sources: bool,
+    /// Limits the rate of `send` and `broadcast`, each datagram taking a token
+    pub pacing: Option<pacing::Pacing>,
+    /// Retries sends failing transiently with `ENOBUFS` or `WouldBlock`, as small NIC drivers do
+    /// under bursts. Once the retries are used up the error names the interface.
+    pub send_retry: Option<SendRetry>,
+    /// Retries `receive` and `send` when interrupted by a signal, instead of failing with
+    /// `ErrorKind::Interrupted`
+    pub retry_interrupted: bool,
+    /// Spins on non-blocking receives for this long before blocking in `receive`, for consumers
+    /// that trade a busy core while traffic flows for latency that doesn't depend on the
+    /// scheduler waking the thread. The read timeout starts once the spinning is over.
+    /// On Windows, the non-blocking receives have the caveat of `try_receive`.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "config::option_duration"))]
+    pub receive_spin: Option<Duration>,
+    /// Mirrors every datagram received or sent into a pcapng capture, see `capture`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub capture: Option<std::sync::Arc<capture::PcapngCapture>>,
+    /// Leaves the group on every interface when the socket is dropped, instead of relying on the
+    /// kernel to do it once the last descriptor referring to the socket is closed.
+    /// `into_inner` keeps the memberships regardless.
+    pub leave_on_drop: bool,
+    /// Checks the local addresses at this interval while receiving, and joins the group again
+    /// when they changed, as memberships can be lost on carrier loss or resume from sleep
+    #[cfg(feature = "enumerate")]
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "config::option_duration"))]
+    pub rejoin_interval: Option<Duration>,
+    /// Leaves interfaces whose only IPv4 addresses are link-local, `169.254.0.0/16` as assigned
+    /// without DHCP server, out of the ones enumerated by `all_interfaces_with_options` and
+    /// `in_networks`, as replies sent there rarely reach anyone
+    #[cfg(feature = "enumerate")]
+    pub skip_link_local_interfaces: bool,
+    /// Leaves and joins the group again at this interval while receiving, as `rejoin_all` does,
+    /// for switches and access points whose IGMP snooping ages memberships out regardless of
+    /// the reports answering their queries. Each refresh sends a fresh membership report.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "config::option_duration"))]
+    pub membership_refresh: Option<Duration>,
+    /// Performs every setsockopt and ioctl at construction, so the steady state only needs
+    /// `recvmsg`, `sendmsg` and `sendto` (dedicated send sockets), `poll` for
+    /// `MulticastSocketSet`, futexes, `clock_gettime`/`nanosleep` for pacing and retries,
+    /// memory allocation and finally `close`, for strict seccomp sandboxes. Dedicated send
+    /// sockets are opened up front for every interface, `memberships` doesn't read
+    /// `/proc/net/igmp` and the kernel leaves the groups on close, ignoring `leave_on_drop`.
+    /// Calls reconfiguring the socket, like `set_write_timeout` or `rejoin_all`, still do syscalls
+    /// of their own, `send_with_loopback` fails. Can't be combined with `rejoin_interval`.
+    /// On Windows non-blocking receives still switch the blocking mode with `ioctlsocket`.
+    pub pre_configured: bool,
+    /// Invoked once the crate options are applied, right before joining and binding,
+    /// to set socket options this crate doesn't model (SO_MARK, BPF filters...)
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub configure: Option<ConfigureSocket>,
 }
 
 impl Default for MulticastOptions {
     fn default() -> Self {
         MulticastOptions {
             read_timeout: Duration::from_secs(1),
+            write_timeout: None,
             loopback: true,
-            buffer_size: 512,
+            host_local: false,
+            router_alert: false,
+            dont_route: false,
+            reuse_address: true,
+            #[cfg(target_os = "linux")]
+            reuseport_steering: None,
+            bind_unspecified: false,
+            broadcast: false,
+            ephemeral_port: false,
+            dedicated_send_sockets: false,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            freebind: false,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            mark: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            original_destination: false,
+            #[cfg(any(target_os = "linux", target_os = "android", windows))]
+            receive_ttl: false,
+            #[cfg(any(target_os = "linux", target_os = "android", windows))]
+            receive_tos: false,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            route_default_interface: false,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            receive_timestamps: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            receive_errors: false,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            mtu_discovery: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            vrf: None,
+            buffer_size: None,
+            control_buffer_size: None,
+            receive_buffer_size: None,
+            dedup_window: None,
+            ignore_own_packets: false,
+            same_subnet_sources: false,
+            pacing: None,
+            send_retry: None,
+            retry_interrupted: true,
+            receive_spin: None,
+            capture: None,
+            leave_on_drop: true,
+            #[cfg(feature = "enumerate")]
+            rejoin_interval: None,
+            #[cfg(feature = "enumerate")]
+            skip_link_local_interfaces: false,
+            membership_refresh: None,
+            pre_configured: false,
+            configure: None,
+        }
+    }
+}
+
+/// Plain UDP view of the socket. Memberships are kept, as they belong to the descriptor.
+impl From<MulticastSocket> for UdpSocket {
+    fn from(socket: MulticastSocket) -> Self {
+        socket.into_inner().into_udp_socket()
+    }
+}
+
+/// Adopts a socket bound to a multicast group address, the way Linux sockets of this crate are
+/// bound, and joins it on all interfaces. Memberships already held by the socket are kept.
+/// Sockets bound to `0.0.0.0` carry no group, use `MulticastSocket::from_socket` for them.
+#[cfg(feature = "enumerate")]
+impl std::convert::TryFrom<UdpSocket> for MulticastSocket {
+    type Error = std::io::Error;
+
+    fn try_from(socket: UdpSocket) -> std::io::Result<Self> {
+        match socket.local_addr()? {
+            std::net::SocketAddr::V4(address) if address.ip().is_multicast() => {
+                MulticastSocket::from_socket(
+                    socket,
+                    address,
+                    all_ipv4_interfaces()?,
+                    Default::default(),
+                )
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "socket is not bound to an IPv4 multicast address",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Ipv4Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ranges_and_clears_host_bits() {
+        let range = cidr("10.20.3.4/16");
+        assert_eq!(range.network(), Ipv4Addr::new(10, 20, 0, 0));
+        assert_eq!(range.prefix_len(), 16);
+        assert_eq!(range.to_string(), "10.20.0.0/16");
+        assert!(range.contains(Ipv4Addr::new(10, 20, 255, 1)));
+        assert!(!range.contains(Ipv4Addr::new(10, 21, 0, 0)));
+    }
+
+    #[test]
+    fn covers_everything_with_a_zero_prefix() {
+        let range = cidr("192.0.2.1/0");
+        assert_eq!(range.network(), Ipv4Addr::UNSPECIFIED);
+        assert!(range.contains(Ipv4Addr::new(255, 255, 255, 255)));
+        assert!(range.contains(Ipv4Addr::UNSPECIFIED));
+    }
+
+    #[test]
+    fn takes_a_bare_address_as_a_single_host() {
+        let host = Ipv4Addr::new(192, 0, 2, 1);
+        assert_eq!(cidr("192.0.2.1"), cidr("192.0.2.1/32"));
+        assert!(cidr("192.0.2.1").contains(host));
+        assert!(!cidr("192.0.2.1").contains(Ipv4Addr::new(192, 0, 2, 2)));
+    }
+
+    #[test]
+    fn rejects_malformed_ranges() {
+        for s in [
+            "10.0.0.0/33",
+            "10.0.0.0/",
+            "10.0.0.0/x",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "",
+        ] {
+            let e = s.parse::<Ipv4Cidr>().unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput, "{:?}", s);
         }
+        assert!(Ipv4Cidr::new(Ipv4Addr::UNSPECIFIED, 33).is_err());
+    }
+
+    #[test]
+    fn doubles_the_retry_delay_up_to_the_maximum() {
+        let retry = SendRetry {
+            retries: 5,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        };
+        assert_eq!(retry.delay(0), Duration::from_millis(10));
+        assert_eq!(retry.delay(1), Duration::from_millis(20));
+        assert_eq!(retry.delay(3), Duration::from_millis(80));
+        assert_eq!(retry.delay(7), Duration::from_secs(1));
+        assert_eq!(retry.delay(u32::MAX), Duration::from_secs(1));
     }
 }
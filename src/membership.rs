@@ -0,0 +1,247 @@
+use std::io;
+use std::mem::ManuallyDrop;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[cfg(not(windows))]
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, FromRawSocket, RawSocket};
+
+use socket2::Socket;
+
+/// Group memberships of a socket, left when dropped if armed.
+///
+/// `MulticastSocket` can't implement `Drop` itself, as `into_inner` moves the socket out, so
+/// this is one of its fields instead, declared first to be dropped while the descriptor is open.
+pub(crate) struct Memberships {
+    #[cfg(not(windows))]
+    descriptor: RawFd,
+    #[cfg(windows)]
+    descriptor: RawSocket,
+    group: Ipv4Addr,
+    // Interfaces the group was joined on
+    interfaces: Mutex<Vec<Ipv4Addr>>,
+    leave_on_drop: bool,
+}
+
+impl Memberships {
+    pub(crate) fn new(
+        socket: &Socket,
+        group: Ipv4Addr,
+        interfaces: Vec<Ipv4Addr>,
+        leave_on_drop: bool,
+    ) -> Self {
+        Memberships {
+            #[cfg(not(windows))]
+            descriptor: socket.as_raw_fd(),
+            #[cfg(windows)]
+            descriptor: socket.as_raw_socket(),
+            group,
+            interfaces: Mutex::new(interfaces),
+            leave_on_drop,
+        }
+    }
+
+    /// Leaves the group on every interface, going on after failures. Returns the first error.
+    pub(crate) fn leave(&mut self) -> io::Result<()> {
+        // Borrows the descriptor of the owning `MulticastSocket`, which stays open meanwhile
+        #[cfg(not(windows))]
+        let socket = ManuallyDrop::new(unsafe { Socket::from_raw_fd(self.descriptor) });
+        #[cfg(windows)]
+        let socket = ManuallyDrop::new(unsafe { Socket::from_raw_socket(self.descriptor) });
+
+        let mut result = Ok(());
+        let interfaces = self.interfaces.get_mut().unwrap_or_else(|e| e.into_inner());
+        for interface in interfaces.drain(..) {
+            let left = socket.leave_multicast_v4(&self.group, &interface);
+            if result.is_ok() {
+                result = left;
+            }
+        }
+        result
+    }
+
+    /// Switches to another group, once the memberships of the current one were left
+    pub(crate) fn set_group(&mut self, group: Ipv4Addr) {
+        self.group = group;
+    }
+
+    /// Keeps the memberships in place, for sockets handed over to the application
+    pub(crate) fn forget(&mut self) {
+        self.interfaces
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+
+    /// Records a membership joined after construction, e.g. by `rejoin_all`
+    pub(crate) fn add(&self, interface: Ipv4Addr) {
+        let mut interfaces = self.interfaces.lock().unwrap_or_else(|e| e.into_inner());
+        if !interfaces.contains(&interface) {
+            interfaces.push(interface);
+        }
+    }
+
+    pub(crate) fn remove(&self, interface: Ipv4Addr) {
+        let mut interfaces = self.interfaces.lock().unwrap_or_else(|e| e.into_inner());
+        interfaces.retain(|i| *i != interface);
+    }
+
+    /// Pairs of group and interface
+    pub(crate) fn list(&self) -> Vec<(Ipv4Addr, Ipv4Addr)> {
+        let interfaces = self.interfaces.lock().unwrap_or_else(|e| e.into_inner());
+        interfaces.iter().map(|i| (self.group, *i)).collect()
+    }
+}
+
+impl Drop for Memberships {
+    fn drop(&mut self) {
+        if self.leave_on_drop {
+            let _ = self.leave();
+        }
+    }
+}
+
+/// Groups joined on each interface index, as listed by `/proc/net/igmp` for the network
+/// namespace of the calling thread
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn kernel_memberships() -> io::Result<std::collections::HashSet<(u32, Ipv4Addr)>> {
+    Ok(parse_igmp_table(&std::fs::read_to_string(
+        "/proc/net/igmp",
+    )?))
+}
+
+/// Parses the text of `/proc/net/igmp`, a line per device followed by one indented line per
+/// group joined on it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn parse_igmp_table(table: &str) -> std::collections::HashSet<(u32, Ipv4Addr)> {
+    let mut memberships = std::collections::HashSet::new();
+    let mut index = None;
+    for line in table.lines().skip(1) {
+        let mut fields = line.split_whitespace();
+        if line.starts_with(char::is_whitespace) {
+            // Group joined on the device above, printed as a native integer of the network order bytes
+            let group = fields.next().and_then(|g| u32::from_str_radix(g, 16).ok());
+            if let (Some(index), Some(group)) = (index, group) {
+                memberships.insert((index, Ipv4Addr::from(u32::from_be(group))));
+            }
+        } else {
+            index = fields.next().and_then(|i| i.parse().ok());
+        }
+    }
+    memberships
+}
+
+/// Tells when the memberships are due to be joined again, for `membership_refresh`
+pub(crate) struct MembershipRefresh {
+    interval: Duration,
+    refreshed_at: Instant,
+}
+
+impl MembershipRefresh {
+    pub(crate) fn new(interval: Duration) -> Self {
+        MembershipRefresh {
+            interval,
+            refreshed_at: Instant::now(),
+        }
+    }
+
+    /// Whether the interval elapsed since the last refresh, restarting it if so
+    pub(crate) fn due(&mut self) -> bool {
+        if self.refreshed_at.elapsed() < self.interval {
+            return false;
+        }
+        self.refreshed_at = Instant::now();
+        true
+    }
+}
+
+/// Notices changes of the local addresses, such as a DHCP renewal or a resume from sleep,
+/// after which memberships may have been lost
+#[cfg(feature = "enumerate")]
+pub(crate) struct AddressWatch {
+    interval: Duration,
+    checked_at: Instant,
+    addresses: Vec<Ipv4Addr>,
+}
+
+#[cfg(feature = "enumerate")]
+impl AddressWatch {
+    pub(crate) fn new(interval: Duration) -> Self {
+        AddressWatch {
+            interval,
+            checked_at: Instant::now(),
+            addresses: local_addresses(),
+        }
+    }
+
+    /// Whether the local addresses changed since the last check, looking at most once per interval
+    pub(crate) fn changed(&mut self) -> bool {
+        if self.checked_at.elapsed() < self.interval {
+            return false;
+        }
+        self.checked_at = Instant::now();
+        let addresses = local_addresses();
+        if addresses == self.addresses {
+            return false;
+        }
+        self.addresses = addresses;
+        true
+    }
+}
+
+#[cfg(feature = "enumerate")]
+fn local_addresses() -> Vec<Ipv4Addr> {
+    let mut addresses = crate::all_ipv4_interfaces().unwrap_or_default();
+    addresses.sort();
+    addresses
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::*;
+
+    // As the kernel prints a group, the network order bytes read as a native integer
+    fn group(address: [u8; 4]) -> String {
+        format!("{:08X}", u32::from_ne_bytes(address))
+    }
+
+    #[test]
+    fn parses_the_groups_of_each_device() {
+        let table = format!(
+            "Idx\tDevice    : Count Querier\tGroup    Users Timer\tReporter\n\
+             1\tlo        :     1      V3\n\
+             \t\t\t\t{}     1 0:00000000\t\t0\n\
+             4\teth0      :     2      V2\n\
+             \t\t\t\t{}     1 0:00000000\t\t0\n\
+             \t\t\t\t{}     2 0:00000000\t\t1\n\
+             5\twlan0     :     0      V3\n",
+            group([224, 0, 0, 1]),
+            group([224, 0, 0, 251]),
+            group([239, 255, 255, 250]),
+        );
+        let memberships = parse_igmp_table(&table);
+        assert_eq!(memberships.len(), 3);
+        assert!(memberships.contains(&(1, Ipv4Addr::new(224, 0, 0, 1))));
+        assert!(memberships.contains(&(4, Ipv4Addr::new(224, 0, 0, 251))));
+        assert!(memberships.contains(&(4, Ipv4Addr::new(239, 255, 255, 250))));
+    }
+
+    #[test]
+    fn skips_groups_without_a_device_and_malformed_lines() {
+        let table = format!(
+            "Idx\tDevice    : Count Querier\tGroup    Users Timer\tReporter\n\
+             \t\t\t\t{}     1 0:00000000\t\t0\n\
+             x\tbad       :     1      V3\n\
+             \t\t\t\t{}     1 0:00000000\t\t0\n\
+             2\teth0      :     1      V3\n\
+             \t\t\t\tnot-hex     1 0:00000000\t\t0\n",
+            group([224, 0, 0, 1]),
+            group([224, 0, 0, 251]),
+        );
+        assert!(parse_igmp_table(&table).is_empty());
+        assert!(parse_igmp_table("").is_empty());
+    }
+}
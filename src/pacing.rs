@@ -0,0 +1,135 @@
+//! Token bucket pacing for outgoing datagrams, so announcement storms don't overflow the
+//! multicast queues of switches.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Sustained rate and burst allowed by a `Pacer`
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct Pacing {
+    pub packets_per_second: u32,
+    /// Datagrams that can be sent back to back after a quiet period
+    pub burst: u32,
+}
+
+struct Bucket {
+    // Goes negative when sends are reserved ahead of time
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+pub struct Pacer {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl Pacer {
+    /// A zero rate or burst is raised to one
+    pub fn new(pacing: Pacing) -> Self {
+        let burst = f64::from(pacing.burst.max(1));
+        Pacer {
+            rate: f64::from(pacing.packets_per_second.max(1)),
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token and returns how long to wait before sending with it.
+    /// Concurrent callers are queued one after the other.
+    pub fn reserve(&self) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.refilled_at = now;
+
+        bucket.tokens -= 1.0;
+        if bucket.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        }
+    }
+
+    /// Takes a token if one is left, or returns how long until one is, without queuing behind
+    /// the callers of `reserve`
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Blocks the thread until a send is allowed
+    pub fn wait(&self) {
+        let delay = self.reserve();
+        if delay > Duration::from_secs(0) {
+            thread::sleep(delay);
+        }
+    }
+
+    /// Awaits until a send is allowed, without blocking the runtime
+    #[cfg(feature = "tokio")]
+    pub async fn wait_async(&self) {
+        let delay = self.reserve();
+        if delay > Duration::from_secs(0) {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pacer(packets_per_second: u32, burst: u32) -> Pacer {
+        Pacer::new(Pacing {
+            packets_per_second,
+            burst,
+        })
+    }
+
+    #[test]
+    fn sends_a_burst_then_paces() {
+        let pacer = pacer(10, 3);
+        for _ in 0..3 {
+            assert_eq!(pacer.reserve(), Duration::ZERO);
+        }
+        // A tenth of a second per token, less the time elapsed since
+        let wait = pacer.reserve();
+        assert!(wait > Duration::from_millis(50) && wait <= Duration::from_millis(100));
+        // Queued behind the one reserved ahead
+        let wait = pacer.reserve();
+        assert!(wait > Duration::from_millis(150) && wait <= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn try_acquire_fails_without_a_token() {
+        let pacer = pacer(10, 1);
+        assert_eq!(pacer.try_acquire(), Ok(()));
+        let wait = pacer.try_acquire().unwrap_err();
+        assert!(wait > Duration::from_millis(50) && wait <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn raises_a_zero_rate_and_burst() {
+        let pacer = pacer(0, 0);
+        assert_eq!(pacer.try_acquire(), Ok(()));
+        let wait = pacer.try_acquire().unwrap_err();
+        assert!(wait > Duration::from_millis(500) && wait <= Duration::from_secs(1));
+    }
+}
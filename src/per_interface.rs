@@ -0,0 +1,185 @@
+//! One underlying socket per interface, behind the `MulticastSocket` API.
+//!
+//! Each socket joins on a single interface and has its egress interface fixed, and on Linux is
+//! also bound to the interface device. This sidesteps kernel quirks of sockets joined on several
+//! interfaces, like a wrong interface index in the packet info on some platforms or reverse path
+//! filtering, at the cost of one descriptor per interface.
+//!
+//! The sockets share the port of the group, which takes `reuse_address`. Elsewhere than on
+//! Linux they aren't bound to their device, so each one receives the datagrams of every
+//! interface, and keeps only those whose packet info names its own.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::{poll_readable, Interface, Message, MulticastOptions, MulticastSocket};
+
+pub struct PerInterfaceSocket {
+    sockets: Vec<MulticastSocket>,
+    // Index of the interface of each socket
+    indexes: Vec<u32>,
+    read_timeout: Duration,
+    // Datagrams drained from ready sockets but not returned yet
+    pending: Mutex<VecDeque<Message>>,
+    // First drain error, returned once the datagrams drained before it are
+    error: Mutex<Option<io::Error>>,
+}
+
+impl PerInterfaceSocket {
+    /// One socket per interface with `options`, which need `reuse_address` for more than one
+    pub fn with_options(
+        multicast_address: SocketAddrV4,
+        interfaces: Vec<Ipv4Addr>,
+        options: MulticastOptions,
+    ) -> io::Result<Self> {
+        if interfaces.len() > 1 && !options.reuse_address {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the sockets of the interfaces share the port, which needs `reuse_address`",
+            ));
+        }
+        let read_timeout = options.read_timeout;
+        let indexes = interfaces
+            .iter()
+            .map(|interface| crate::interface_index(*interface))
+            .collect::<io::Result<_>>()?;
+        let sockets = interfaces
+            .into_iter()
+            .map(|interface| {
+                let socket = MulticastSocket::with_options(
+                    multicast_address,
+                    vec![interface],
+                    options.clone(),
+                )?;
+                socket.set_default_send_interface(&Interface::Ip(interface))?;
+                #[cfg(target_os = "linux")]
+                {
+                    let name = crate::interface_name(crate::interface_index(interface)?)?;
+                    let name = std::ffi::CString::new(name)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    socket.as_socket().bind_device(Some(&name))?;
+                }
+                Ok(socket)
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(PerInterfaceSocket {
+            sockets,
+            indexes,
+            read_timeout,
+            pending: Mutex::new(VecDeque::new()),
+            error: Mutex::new(None),
+        })
+    }
+
+    /// Waits up to the read timeout for a datagram on any of the sockets,
+    /// failing with `WouldBlock` if none arrives. A socket failing to receive doesn't lose the
+    /// datagrams of the others, its error is returned once they are.
+    pub fn receive(&self) -> io::Result<Message> {
+        if let Some(message) = self.pending().pop_front() {
+            return Ok(message);
+        }
+        if let Some(e) = self.error().take() {
+            return Err(e);
+        }
+
+        // Polled without holding `pending`, so other receivers can take what is drained meanwhile
+        let sockets: Vec<_> = self.sockets.iter().map(|s| s.as_socket()).collect();
+        let readable = poll_readable(&sockets, self.read_timeout)?;
+        let mut drained = Vec::new();
+        for ((socket, index), readable) in self.sockets.iter().zip(&self.indexes).zip(readable) {
+            if !readable {
+                continue;
+            }
+            // The other sockets are still drained, their datagrams returned first
+            match socket.drain() {
+                Ok(messages) => drained.extend(
+                    messages
+                        .into_iter()
+                        .filter(|message| arrived_on(message, *index)),
+                ),
+                Err(e) => {
+                    self.error().get_or_insert(e);
+                }
+            }
+        }
+
+        let mut pending = self.pending();
+        pending.extend(drained);
+        if let Some(message) = pending.pop_front() {
+            return Ok(message);
+        }
+        match self.error().take() {
+            Some(e) => Err(e),
+            None => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "no datagram within the read timeout",
+            )),
+        }
+    }
+
+    fn pending(&self) -> MutexGuard<'_, VecDeque<Message>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn error(&self) -> MutexGuard<'_, Option<io::Error>> {
+        self.error.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Sends from the socket of `interface`. `Interface::Default` uses the first one.
+    pub fn send(&self, buf: &[u8], interface: &Interface) -> io::Result<usize> {
+        let address = match interface {
+            Interface::Default => None,
+            Interface::Ip(address) => Some(*address),
+            Interface::Index(index) => crate::interface_ips(*index)?
+                .into_iter()
+                .find(|address| self.socket_of(*address).is_some()),
+        };
+        let socket = match address {
+            Some(address) => self.socket_of(address),
+            None if matches!(interface, Interface::Default) => self.sockets.first(),
+            None => None,
+        };
+        match socket {
+            Some(socket) => socket.send(buf, &Interface::Default),
+            None => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "no socket on this interface",
+            )),
+        }
+    }
+
+    pub fn broadcast(&self, buf: &[u8]) -> io::Result<()> {
+        for socket in &self.sockets {
+            socket.send(buf, &Interface::Default)?;
+        }
+        Ok(())
+    }
+
+    /// Addresses of the interfaces, one per socket
+    pub fn interfaces(&self) -> Vec<Ipv4Addr> {
+        self.sockets.iter().flat_map(|s| s.interfaces()).collect()
+    }
+
+    /// The sockets, one per interface
+    pub fn sockets(&self) -> &[MulticastSocket] {
+        &self.sockets
+    }
+
+    fn socket_of(&self, address: Ipv4Addr) -> Option<&MulticastSocket> {
+        self.sockets
+            .iter()
+            .find(|s| s.interfaces().contains(&address))
+    }
+}
+
+/// Whether `message` came in on the interface `index`, taken for granted without packet info
+fn arrived_on(message: &Message, index: u32) -> bool {
+    match message.interface {
+        Interface::Index(arrived) => arrived == index,
+        _ => true,
+    }
+}
@@ -0,0 +1,324 @@
+//! Multicast reachability diagnostics: "can anyone hear me on this VLAN?"
+//!
+//! `MulticastPing` sends a timestamped probe out of every interface of a socket and collects
+//! the echoes sent back by peers, either running `MulticastPing` themselves or answering with
+//! `respond`. Probes carry the interface they were sent from, so echoes are attributed to it
+//! regardless of the interface they come back on.
+//!
+//! `MulticastPing::measure` sends several rounds of probes, for the RTT, jitter and loss of
+//! each responder from `InterfaceReport::rtt`.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::socket::is_timeout;
+use crate::{Interface, Message, MulticastSocket};
+
+const MAGIC: &[u8; 4] = b"MCPG";
+const PROBE_LEN: usize = 25;
+
+const KIND_REQUEST: u8 = 1;
+const KIND_REPLY: u8 = 2;
+
+struct Probe {
+    kind: u8,
+    id: u32,
+    sequence: u32,
+    interface: Ipv4Addr,
+    // Nanoseconds since the start of the pinging session, opaque to responders
+    timestamp: u64,
+}
+
+impl Probe {
+    fn encode(&self) -> [u8; PROBE_LEN] {
+        let mut buf = [0; PROBE_LEN];
+        buf[0..4].copy_from_slice(MAGIC);
+        buf[4] = self.kind;
+        buf[5..9].copy_from_slice(&self.id.to_be_bytes());
+        buf[9..13].copy_from_slice(&self.sequence.to_be_bytes());
+        buf[13..17].copy_from_slice(&self.interface.octets());
+        buf[17..25].copy_from_slice(&self.timestamp.to_be_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Probe> {
+        if buf.len() != PROBE_LEN || &buf[0..4] != MAGIC {
+            return None;
+        }
+        let word = |at: usize| u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]]);
+
+        Some(Probe {
+            kind: buf[4],
+            id: word(5),
+            sequence: word(9),
+            interface: Ipv4Addr::from(word(13)),
+            timestamp: u64::from(word(17)) << 32 | u64::from(word(21)),
+        })
+    }
+}
+
+/// Answers `message` if it is a ping probe, sending the echo out of the interface it arrived on.
+/// Returns whether the message was a probe, so it can be skipped by the application.
+pub fn respond(socket: &MulticastSocket, message: &Message) -> io::Result<bool> {
+    match Probe::decode(&message.data) {
+        Some(probe) if probe.kind == KIND_REQUEST => {
+            let reply = Probe {
+                kind: KIND_REPLY,
+                ..probe
+            };
+            socket.send(&reply.encode(), &message.interface)?;
+            Ok(true)
+        }
+        Some(_) => Ok(true),
+        None => Ok(false),
+    }
+}
+
+/// An echo received for a probe
+#[derive(Debug, Clone)]
+pub struct Echo {
+    pub responder: SocketAddrV4,
+    pub sequence: u32,
+    pub rtt: Duration,
+}
+
+/// What was heard back for the probes sent out of one interface
+#[derive(Debug, Clone)]
+pub struct InterfaceReport {
+    pub interface: Ipv4Addr,
+    /// Probes sent out of the interface
+    pub probes: u32,
+    pub echoes: Vec<Echo>,
+}
+
+impl InterfaceReport {
+    pub fn is_reachable(&self) -> bool {
+        !self.echoes.is_empty()
+    }
+
+    /// RTT statistics of each responder, in the order they first answered
+    pub fn rtt(&self) -> Vec<ResponderRtt> {
+        let mut responders: Vec<(SocketAddrV4, Vec<&Echo>)> = Vec::new();
+        for echo in &self.echoes {
+            match responders.iter_mut().find(|(r, _)| *r == echo.responder) {
+                Some((_, echoes)) => echoes.push(echo),
+                None => responders.push((echo.responder, vec![echo])),
+            }
+        }
+
+        responders
+            .into_iter()
+            .map(|(responder, mut echoes)| {
+                // Echoes heard twice, e.g. on two interfaces, count once
+                echoes.sort_by_key(|e| e.sequence);
+                echoes.dedup_by_key(|e| e.sequence);
+                let rtts: Vec<Duration> = echoes.iter().map(|e| e.rtt).collect();
+                ResponderRtt::new(responder, self.probes, &rtts)
+            })
+            .collect()
+    }
+}
+
+/// RTT of the echoes of one responder to the probes of one interface
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponderRtt {
+    pub responder: SocketAddrV4,
+    pub received: u32,
+    /// Probes the responder didn't answer, or whose echo was lost
+    pub lost: u32,
+    pub min: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+    /// Mean difference between the RTTs of consecutive probes, zero with less than two
+    pub jitter: Duration,
+}
+
+impl ResponderRtt {
+    // `rtts` is in the order of the probes, and not empty
+    fn new(responder: SocketAddrV4, probes: u32, rtts: &[Duration]) -> Self {
+        let received = rtts.len() as u32;
+        let differences: Duration = rtts.windows(2).map(|pair| pair[1].abs_diff(pair[0])).sum();
+        ResponderRtt {
+            responder,
+            received,
+            lost: probes.saturating_sub(received),
+            min: rtts.iter().copied().min().unwrap_or_default(),
+            mean: rtts.iter().sum::<Duration>() / received.max(1),
+            max: rtts.iter().copied().max().unwrap_or_default(),
+            jitter: differences / received.saturating_sub(1).max(1),
+        }
+    }
+}
+
+pub struct MulticastPing<'a> {
+    socket: &'a MulticastSocket,
+    id: u32,
+    sequence: u32,
+    started: Instant,
+}
+
+impl<'a> MulticastPing<'a> {
+    pub fn new(socket: &'a MulticastSocket) -> Self {
+        // Tells our echoes apart from the ones of other pinging peers
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        MulticastPing {
+            socket,
+            id: nanos ^ std::process::id().rotate_left(16),
+            sequence: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Sends one probe per interface, then collects echoes for `wait`.
+    /// Probes of other peers received meanwhile are answered.
+    pub fn ping(&mut self, wait: Duration) -> io::Result<Vec<InterfaceReport>> {
+        self.measure(1, wait)
+    }
+
+    /// Sends `rounds` probes per interface, `interval` apart, collecting echoes meanwhile and
+    /// for `interval` after the last round. Probes of other peers are answered.
+    pub fn measure(&mut self, rounds: u32, interval: Duration) -> io::Result<Vec<InterfaceReport>> {
+        let mut reports: Vec<InterfaceReport> = self
+            .socket
+            .interfaces()
+            .into_iter()
+            .map(|interface| InterfaceReport {
+                interface,
+                probes: 0,
+                echoes: Vec::new(),
+            })
+            .collect();
+
+        for _ in 0..rounds {
+            self.send_probes(&mut reports)?;
+            self.collect(Instant::now() + interval, &mut reports)?;
+        }
+        Ok(reports)
+    }
+
+    fn send_probes(&mut self, reports: &mut [InterfaceReport]) -> io::Result<()> {
+        for report in reports {
+            self.sequence = self.sequence.wrapping_add(1);
+            let probe = Probe {
+                kind: KIND_REQUEST,
+                id: self.id,
+                sequence: self.sequence,
+                interface: report.interface,
+                timestamp: self.started.elapsed().as_nanos() as u64,
+            };
+            self.socket
+                .send(&probe.encode(), &Interface::Ip(report.interface))?;
+            report.probes += 1;
+        }
+        Ok(())
+    }
+
+    fn collect(&self, deadline: Instant, reports: &mut [InterfaceReport]) -> io::Result<()> {
+        while Instant::now() < deadline {
+            let message = match self.socket.receive() {
+                Ok(message) => message,
+                Err(e) if is_timeout(&e) => continue,
+                Err(e) => return Err(e),
+            };
+            let probe = match Probe::decode(&message.data) {
+                Some(probe) => probe,
+                None => continue,
+            };
+
+            match probe.kind {
+                KIND_REQUEST if probe.id != self.id => {
+                    respond(self.socket, &message)?;
+                }
+                KIND_REPLY if probe.id == self.id => {
+                    let sent = self.started + Duration::from_nanos(probe.timestamp);
+                    let echo = Echo {
+                        responder: message.origin_address,
+                        sequence: probe.sequence,
+                        rtt: Instant::now().saturating_duration_since(sent),
+                    };
+                    if let Some(report) =
+                        reports.iter_mut().find(|r| r.interface == probe.interface)
+                    {
+                        report.echoes.push(echo);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn responder(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), port)
+    }
+
+    fn echo(port: u16, sequence: u32, millis: u64) -> Echo {
+        Echo {
+            responder: responder(port),
+            sequence,
+            rtt: Duration::from_millis(millis),
+        }
+    }
+
+    #[test]
+    fn rtt_statistics_of_a_responder() {
+        let ms = Duration::from_millis;
+        let rtt = ResponderRtt::new(responder(1), 4, &[ms(10), ms(30), ms(20)]);
+        assert_eq!(rtt.received, 3);
+        assert_eq!(rtt.lost, 1);
+        assert_eq!((rtt.min, rtt.mean, rtt.max), (ms(10), ms(20), ms(30)));
+        // Consecutive differences of 20 and 10
+        assert_eq!(rtt.jitter, ms(15));
+
+        let single = ResponderRtt::new(responder(1), 1, &[ms(7)]);
+        assert_eq!((single.lost, single.jitter), (0, Duration::ZERO));
+    }
+
+    #[test]
+    fn report_groups_echoes_by_responder_once_per_probe() {
+        let report = InterfaceReport {
+            interface: Ipv4Addr::new(192, 0, 2, 2),
+            probes: 2,
+            echoes: vec![
+                echo(2, 1, 5),
+                echo(1, 0, 10),
+                echo(1, 0, 12),
+                echo(1, 1, 20),
+            ],
+        };
+        let rtt = report.rtt();
+        assert_eq!(rtt.len(), 2);
+        assert_eq!(rtt[0].responder, responder(2));
+        assert_eq!((rtt[0].received, rtt[0].lost), (1, 1));
+        assert_eq!(rtt[1].responder, responder(1));
+        assert_eq!((rtt[1].received, rtt[1].lost), (2, 0));
+        assert_eq!(rtt[1].min, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn probes_round_trip() {
+        let probe = Probe {
+            kind: KIND_REQUEST,
+            id: 0xdead_beef,
+            sequence: 7,
+            interface: Ipv4Addr::new(192, 0, 2, 2),
+            timestamp: 0x0102_0304_0506_0708,
+        };
+        let decoded = Probe::decode(&probe.encode()).unwrap();
+        assert_eq!(decoded.kind, KIND_REQUEST);
+        assert_eq!((decoded.id, decoded.sequence), (0xdead_beef, 7));
+        assert_eq!(decoded.interface, probe.interface);
+        assert_eq!(decoded.timestamp, probe.timestamp);
+        assert!(Probe::decode(&probe.encode()[..PROBE_LEN - 1]).is_none());
+        assert!(Probe::decode(b"not a probe at all, not at all").is_none());
+    }
+}
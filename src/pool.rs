@@ -0,0 +1,159 @@
+//! Receiving on several sockets sharing the group and port with `SO_REUSEPORT`, one worker
+//! thread each, to spread the processing of a busy group over several cores.
+//!
+//! The kernel delivers every multicast and broadcast datagram to each socket of the port, so
+//! each worker only handles those whose source address falls on its shard, the way
+//! `ReuseportSteering::SourceAddress` spreads unicast. Unicast, received with
+//! `bind_unspecified`, only reaches one socket and is always handled, spread by the steering
+//! program of the options on Linux, or a hash of the 4-tuple without one.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::socket::is_timeout;
+use crate::{Message, MessageFlags, MulticastOptions, MulticastSocket};
+
+pub struct MulticastSocketPool {
+    sockets: Vec<Arc<MulticastSocket>>,
+}
+
+/// Stops the workers started by `MulticastSocketPool::spawn`
+pub struct PoolHandle {
+    stop: Arc<AtomicBool>,
+    workers: Vec<JoinHandle<io::Result<()>>>,
+}
+
+impl MulticastSocketPool {
+    /// Joins `multicast_address` on `interfaces` with `n_shards` sockets
+    pub fn new(
+        multicast_address: SocketAddrV4,
+        interfaces: Vec<Ipv4Addr>,
+        n_shards: usize,
+    ) -> io::Result<Self> {
+        Self::with_options(
+            multicast_address,
+            interfaces,
+            n_shards,
+            MulticastOptions::default(),
+        )
+    }
+
+    /// Same as `new`, creating each socket with `options`, which need `reuse_address`
+    pub fn with_options(
+        multicast_address: SocketAddrV4,
+        interfaces: Vec<Ipv4Addr>,
+        n_shards: usize,
+        options: MulticastOptions,
+    ) -> io::Result<Self> {
+        if n_shards == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a socket pool needs at least one shard",
+            ));
+        }
+        if !options.reuse_address {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the sockets of a pool share the port, which needs `reuse_address`",
+            ));
+        }
+
+        let sockets = (0..n_shards)
+            .map(|_| {
+                MulticastSocket::with_options(
+                    multicast_address,
+                    interfaces.clone(),
+                    options.clone(),
+                )
+                .map(Arc::new)
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(MulticastSocketPool { sockets })
+    }
+
+    /// The sockets by shard, e.g. to send from or read `stats` of
+    pub fn sockets(&self) -> &[Arc<MulticastSocket>] {
+        &self.sockets
+    }
+
+    /// Receives on one thread per shard, calling `handler` with the shard, its socket, to reply
+    /// from, and each datagram of the shard. A worker ends on `shutdown` or on a receive error
+    /// other than a timeout, the others go on.
+    pub fn spawn<F>(&self, handler: F) -> io::Result<PoolHandle>
+    where
+        F: Fn(usize, &MulticastSocket, Message) + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        let stop = Arc::new(AtomicBool::new(false));
+        let shards = self.sockets.len();
+        let mut workers = Vec::with_capacity(shards);
+
+        for (shard, socket) in self.sockets.iter().enumerate() {
+            let (socket, handler, stop_worker) = (socket.clone(), handler.clone(), stop.clone());
+            let worker = thread::Builder::new()
+                .name(format!("multicast-shard-{}", shard))
+                .spawn(move || {
+                    while !stop_worker.load(Ordering::Relaxed) {
+                        let message = match socket.receive() {
+                            Ok(message) => message,
+                            Err(e) if is_timeout(&e) => continue,
+                            Err(e) => return Err(e),
+                        };
+                        if shard_of(&message, shards).is_none_or(|s| s == shard) {
+                            handler(shard, &socket, message);
+                        }
+                    }
+                    Ok(())
+                });
+            match worker {
+                Ok(worker) => workers.push(worker),
+                Err(e) => {
+                    // Stops the workers already running
+                    drop(PoolHandle { stop, workers }.shutdown());
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(PoolHandle { stop, workers })
+    }
+}
+
+/// Shard of a datagram every socket receives, `None` for unicast
+fn shard_of(message: &Message, shards: usize) -> Option<usize> {
+    let flags = message.flags;
+    let delivered_to_all =
+        flags.contains(MessageFlags::MULTICAST) || flags.contains(MessageFlags::BROADCAST);
+    // Without packet info the destination is unknown, as with a socket bound to the group
+    if !delivered_to_all && message.packet_info.is_some() {
+        return None;
+    }
+    Some(u32::from(*message.origin_address.ip()) as usize % shards)
+}
+
+impl PoolHandle {
+    /// Asks the workers to stop and waits for them, which takes up to the read timeout.
+    /// Returns the first error that ended a worker earlier, if any.
+    pub fn shutdown(self) -> io::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        let mut result = Ok(());
+        for worker in self.workers {
+            let ended = match worker.join() {
+                Ok(ended) => ended,
+                Err(_) => Err(io::Error::other("pool worker panicked")),
+            };
+            if result.is_ok() {
+                result = ended;
+            }
+        }
+        result
+    }
+
+    /// Whether every worker ended, on error
+    pub fn is_finished(&self) -> bool {
+        self.workers.iter().all(|worker| worker.is_finished())
+    }
+}
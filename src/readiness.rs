@@ -0,0 +1,125 @@
+//! Wakers behind `MulticastSocket::poll_recv` and `poll_send`, for executors other than tokio.
+//!
+//! There's no reactor to register the socket with, so a helper thread, started when a task
+//! first has to wait, polls a duplicate of the socket and wakes the task once it's readable
+//! or its pacing delay is over. The thread ends when no task waits, or the socket is dropped.
+
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::Waker;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use socket2::Socket;
+
+use crate::poll_readable;
+
+/// Longest the helper thread waits before checking for new wakers or a dropped socket
+const POLL_SLICE: Duration = Duration::from_millis(50);
+
+#[derive(Default)]
+struct State {
+    readable: Option<Waker>,
+    timers: Vec<(Instant, Waker)>,
+    running: bool,
+    closed: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct Readiness {
+    state: Arc<Mutex<State>>,
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Readiness {
+    /// Wakes `waker` once `socket` is readable
+    pub(crate) fn wake_when_readable(&self, socket: &Socket, waker: &Waker) -> io::Result<()> {
+        let mut state = lock(&self.state);
+        match &state.readable {
+            Some(registered) if registered.will_wake(waker) => {}
+            _ => state.readable = Some(waker.clone()),
+        }
+        self.start(state, socket)
+    }
+
+    /// Wakes `waker` at `deadline`
+    pub(crate) fn wake_at(
+        &self,
+        deadline: Instant,
+        socket: &Socket,
+        waker: &Waker,
+    ) -> io::Result<()> {
+        let mut state = lock(&self.state);
+        state.timers.push((deadline, waker.clone()));
+        self.start(state, socket)
+    }
+
+    fn start(&self, mut state: MutexGuard<'_, State>, socket: &Socket) -> io::Result<()> {
+        if state.running {
+            return Ok(());
+        }
+        let socket = socket.try_clone()?;
+        state.running = true;
+        drop(state);
+
+        let shared = Arc::clone(&self.state);
+        thread::Builder::new()
+            .name("multicast-readiness".into())
+            .spawn(move || wait(&shared, &socket))
+            .map(|_| ())
+            .inspect_err(|_| lock(&self.state).running = false)
+    }
+}
+
+impl Drop for Readiness {
+    fn drop(&mut self) {
+        lock(&self.state).closed = true;
+    }
+}
+
+fn wait(shared: &Mutex<State>, socket: &Socket) {
+    loop {
+        let (wants_readable, timeout) = {
+            let mut state = lock(shared);
+            if state.closed || (state.readable.is_none() && state.timers.is_empty()) {
+                state.running = false;
+                return;
+            }
+            let now = Instant::now();
+            let timeout = state
+                .timers
+                .iter()
+                .map(|(deadline, _)| deadline.saturating_duration_since(now))
+                .fold(POLL_SLICE, Duration::min);
+            (state.readable.is_some(), timeout)
+        };
+
+        // A failing poll wakes the task too, for its receive to report the error
+        let readable = if wants_readable {
+            poll_readable(&[socket], timeout).map_or(true, |r| r[0])
+        } else {
+            thread::sleep(timeout);
+            false
+        };
+
+        let mut state = lock(shared);
+        if readable {
+            if let Some(waker) = state.readable.take() {
+                waker.wake();
+            }
+        }
+        let now = Instant::now();
+        let (due, pending): (Vec<_>, Vec<_>) = state
+            .timers
+            .drain(..)
+            .partition(|(deadline, _)| *deadline <= now);
+        state.timers = pending;
+        drop(state);
+        for (_, waker) in due {
+            waker.wake();
+        }
+    }
+}
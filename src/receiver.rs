@@ -0,0 +1,151 @@
+//! Receiving on a dedicated thread, handing the datagrams over through a channel.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::socket::is_timeout;
+use crate::{Message, MulticastSocket};
+
+/// Stops the thread started by `MulticastSocket::spawn_receiver`
+pub struct ReceiverHandle {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+impl ReceiverHandle {
+    /// Asks the thread to stop and waits for it, which takes up to the read timeout.
+    /// Returns the error that ended the thread earlier, if any.
+    pub fn shutdown(self) -> io::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        match self.thread.join() {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::other("receiver thread panicked")),
+        }
+    }
+
+    /// Whether the thread ended, on error or because the channel receiver was dropped
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+}
+
+impl MulticastSocket {
+    /// Receives on a new thread, sending every datagram to the returned channel.
+    /// The socket stays usable for sending through the other `Arc` clones.
+    /// The thread ends on `shutdown`, on a receive error other than a timeout,
+    /// or once the channel receiver is dropped. The channel is unbounded, so a slow consumer
+    /// leaves datagrams piling up in memory, and its depth doesn't show in `stats`.
+    pub fn spawn_receiver(
+        self: Arc<Self>,
+    ) -> io::Result<(mpsc::Receiver<Message>, ReceiverHandle)> {
+        let (sender, receiver) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("multicast-receiver".into())
+                .spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        let message = match self.receive() {
+                            Ok(message) => message,
+                            Err(e) if is_timeout(&e) => continue,
+                            Err(e) => return Err(e),
+                        };
+                        if sender.send(message).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(())
+                })?
+        };
+
+        Ok((receiver, ReceiverHandle { stop, thread }))
+    }
+}
+
+/// What the tokio bridge does with a datagram when the channel is full
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Waits for room, leaving the kernel to drop datagrams once the socket buffer fills up
+    Block,
+    /// Drops the datagram that didn't fit
+    DropNewest,
+}
+
+#[cfg(feature = "tokio")]
+impl MulticastSocket {
+    /// Receives on a blocking task of the current tokio runtime, bridging the datagrams into a
+    /// channel of `capacity`. Must be called from within the runtime. The task ends on a receive
+    /// error other than a timeout, or within the read timeout once the channel receiver is dropped.
+    /// How deep the channel got and how long it stayed full show in `stats`.
+    pub fn spawn_tokio_receiver(
+        self: Arc<Self>,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> (
+        tokio::sync::mpsc::Receiver<Message>,
+        tokio::task::JoinHandle<io::Result<()>>,
+    ) {
+        use std::time::Instant;
+        use tokio::sync::mpsc::error::TrySendError;
+
+        use crate::stats::{count, count_duration, count_max};
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(capacity);
+        let task = tokio::task::spawn_blocking(move || {
+            let counters = &self.counters;
+            // Since when datagrams are dropped, with `Overflow::DropNewest`
+            let mut full_since: Option<Instant> = None;
+            while !sender.is_closed() {
+                let message = match self.receive() {
+                    Ok(message) => message,
+                    Err(e) if is_timeout(&e) => continue,
+                    Err(e) => return Err(e),
+                };
+                let delivered = match overflow {
+                    Overflow::Block if sender.capacity() == 0 => {
+                        let blocked = Instant::now();
+                        let delivered = sender.blocking_send(message).is_ok();
+                        count_duration(&counters.receive_queue_full_micros, blocked.elapsed());
+                        delivered
+                    }
+                    Overflow::Block => sender.blocking_send(message).is_ok(),
+                    Overflow::DropNewest => match sender.try_send(message) {
+                        Ok(()) => {
+                            if let Some(since) = full_since.take() {
+                                count_duration(
+                                    &counters.receive_queue_full_micros,
+                                    since.elapsed(),
+                                );
+                            }
+                            true
+                        }
+                        Err(TrySendError::Full(_)) => {
+                            count(&counters.receive_queue_dropped, 1);
+                            #[cfg(feature = "metrics")]
+                            crate::telemetry::dropped(&self.labels, None, "queue_full");
+                            full_since.get_or_insert_with(Instant::now);
+                            true
+                        }
+                        Err(TrySendError::Closed(_)) => false,
+                    },
+                };
+                if !delivered {
+                    break;
+                }
+                let depth = sender.max_capacity() - sender.capacity();
+                count_max(&counters.receive_queue_high_water, depth);
+                #[cfg(feature = "metrics")]
+                crate::telemetry::receive_queue_depth(&self.labels, depth);
+            }
+            Ok(())
+        });
+
+        (receiver, task)
+    }
+}
@@ -0,0 +1,214 @@
+//! Relaying a group to unicast destinations, and optionally their datagrams back to the group,
+//! to bridge networks that don't carry multicast, like most cloud VPCs.
+//!
+//! Each datagram received from the group is sent on to every destination from a unicast socket
+//! of the relay. With `RelayOptions::reverse`, the datagrams that socket receives from one of
+//! the destinations are sent to the group, others are dropped.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::socket::is_timeout;
+use crate::{Interface, MulticastSocket};
+
+#[derive(Debug, Clone)]
+pub struct RelayOptions {
+    /// Address of the unicast socket, which the destinations send to with `reverse`
+    pub bind_address: SocketAddrV4,
+    /// Sends the datagrams of the destinations to the group as well
+    pub reverse: bool,
+    /// Interface the datagrams of the destinations go out of
+    pub interface: Interface,
+    /// How long the unicast socket waits for a datagram before checking for `shutdown`
+    pub read_timeout: Duration,
+}
+
+impl Default for RelayOptions {
+    fn default() -> Self {
+        RelayOptions {
+            bind_address: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+            reverse: false,
+            interface: Interface::Default,
+            read_timeout: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Datagrams relayed since the relay started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RelayStats {
+    /// Sent to a destination, once per destination
+    pub to_unicast: u64,
+    /// Sent to the group, with `reverse`
+    pub to_group: u64,
+    /// Sends that failed, which don't stop the relay
+    pub send_errors: u64,
+    /// Received on the unicast socket from elsewhere than the destinations
+    pub unknown_senders: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    to_unicast: AtomicU64,
+    to_group: AtomicU64,
+    send_errors: AtomicU64,
+    unknown_senders: AtomicU64,
+}
+
+/// Stops the threads started by `MulticastSocket::relay`
+pub struct RelayHandle {
+    stop: Arc<AtomicBool>,
+    workers: Vec<JoinHandle<io::Result<()>>>,
+    local_address: SocketAddr,
+    counters: Arc<Counters>,
+}
+
+impl MulticastSocket {
+    /// Relays the group of this socket to `destinations`, on a thread, and with
+    /// `options.reverse` their datagrams to the group, on another one. A thread ends on
+    /// `shutdown` or on a receive error other than a timeout.
+    ///
+    /// Fails with `InvalidInput` without destinations, or with `reverse` on a socket that would
+    /// receive its own datagrams through multicast loopback and relay them back, which
+    /// `loopback` off or `ignore_own_packets` prevent.
+    pub fn relay(
+        self: Arc<Self>,
+        destinations: Vec<SocketAddrV4>,
+        options: RelayOptions,
+    ) -> io::Result<RelayHandle> {
+        if destinations.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a relay needs at least one destination",
+            ));
+        }
+        if options.reverse && self.options.loopback && !self.options.ignore_own_packets {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "relaying back to the group needs `ignore_own_packets` or `loopback` off",
+            ));
+        }
+
+        let unicast = Arc::new(UdpSocket::bind(options.bind_address)?);
+        unicast.set_read_timeout(Some(options.read_timeout))?;
+        let mut handle = RelayHandle {
+            stop: Arc::new(AtomicBool::new(false)),
+            workers: Vec::with_capacity(2),
+            local_address: unicast.local_addr()?,
+            counters: Arc::new(Counters::default()),
+        };
+        let destinations = Arc::new(destinations);
+
+        let (socket, sender, to) = (self.clone(), unicast.clone(), destinations.clone());
+        let (stop, counters) = (handle.stop.clone(), handle.counters.clone());
+        let worker = thread::Builder::new()
+            .name("multicast-relay-out".to_owned())
+            .spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let message = match socket.receive() {
+                        Ok(message) => message,
+                        Err(e) if is_timeout(&e) => continue,
+                        Err(e) => return Err(e),
+                    };
+                    for destination in to.iter() {
+                        let counter = match sender.send_to(&message.data, destination) {
+                            Ok(_) => &counters.to_unicast,
+                            Err(_) => &counters.send_errors,
+                        };
+                        counter.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Ok(())
+            });
+        handle.spawned(worker)?;
+
+        if options.reverse {
+            let (stop, counters) = (handle.stop.clone(), handle.counters.clone());
+            let interface = options.interface;
+            let worker = thread::Builder::new()
+                .name("multicast-relay-in".to_owned())
+                .spawn(move || {
+                    let mut buf = vec![0; 65536];
+                    while !stop.load(Ordering::Relaxed) {
+                        let (len, origin) = match unicast.recv_from(&mut buf) {
+                            Ok(received) => received,
+                            Err(e) if is_timeout(&e) => continue,
+                            Err(e) => return Err(e),
+                        };
+                        let known = match origin {
+                            SocketAddr::V4(origin) => destinations.contains(&origin),
+                            SocketAddr::V6(_) => false,
+                        };
+                        let counter = if !known {
+                            &counters.unknown_senders
+                        } else if self.send(&buf[..len], &interface).is_ok() {
+                            &counters.to_group
+                        } else {
+                            &counters.send_errors
+                        };
+                        counter.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Ok(())
+                });
+            handle.spawned(worker)?;
+        }
+
+        Ok(handle)
+    }
+}
+
+impl RelayHandle {
+    fn spawned(&mut self, worker: io::Result<JoinHandle<io::Result<()>>>) -> io::Result<()> {
+        match worker {
+            Ok(worker) => {
+                self.workers.push(worker);
+                Ok(())
+            }
+            Err(e) => {
+                // Stops the thread already running
+                self.stop.store(true, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    /// Address of the unicast socket, for the destinations to send to with `reverse`
+    pub fn local_address(&self) -> SocketAddr {
+        self.local_address
+    }
+
+    pub fn stats(&self) -> RelayStats {
+        RelayStats {
+            to_unicast: self.counters.to_unicast.load(Ordering::Relaxed),
+            to_group: self.counters.to_group.load(Ordering::Relaxed),
+            send_errors: self.counters.send_errors.load(Ordering::Relaxed),
+            unknown_senders: self.counters.unknown_senders.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Asks the threads to stop and waits for them, which takes up to the read timeouts.
+    /// Returns the first error that ended a thread earlier, if any.
+    pub fn shutdown(self) -> io::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        let mut result = Ok(());
+        for worker in self.workers {
+            let ended = match worker.join() {
+                Ok(ended) => ended,
+                Err(_) => Err(io::Error::other("relay thread panicked")),
+            };
+            if result.is_ok() {
+                result = ended;
+            }
+        }
+        result
+    }
+
+    /// Whether every thread ended, on error
+    pub fn is_finished(&self) -> bool {
+        self.workers.iter().all(|worker| worker.is_finished())
+    }
+}
@@ -0,0 +1,297 @@
+//! Recording received datagrams to a file, and sending them again with the original pacing,
+//! to reproduce the traffic of a site in the lab.
+//!
+//! A recording starts with the magic `MCASTREC`, a `u16` format version and the wall clock
+//! time it started at, in microseconds since the Unix epoch as `u64`. Each datagram follows as
+//! its offset from that start in microseconds as `u64`, the origin address as `u32` and port as
+//! `u16`, the interface as a `u8` kind, 0 default, 1 address or 2 index, and a `u32` value, the
+//! destination address as `u32`, 0 without packet info, and the payload as a `u32` length, at
+//! most 65535, and the bytes. All integers are little endian, addresses in host order as
+//! `u32::from` has them.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{Interface, Message, MulticastSocket};
+
+const MAGIC: &[u8; 8] = b"MCASTREC";
+const VERSION: u16 = 1;
+/// Longest payload of a UDP datagram, longer lengths mean a corrupt recording
+const MAX_DATAGRAM: usize = 65535;
+
+/// Writes received datagrams to a recording
+pub struct Recorder<W: Write> {
+    writer: W,
+    start: Instant,
+}
+
+impl<W: Write> Recorder<W> {
+    /// Starts a recording on `writer`, a `BufWriter` around a file usually
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_micros() as u64);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&started_at.to_le_bytes())?;
+        Ok(Recorder {
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Appends `message`, at the time it was received with `receive_timestamps`, or now
+    pub fn record(&mut self, message: &Message) -> io::Result<()> {
+        let received = message.received_instant.unwrap_or_else(Instant::now);
+        let offset = received.saturating_duration_since(self.start).as_micros() as u64;
+        let (kind, value) = match message.interface {
+            Interface::Default => (0u8, 0),
+            Interface::Ip(address) => (1, u32::from(address)),
+            Interface::Index(index) => (2, index),
+        };
+        let destination = message
+            .packet_info
+            .map_or(0, |info| u32::from(info.destination));
+        if message.data.len() > MAX_DATAGRAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram longer than UDP allows",
+            ));
+        }
+        let len = message.data.len() as u32;
+
+        let mut record = Vec::with_capacity(27 + message.data.len());
+        record.extend_from_slice(&offset.to_le_bytes());
+        record.extend_from_slice(&u32::from(*message.origin_address.ip()).to_le_bytes());
+        record.extend_from_slice(&message.origin_address.port().to_le_bytes());
+        record.push(kind);
+        record.extend_from_slice(&value.to_le_bytes());
+        record.extend_from_slice(&destination.to_le_bytes());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(&message.data);
+        self.writer.write_all(&record)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Datagram read back from a recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedMessage {
+    /// Time since the recording started
+    pub offset: Duration,
+    pub origin_address: SocketAddrV4,
+    pub interface: Interface,
+    /// Destination of the packet info, when the OS delivered it
+    pub destination: Option<Ipv4Addr>,
+    pub data: Vec<u8>,
+}
+
+/// Iterator over the datagrams of a recording
+pub struct Recording<R: Read> {
+    reader: R,
+    started_at: SystemTime,
+}
+
+impl<R: Read> Recording<R> {
+    /// Reads the header, failing with `InvalidData` when `reader` holds no recording
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 18];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a multicast recording",
+            ));
+        }
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        if version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported recording version {}", version),
+            ));
+        }
+        let started_at = u64::from_le_bytes(header[10..18].try_into().unwrap());
+        Ok(Recording {
+            reader,
+            started_at: UNIX_EPOCH + Duration::from_micros(started_at),
+        })
+    }
+
+    /// Wall clock time the recording started at
+    pub fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+
+    fn read_message(&mut self) -> io::Result<Option<RecordedMessage>> {
+        let mut fixed = [0; 27];
+        // A clean end of the recording falls between datagrams
+        match self.reader.read(&mut fixed[..1])? {
+            0 => return Ok(None),
+            _ => self.reader.read_exact(&mut fixed[1..])?,
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(fixed[at..at + 4].try_into().unwrap());
+
+        let offset = Duration::from_micros(u64::from_le_bytes(fixed[..8].try_into().unwrap()));
+        let origin_address = SocketAddrV4::new(
+            Ipv4Addr::from(u32_at(8)),
+            u16::from_le_bytes([fixed[12], fixed[13]]),
+        );
+        let interface = match (fixed[14], u32_at(15)) {
+            (0, _) => Interface::Default,
+            (1, address) => Interface::Ip(Ipv4Addr::from(address)),
+            (2, index) => Interface::Index(index),
+            (kind, _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown interface kind {} in recording", kind),
+                ))
+            }
+        };
+        let destination = match u32_at(19) {
+            0 => None,
+            address => Some(Ipv4Addr::from(address)),
+        };
+        let len = u32_at(23) as usize;
+        if len > MAX_DATAGRAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "datagram of {} bytes in recording, longer than UDP allows",
+                    len
+                ),
+            ));
+        }
+        let mut data = vec![0; len];
+        self.reader.read_exact(&mut data)?;
+
+        Ok(Some(RecordedMessage {
+            offset,
+            origin_address,
+            interface,
+            destination,
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for Recording<R> {
+    type Item = io::Result<RecordedMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+/// Sends the datagrams of `recording` to the group of `socket` out of `interface`, each at its
+/// offset from the start of the replay. Returns how many were sent. The origin of each is the
+/// socket, which can't send on behalf of the recorded senders.
+pub fn replay<R: Read>(
+    recording: Recording<R>,
+    socket: &MulticastSocket,
+    interface: &Interface,
+) -> io::Result<u64> {
+    let start = Instant::now();
+    let mut sent = 0;
+    for message in recording {
+        let message = message?;
+        let wait = (start + message.offset).saturating_duration_since(Instant::now());
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+        socket.send(&message.data, interface)?;
+        sent += 1;
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageFlags, PacketInfo};
+    use std::io::Cursor;
+
+    fn message(data: &[u8], interface: Interface, packet_info: Option<PacketInfo>) -> Message {
+        Message {
+            data: data.to_vec(),
+            origin_address: SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 5353),
+            interface,
+            packet_info,
+            original_destination: None,
+            ttl: None,
+            tos: None,
+            received_at: None,
+            received_instant: None,
+            flags: MessageFlags::MULTICAST,
+        }
+    }
+
+    #[test]
+    fn round_trips_through_a_recording() {
+        let info = PacketInfo {
+            interface_index: 2,
+            local_address: None,
+            destination: Ipv4Addr::new(224, 0, 0, 251),
+        };
+        let messages = [
+            message(b"first", Interface::Index(2), Some(info)),
+            message(b"", Interface::Default, None),
+            message(
+                &[0xff; 1500],
+                Interface::Ip(Ipv4Addr::new(10, 0, 0, 1)),
+                None,
+            ),
+        ];
+        let mut recorder = Recorder::new(Vec::new()).unwrap();
+        for message in &messages {
+            recorder.record(message).unwrap();
+        }
+
+        let recording = Recording::new(Cursor::new(recorder.into_inner())).unwrap();
+        assert!(recording.started_at() <= SystemTime::now());
+        let recorded = recording.collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(recorded.len(), messages.len());
+        for (recorded, message) in recorded.iter().zip(&messages) {
+            assert_eq!(recorded.data, message.data);
+            assert_eq!(recorded.origin_address, message.origin_address);
+            assert_eq!(recorded.interface, message.interface);
+            assert_eq!(
+                recorded.destination,
+                message.packet_info.map(|info| info.destination)
+            );
+        }
+        assert!(recorded.windows(2).all(|w| w[0].offset <= w[1].offset));
+    }
+
+    #[test]
+    fn rejects_corrupt_recordings() {
+        let not_one = Recording::new(Cursor::new(b"NOTARECORDING.....".to_vec()));
+        assert_eq!(not_one.err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let mut recorder = Recorder::new(Vec::new()).unwrap();
+        recorder
+            .record(&message(b"payload", Interface::Default, None))
+            .unwrap();
+        let mut bytes = recorder.into_inner();
+        // Length of the first datagram, right after the header and its fixed fields
+        bytes[18 + 23..18 + 27].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut recording = Recording::new(Cursor::new(bytes.clone())).unwrap();
+        let e = recording.next().unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // Cut in the middle of a datagram
+        bytes.truncate(18 + 10);
+        let mut recording = Recording::new(Cursor::new(bytes)).unwrap();
+        let e = recording.next().unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
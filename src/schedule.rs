@@ -0,0 +1,191 @@
+//! Sends scheduled for later, from a timer thread of the `SendScheduler`, for the jittered
+//! announcement and response delays mDNS and SSDP ask for.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::{Interface, MulticastSocket};
+
+struct Scheduled {
+    at: Instant,
+    id: u64,
+    data: Vec<u8>,
+    interface: Interface,
+}
+
+// Reversed, so the heap pops the earliest first, and the earlier scheduled one on a tie
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at, other.id).cmp(&(self.at, self.id))
+    }
+}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scheduled {}
+
+struct State {
+    queue: BinaryHeap<Scheduled>,
+    next_id: u64,
+    closed: bool,
+    // First send error not reported yet
+    error: Option<io::Error>,
+}
+
+struct Shared {
+    socket: Arc<MulticastSocket>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// Send scheduled on a `SendScheduler`, to cancel it with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ScheduledSend(u64);
+
+/// Schedules sends of `MulticastSocket::scheduler`. Dropping it cancels the ones still pending.
+pub struct SendScheduler {
+    shared: Arc<Shared>,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl MulticastSocket {
+    /// Sends the datagrams scheduled on the returned scheduler with `send`, each once its time
+    /// comes
+    pub fn scheduler(self: Arc<Self>) -> io::Result<SendScheduler> {
+        let shared = Arc::new(Shared {
+            socket: self,
+            state: Mutex::new(State {
+                queue: BinaryHeap::new(),
+                next_id: 0,
+                closed: false,
+                error: None,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let thread_shared = shared.clone();
+        let timer = thread::Builder::new()
+            .name("multicast-timer".to_owned())
+            .spawn(move || thread_shared.send_when_due())?;
+        Ok(SendScheduler {
+            shared,
+            timer: Some(timer),
+        })
+    }
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn send_when_due(&self) {
+        let mut state = self.state();
+        loop {
+            if state.closed {
+                return;
+            }
+            let wait = match state.queue.peek() {
+                Some(next) => next.at.saturating_duration_since(Instant::now()),
+                None => {
+                    state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+                    continue;
+                }
+            };
+            if !wait.is_zero() {
+                state = self
+                    .changed
+                    .wait_timeout(state, wait)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0;
+                continue;
+            }
+
+            let due = state.queue.pop().expect("peeked above");
+            drop(state);
+            let result = self.socket.send(&due.data, &due.interface);
+            state = self.state();
+            if let Err(e) = result {
+                state.error.get_or_insert(e);
+            }
+        }
+    }
+}
+
+impl SendScheduler {
+    /// Sends a copy of `buf` to the group out of `interface` at `at`, right away when it has
+    /// passed. Fails with the error of a datagram sent earlier, if one failed meanwhile.
+    pub fn send_at(
+        &self,
+        buf: &[u8],
+        interface: &Interface,
+        at: Instant,
+    ) -> io::Result<ScheduledSend> {
+        let mut state = self.shared.state();
+        if let Some(e) = state.error.take() {
+            return Err(e);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.queue.push(Scheduled {
+            at,
+            id,
+            data: buf.to_vec(),
+            interface: *interface,
+        });
+        self.shared.changed.notify_one();
+        Ok(ScheduledSend(id))
+    }
+
+    /// Same as `send_at`, `delay` from now
+    pub fn send_after(
+        &self,
+        buf: &[u8],
+        interface: &Interface,
+        delay: Duration,
+    ) -> io::Result<ScheduledSend> {
+        self.send_at(buf, interface, Instant::now() + delay)
+    }
+
+    /// Cancels `send`, returning whether it was still pending, e.g. to suppress an mDNS answer
+    /// another responder gave meanwhile
+    pub fn cancel(&self, send: ScheduledSend) -> bool {
+        let mut state = self.shared.state();
+        let before = state.queue.len();
+        state.queue.retain(|scheduled| scheduled.id != send.0);
+        state.queue.len() != before
+    }
+
+    /// Sends still pending
+    pub fn pending(&self) -> usize {
+        self.shared.state().queue.len()
+    }
+
+    pub fn socket(&self) -> &Arc<MulticastSocket> {
+        &self.shared.socket
+    }
+}
+
+impl Drop for SendScheduler {
+    fn drop(&mut self) {
+        self.shared.state().closed = true;
+        self.shared.changed.notify_one();
+        if let Some(timer) = self.timer.take() {
+            let _ = timer.join();
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Bounded queue of outgoing datagrams, sent from a blocking task of the tokio runtime.
+//! Producers wait for room once it is full, instead of piling up sends when an interface is slow.
+
+use std::future::{poll_fn, Future};
+use std::io;
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use tokio::sync::{mpsc, Notify};
+
+use crate::stats::{count_duration, count_max};
+use crate::{Interface, MulticastSocket};
+
+type Datagram = (Vec<u8>, Interface);
+type Permit = mpsc::OwnedPermit<Datagram>;
+type Reserve = Pin<Box<dyn Future<Output = Result<Permit, mpsc::error::SendError<()>>> + Send>>;
+type Flush = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+struct Shared {
+    // Datagrams queued or being sent
+    pending: AtomicUsize,
+    flushed: Notify,
+    // First send error not reported yet
+    error: Mutex<Option<io::Error>>,
+}
+
+/// Sending half of `MulticastSocket::spawn_tokio_sender`. The task ends once this is dropped
+/// and the queued datagrams are sent.
+pub struct SendQueue {
+    sender: mpsc::Sender<Datagram>,
+    shared: Arc<Shared>,
+    // For its counters
+    socket: Arc<MulticastSocket>,
+    reserving: Option<Reserve>,
+    // Since when `poll_ready` waits for room
+    full_since: Option<Instant>,
+    permit: Option<Permit>,
+    flushing: Option<Flush>,
+}
+
+impl MulticastSocket {
+    /// Sends the datagrams of the returned queue, up to `capacity` waiting, on a blocking task
+    /// of the current tokio runtime. Must be called from within the runtime.
+    /// How deep the queue got and how long producers waited for room show in `stats`.
+    pub fn spawn_tokio_sender(self: Arc<Self>, capacity: usize) -> SendQueue {
+        let (sender, mut receiver) = mpsc::channel::<Datagram>(capacity);
+        let shared = Arc::new(Shared {
+            pending: AtomicUsize::new(0),
+            flushed: Notify::new(),
+            error: Mutex::new(None),
+        });
+
+        let (task_shared, socket) = (shared.clone(), self.clone());
+        tokio::task::spawn_blocking(move || {
+            while let Some((data, interface)) = receiver.blocking_recv() {
+                if let Err(e) = self.send(&data, &interface) {
+                    let mut error = task_shared.error.lock().unwrap_or_else(|e| e.into_inner());
+                    error.get_or_insert(e);
+                }
+                if task_shared.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+                    task_shared.flushed.notify_waiters();
+                }
+            }
+        });
+
+        SendQueue {
+            sender,
+            shared,
+            socket,
+            reserving: None,
+            full_since: None,
+            permit: None,
+            flushing: None,
+        }
+    }
+}
+
+impl SendQueue {
+    /// Ready once the queue has room for a datagram, which is reserved for `start_send`.
+    /// Fails with the error of a datagram sent earlier, if one failed meanwhile.
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.take_error()?;
+        if self.permit.is_some() {
+            return Poll::Ready(Ok(()));
+        }
+
+        if self.reserving.is_none() {
+            self.reserving = Some(Box::pin(self.sender.clone().reserve_owned()));
+        }
+        let reserved = match self.reserving.as_mut().map(|r| r.as_mut().poll(cx)) {
+            Some(Poll::Ready(reserved)) => reserved,
+            _ => {
+                self.full_since.get_or_insert_with(Instant::now);
+                return Poll::Pending;
+            }
+        };
+        self.reserving = None;
+        if let Some(since) = self.full_since.take() {
+            count_duration(
+                &self.socket.counters.send_queue_full_micros,
+                since.elapsed(),
+            );
+        }
+        match reserved {
+            Ok(permit) => {
+                self.permit = Some(permit);
+                Poll::Ready(Ok(()))
+            }
+            Err(_) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "send task ended",
+            ))),
+        }
+    }
+
+    /// Queues a datagram into the room reserved by a ready `poll_ready`
+    pub fn start_send(&mut self, data: Vec<u8>, interface: Interface) -> io::Result<()> {
+        let permit = self.permit.take().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "start_send called without a ready poll_ready",
+            )
+        })?;
+        self.shared.pending.fetch_add(1, Ordering::AcqRel);
+        permit.send((data, interface));
+        let depth = self.sender.max_capacity() - self.sender.capacity();
+        count_max(&self.socket.counters.send_queue_high_water, depth);
+        Ok(())
+    }
+
+    /// Ready once every queued datagram was sent, failing with the first error meanwhile
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let shared = self.shared.clone();
+        let flushing = self.flushing.get_or_insert_with(|| {
+            Box::pin(async move {
+                loop {
+                    // Registered before checking, so a notification in between isn't missed
+                    let mut notified = pin!(shared.flushed.notified());
+                    notified.as_mut().enable();
+                    if shared.pending.load(Ordering::Acquire) == 0 {
+                        return;
+                    }
+                    notified.await;
+                }
+            })
+        });
+        if flushing.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        self.flushing = None;
+        Poll::Ready(self.take_error())
+    }
+
+    /// Waits for room, then queues the datagram
+    pub async fn send(&mut self, data: Vec<u8>, interface: Interface) -> io::Result<()> {
+        poll_fn(|cx| self.poll_ready(cx)).await?;
+        self.start_send(data, interface)
+    }
+
+    /// Waits until every queued datagram was sent
+    pub async fn flush(&mut self) -> io::Result<()> {
+        poll_fn(|cx| self.poll_flush(cx)).await
+    }
+
+    fn take_error(&self) -> io::Result<()> {
+        let mut error = self.shared.error.lock().unwrap_or_else(|e| e.into_inner());
+        match error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
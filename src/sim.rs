@@ -0,0 +1,543 @@
+//! Deterministic in-memory network, for testing protocol logic built on top of this crate.
+//!
+//! A `SimNetwork` owns a virtual clock, a set of links (broadcast domains) and the virtual
+//! interfaces attached to them. Sockets created from it behave like `MulticastSocket`s, but
+//! packets only travel through the simulation: every link can drop, delay and reorder
+//! datagrams, and all randomness comes from the seed, so a run can be replayed exactly.
+//!
+//! Time only moves when asked to: either explicitly with `SimNetwork::advance`, or when a
+//! `SimSocket::receive` has nothing queued and waits (in virtual time) for up to its read timeout.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::socket::validate_multicast_address;
+use crate::{Interface, Message, MessageFlags, MulticastOptions, PacketInfo};
+
+/// Behaviour of a single link. The default is a perfect link with no latency.
+#[derive(Debug, Clone, Default)]
+pub struct LinkConfig {
+    /// Fixed delay applied to every datagram
+    pub latency: Duration,
+    /// Extra random delay in `0..jitter`, which reorders datagrams sent close to each other
+    pub jitter: Duration,
+    /// Probability between 0.0 and 1.0 that a datagram is dropped, per receiver
+    pub loss: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkId(usize);
+
+struct Link {
+    config: LinkConfig,
+}
+
+struct VirtualInterface {
+    address: Ipv4Addr,
+    link: LinkId,
+}
+
+struct SocketState {
+    multicast_address: SocketAddrV4,
+    interfaces: Vec<Ipv4Addr>,
+    loopback: bool,
+    queue: VecDeque<Message>,
+    open: bool,
+}
+
+struct InFlight {
+    deliver_at: Duration,
+    // Tie breaker so datagrams due at the same instant keep their emission order
+    sequence: u64,
+    socket: usize,
+    message: Message,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    // Reversed, so the `BinaryHeap` pops the earliest delivery first
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.deliver_at, other.sequence).cmp(&(self.deliver_at, self.sequence))
+    }
+}
+
+/// splitmix64, small and good enough to drive loss and jitter reproducibly
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn below(&mut self, bound: Duration) -> Duration {
+        let nanos = bound.as_nanos() as u64;
+        if nanos == 0 {
+            return Duration::from_nanos(0);
+        }
+        Duration::from_nanos(self.next_u64() % nanos)
+    }
+}
+
+struct State {
+    rng: Rng,
+    now: Duration,
+    sequence: u64,
+    links: Vec<Link>,
+    interfaces: Vec<VirtualInterface>,
+    sockets: Vec<SocketState>,
+    in_flight: BinaryHeap<InFlight>,
+}
+
+impl State {
+    fn interface_index(&self, address: Ipv4Addr) -> Option<usize> {
+        self.interfaces.iter().position(|i| i.address == address)
+    }
+
+    fn deliver_until(&mut self, until: Duration) {
+        while self
+            .in_flight
+            .peek()
+            .is_some_and(|packet| packet.deliver_at <= until)
+        {
+            let packet = self.in_flight.pop().expect("peeked above");
+            let socket = &mut self.sockets[packet.socket];
+            if socket.open {
+                socket.queue.push_back(packet.message);
+            }
+        }
+        if until > self.now {
+            self.now = until;
+        }
+    }
+
+    fn emit(&mut self, from: usize, source: Ipv4Addr, buf: &[u8]) -> io::Result<usize> {
+        let origin = match self.interface_index(source) {
+            Some(origin) => origin,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "address is not attached to the simulated network",
+                ))
+            }
+        };
+        let link = self.interfaces[origin].link;
+        let destination = self.sockets[from].multicast_address;
+        let origin_address = SocketAddrV4::new(source, destination.port());
+
+        for target in 0..self.sockets.len() {
+            let socket = &self.sockets[target];
+            if !socket.open || socket.multicast_address != destination {
+                continue;
+            }
+            if target == from && !socket.loopback {
+                continue;
+            }
+
+            for address in socket.interfaces.clone() {
+                let index = match self.interface_index(address) {
+                    Some(index) if self.interfaces[index].link == link => index,
+                    _ => continue,
+                };
+                let config = &self.links[link.0].config;
+                let (loss, latency, jitter) = (config.loss, config.latency, config.jitter);
+
+                if self.rng.next_f64() < loss {
+                    continue;
+                }
+                let deliver_at = self.now + latency + self.rng.below(jitter);
+
+                self.sequence += 1;
+                self.in_flight.push(InFlight {
+                    deliver_at,
+                    sequence: self.sequence,
+                    socket: target,
+                    message: Message {
+                        data: buf.to_vec(),
+                        origin_address,
+                        interface: Interface::Index((index + 1) as u32),
+                        packet_info: Some(PacketInfo {
+                            interface_index: (index + 1) as u32,
+                            local_address: Some(address),
+                            destination: *destination.ip(),
+                        }),
+                        original_destination: None,
+                        ttl: None,
+                        tos: None,
+                        received_at: None,
+                        received_instant: None,
+                        flags: MessageFlags::MULTICAST,
+                    },
+                });
+            }
+        }
+
+        Ok(buf.len())
+    }
+}
+
+/// A deterministic simulated network. Cloning it gives another handle to the same network.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<State>>,
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64) -> Self {
+        SimNetwork {
+            state: Arc::new(Mutex::new(State {
+                rng: Rng(seed),
+                now: Duration::from_secs(0),
+                sequence: 0,
+                links: Vec::new(),
+                interfaces: Vec::new(),
+                sockets: Vec::new(),
+                in_flight: BinaryHeap::new(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn add_link(&self, config: LinkConfig) -> LinkId {
+        let mut state = self.lock();
+        state.links.push(Link { config });
+        LinkId(state.links.len() - 1)
+    }
+
+    /// Changes the behaviour of a link. Datagrams already in flight keep their delivery time.
+    pub fn set_link_config(&self, link: LinkId, config: LinkConfig) {
+        self.lock().links[link.0].config = config;
+    }
+
+    /// Adds a virtual interface with `address` to `link`.
+    /// Its index, as reported in `Message::interface`, is the order of attachment starting at 1.
+    pub fn attach(&self, link: LinkId, address: Ipv4Addr) -> io::Result<()> {
+        let mut state = self.lock();
+        if state.interface_index(address).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "address is already attached to the simulated network",
+            ));
+        }
+        state.interfaces.push(VirtualInterface { address, link });
+        Ok(())
+    }
+
+    /// Time elapsed on the virtual clock since the network was created
+    pub fn now(&self) -> Duration {
+        self.lock().now
+    }
+
+    /// Moves the virtual clock forward, delivering everything due in the meantime
+    pub fn advance(&self, by: Duration) {
+        let mut state = self.lock();
+        let until = state.now + by;
+        state.deliver_until(until);
+    }
+
+    pub fn socket(
+        &self,
+        multicast_address: SocketAddrV4,
+        interfaces: Vec<Ipv4Addr>,
+        options: MulticastOptions,
+    ) -> io::Result<SimSocket> {
+        validate_multicast_address(&multicast_address)?;
+        let mut state = self.lock();
+        if let Some(missing) = interfaces
+            .iter()
+            .find(|i| state.interface_index(**i).is_none())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("{} is not attached to the simulated network", missing),
+            ));
+        }
+
+        state.sockets.push(SocketState {
+            multicast_address,
+            interfaces,
+            loopback: options.loopback,
+            queue: VecDeque::new(),
+            open: true,
+        });
+
+        Ok(SimSocket {
+            network: self.clone(),
+            id: state.sockets.len() - 1,
+            read_timeout: options.read_timeout,
+            buffer_size: options.buffer_size.unwrap_or(usize::MAX),
+        })
+    }
+}
+
+/// A socket on a `SimNetwork`, mirroring the `MulticastSocket` API
+pub struct SimSocket {
+    network: SimNetwork,
+    id: usize,
+    read_timeout: Duration,
+    buffer_size: usize,
+}
+
+impl SimSocket {
+    /// Returns the next queued datagram. When nothing is queued, the virtual clock is moved up
+    /// to the read timeout waiting for one, and `WouldBlock` is returned if none arrives.
+    pub fn receive(&self) -> io::Result<Message> {
+        let mut state = self.network.lock();
+        let deadline = state.now + self.read_timeout;
+
+        loop {
+            let now = state.now;
+            state.deliver_until(now);
+            if let Some(mut message) = state.sockets[self.id].queue.pop_front() {
+                message.data.truncate(self.buffer_size);
+                return Ok(message);
+            }
+
+            match state.in_flight.peek().map(|packet| packet.deliver_at) {
+                Some(next) if next <= deadline => state.deliver_until(next),
+                _ => {
+                    state.deliver_until(deadline);
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "no datagram within the read timeout",
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Returns the datagrams due by now, without moving the virtual clock
+    pub fn drain(&self) -> io::Result<Vec<Message>> {
+        let mut state = self.network.lock();
+        let now = state.now;
+        state.deliver_until(now);
+        let buffer_size = self.buffer_size;
+        Ok(state.sockets[self.id]
+            .queue
+            .drain(..)
+            .map(|mut message| {
+                message.data.truncate(buffer_size);
+                message
+            })
+            .collect())
+    }
+
+    pub fn send(&self, buf: &[u8], interface: &Interface) -> io::Result<usize> {
+        let mut state = self.network.lock();
+        let source = match interface {
+            Interface::Default => state.sockets[self.id].interfaces.first().copied(),
+            Interface::Ip(address) => Some(*address),
+            Interface::Index(index) => (*index as usize)
+                .checked_sub(1)
+                .and_then(|i| state.interfaces.get(i))
+                .map(|i| i.address),
+        };
+        match source {
+            Some(source) => state.emit(self.id, source, buf),
+            None => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "no such interface on the simulated network",
+            )),
+        }
+    }
+
+    pub fn broadcast(&self, buf: &[u8]) -> io::Result<()> {
+        let mut state = self.network.lock();
+        for interface in state.sockets[self.id].interfaces.clone() {
+            state.emit(self.id, interface, buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        let mut state = self.network.lock();
+        let socket = &mut state.sockets[self.id];
+        socket.open = false;
+        socket.queue.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 0, 1), 5000);
+    const SENDER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const RECEIVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    fn options() -> MulticastOptions {
+        MulticastOptions {
+            read_timeout: Duration::from_millis(100),
+            ..MulticastOptions::default()
+        }
+    }
+
+    // A sender and a receiver on one link
+    fn one_link(seed: u64, config: LinkConfig) -> (SimNetwork, SimSocket, SimSocket) {
+        let network = SimNetwork::new(seed);
+        let link = network.add_link(config);
+        network.attach(link, SENDER).unwrap();
+        network.attach(link, RECEIVER).unwrap();
+        let sender = network.socket(GROUP, vec![SENDER], options()).unwrap();
+        let receiver = network.socket(GROUP, vec![RECEIVER], options()).unwrap();
+        (network, sender, receiver)
+    }
+
+    fn send_numbered(socket: &SimSocket, count: u8) {
+        for n in 0..count {
+            socket.send(&[n], &Interface::Default).unwrap();
+        }
+    }
+
+    fn payloads(socket: &SimSocket) -> Vec<u8> {
+        socket.drain().unwrap().iter().map(|m| m.data[0]).collect()
+    }
+
+    fn lossy() -> LinkConfig {
+        LinkConfig {
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(20),
+            loss: 0.3,
+        }
+    }
+
+    #[test]
+    fn same_seed_same_deliveries() {
+        let run = |seed| {
+            let (network, sender, receiver) = one_link(seed, lossy());
+            send_numbered(&sender, 100);
+            network.advance(Duration::from_secs(1));
+            payloads(&receiver)
+        };
+        let delivered = run(7);
+        assert_eq!(delivered, run(7));
+        assert!(!delivered.is_empty() && delivered.len() < 100);
+        assert_ne!(delivered, run(8));
+    }
+
+    #[test]
+    fn latency_holds_datagrams_until_advanced() {
+        let config = LinkConfig {
+            latency: Duration::from_millis(10),
+            ..LinkConfig::default()
+        };
+        let (network, sender, receiver) = one_link(1, config);
+        send_numbered(&sender, 1);
+        assert!(payloads(&receiver).is_empty());
+        network.advance(Duration::from_millis(9));
+        assert!(payloads(&receiver).is_empty());
+        network.advance(Duration::from_millis(1));
+        assert_eq!(payloads(&receiver), [0]);
+    }
+
+    #[test]
+    fn jitter_reorders_and_equal_delays_keep_the_send_order() {
+        let jittery = LinkConfig {
+            jitter: Duration::from_millis(50),
+            ..LinkConfig::default()
+        };
+        let (network, sender, receiver) = one_link(1, jittery);
+        send_numbered(&sender, 20);
+        network.advance(Duration::from_secs(1));
+        let delivered = payloads(&receiver);
+        assert_eq!(delivered.len(), 20);
+        assert!(delivered.windows(2).any(|pair| pair[0] > pair[1]));
+
+        let fixed = LinkConfig {
+            latency: Duration::from_millis(5),
+            ..LinkConfig::default()
+        };
+        let (network, sender, receiver) = one_link(1, fixed);
+        send_numbered(&sender, 20);
+        network.advance(Duration::from_secs(1));
+        assert_eq!(payloads(&receiver), (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn full_loss_drops_everything() {
+        let config = LinkConfig {
+            loss: 1.0,
+            ..LinkConfig::default()
+        };
+        let (network, sender, receiver) = one_link(1, config);
+        send_numbered(&sender, 50);
+        network.advance(Duration::from_secs(1));
+        assert!(payloads(&receiver).is_empty());
+        assert!(payloads(&sender).is_empty());
+    }
+
+    #[test]
+    fn loopback_off_stops_self_delivery() {
+        let network = SimNetwork::new(1);
+        let link = network.add_link(LinkConfig::default());
+        network.attach(link, SENDER).unwrap();
+        network.attach(link, RECEIVER).unwrap();
+        let quiet = MulticastOptions {
+            loopback: false,
+            ..options()
+        };
+        let sender = network.socket(GROUP, vec![SENDER], quiet).unwrap();
+        let looped = network.socket(GROUP, vec![RECEIVER], options()).unwrap();
+        send_numbered(&sender, 1);
+        send_numbered(&looped, 1);
+        assert_eq!(payloads(&sender), [0]);
+        assert_eq!(payloads(&looped), [0, 0]);
+    }
+
+    #[test]
+    fn other_links_receive_nothing() {
+        let (network, sender, receiver) = one_link(1, LinkConfig::default());
+        let elsewhere = Ipv4Addr::new(10, 1, 0, 1);
+        let other = network.add_link(LinkConfig::default());
+        network.attach(other, elsewhere).unwrap();
+        let isolated = network.socket(GROUP, vec![elsewhere], options()).unwrap();
+        send_numbered(&sender, 3);
+        assert_eq!(payloads(&receiver), [0, 1, 2]);
+        assert!(payloads(&isolated).is_empty());
+    }
+
+    #[test]
+    fn receive_waits_in_virtual_time() {
+        let config = LinkConfig {
+            latency: Duration::from_millis(30),
+            ..LinkConfig::default()
+        };
+        let (network, sender, receiver) = one_link(1, config);
+        send_numbered(&sender, 1);
+        assert_eq!(receiver.receive().unwrap().data, [0]);
+        assert_eq!(network.now(), Duration::from_millis(30));
+
+        let e = receiver.receive().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(network.now(), Duration::from_millis(130));
+    }
+}
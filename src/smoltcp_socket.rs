@@ -0,0 +1,313 @@
+//! Multicast on top of smoltcp, for programs bringing their own network stack on a target
+//! with `std`, e.g. over a TAP device, a userspace driver, or an RTOS with a std port whose
+//! stack lacks multicast.
+//!
+//! A `SmoltcpSocket` behaves like a `MulticastSocket`, with the same `receive`, `send` and
+//! `broadcast`, so discovery code can be shared with the hosted version of a product. Each
+//! interface is a smoltcp `Interface` with its own device and UDP socket, which attributes
+//! datagrams to the interface they came in on, like `IP_PKTINFO` does on hosted targets.
+//!
+//! Nothing runs in the background: `receive` and `send` poll the interfaces, and `poll` should
+//! run again within `poll_delay`, for IGMP to answer the queries of the link.
+//!
+//! It doesn't make the crate usable on `no_std` targets such as `thumbv7em-none-eabihf`: the
+//! module needs `std`, for `io::Error` and `Mutex`, and the crate builds the hosted backend
+//! on socket2 and libc in any case. Firmware without `std` should use smoltcp directly.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use smoltcp::iface::{self, MulticastError, SocketHandle, SocketSet};
+use smoltcp::phy::Device;
+use smoltcp::socket::udp;
+use smoltcp::time::Instant;
+use smoltcp::wire::{IpAddress, IpEndpoint};
+
+use crate::socket::validate_multicast_address;
+use crate::{Interface, Message, MessageFlags, PacketInfo};
+
+/// Datagrams queued per interface and direction by `SmoltcpSocket::new`
+pub const DEFAULT_QUEUED_DATAGRAMS: usize = 4;
+/// Largest datagram `SmoltcpSocket::new` queues, the payload of an Ethernet frame
+pub const DEFAULT_DATAGRAM_SIZE: usize = 1472;
+
+struct Endpoint<D> {
+    interface: iface::Interface,
+    device: D,
+    sockets: SocketSet<'static>,
+    handle: SocketHandle,
+}
+
+impl<D: Device> Endpoint<D> {
+    fn poll(&mut self, now: Instant) {
+        self.interface
+            .poll(now, &mut self.device, &mut self.sockets);
+    }
+
+    fn socket(&mut self) -> &mut udp::Socket<'static> {
+        self.sockets.get_mut::<udp::Socket>(self.handle)
+    }
+}
+
+struct State<D> {
+    endpoints: Vec<Endpoint<D>>,
+    // Interface to receive from first, rotating so a busy one doesn't starve the others
+    next: usize,
+}
+
+pub struct SmoltcpSocket<D: Device> {
+    state: Mutex<State<D>>,
+    multicast_address: SocketAddrV4,
+    clock: fn() -> Instant,
+}
+
+impl<D: Device> SmoltcpSocket<D> {
+    /// Joins the group on each interface, driven by its device. `clock` tells the time to
+    /// smoltcp, e.g. from a hardware timer, or `Instant::now` where `std` has one.
+    pub fn new(
+        multicast_address: SocketAddrV4,
+        interfaces: Vec<(iface::Interface, D)>,
+        clock: fn() -> Instant,
+    ) -> io::Result<Self> {
+        Self::with_buffers(
+            multicast_address,
+            interfaces,
+            clock,
+            DEFAULT_QUEUED_DATAGRAMS,
+            DEFAULT_DATAGRAM_SIZE,
+        )
+    }
+
+    /// Same as `new`, queuing `queued_datagrams` of up to `datagram_size` bytes per interface
+    /// and direction, to fit the memory of the target
+    pub fn with_buffers(
+        multicast_address: SocketAddrV4,
+        interfaces: Vec<(iface::Interface, D)>,
+        clock: fn() -> Instant,
+        queued_datagrams: usize,
+        datagram_size: usize,
+    ) -> io::Result<Self> {
+        validate_multicast_address(&multicast_address)?;
+
+        let now = clock();
+        let mut endpoints = Vec::with_capacity(interfaces.len());
+        for (mut interface, device) in interfaces {
+            let buffer = || {
+                udp::PacketBuffer::new(
+                    vec![udp::PacketMetadata::EMPTY; queued_datagrams],
+                    vec![0; queued_datagrams * datagram_size],
+                )
+            };
+            let mut socket = udp::Socket::new(buffer(), buffer());
+            socket.bind(multicast_address.port()).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("bind failed: {}", e))
+            })?;
+
+            interface
+                .join_multicast_group(*multicast_address.ip())
+                .map_err(multicast_error)?;
+
+            let mut sockets = SocketSet::new(Vec::new());
+            let handle = sockets.add(socket);
+            let mut endpoint = Endpoint {
+                interface,
+                device,
+                sockets,
+                handle,
+            };
+            // Sends the membership report
+            endpoint.poll(now);
+            endpoints.push(endpoint);
+        }
+
+        Ok(SmoltcpSocket {
+            state: Mutex::new(State { endpoints, next: 0 }),
+            multicast_address,
+            clock,
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, State<D>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Processes the traffic of every interface: queues received datagrams, sends queued ones
+    /// and answers IGMP queries
+    pub fn poll(&self) {
+        let now = (self.clock)();
+        for endpoint in &mut self.state().endpoints {
+            endpoint.poll(now);
+        }
+    }
+
+    /// How soon `poll` should run again, `None` when only new traffic needs it
+    pub fn poll_delay(&self) -> Option<Duration> {
+        let now = (self.clock)();
+        self.state()
+            .endpoints
+            .iter_mut()
+            .filter_map(|e| e.interface.poll_delay(now, &e.sockets))
+            .map(Duration::from)
+            .min()
+    }
+
+    /// Polls the interfaces and returns the next datagram queued on any of them. Fails with
+    /// `WouldBlock` when none is, as there's no thread to wait on: poll again once the device
+    /// signals traffic, or after `poll_delay`.
+    pub fn receive(&self) -> io::Result<Message> {
+        let now = (self.clock)();
+        let mut state = self.state();
+        let count = state.endpoints.len();
+        for endpoint in &mut state.endpoints {
+            endpoint.poll(now);
+        }
+
+        for offset in 0..count {
+            let index = (state.next + offset) % count;
+            let endpoint = &mut state.endpoints[index];
+            let local_address = endpoint.interface.ipv4_addr();
+            let (data, meta) = match endpoint.socket().recv() {
+                Ok(received) => received,
+                Err(udp::RecvError::Exhausted) | Err(udp::RecvError::Truncated) => continue,
+            };
+            let origin_address = match meta.endpoint.addr {
+                IpAddress::Ipv4(address) => SocketAddrV4::new(address, meta.endpoint.port),
+                #[allow(unreachable_patterns)]
+                _ => continue,
+            };
+            #[allow(unreachable_patterns)]
+            let destination = match meta.local_address {
+                Some(IpAddress::Ipv4(address)) => address,
+                _ => *self.multicast_address.ip(),
+            };
+
+            let message = Message {
+                data: data.to_vec(),
+                origin_address,
+                interface: Interface::Index(index as u32 + 1),
+                packet_info: Some(PacketInfo {
+                    interface_index: index as u32 + 1,
+                    local_address,
+                    destination,
+                }),
+                original_destination: None,
+                ttl: None,
+                tos: None,
+                received_at: None,
+                received_instant: None,
+                flags: if destination.is_multicast() {
+                    MessageFlags::MULTICAST
+                } else {
+                    MessageFlags::empty()
+                },
+            };
+            state.next = (index + 1) % count;
+            return Ok(message);
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "no datagram queued on the interfaces",
+        ))
+    }
+
+    /// Sends to the group out of `interface`: `Interface::Index` counts the interfaces passed
+    /// to `new` from 1, as `Message::interface` reports them, and `Interface::Default` is the
+    /// first one
+    pub fn send(&self, buf: &[u8], interface: &Interface) -> io::Result<usize> {
+        let now = (self.clock)();
+        let mut state = self.state();
+        let endpoint = match interface {
+            Interface::Default => state.endpoints.first_mut(),
+            Interface::Index(index) => (*index as usize)
+                .checked_sub(1)
+                .and_then(|i| state.endpoints.get_mut(i)),
+            Interface::Ip(address) => state
+                .endpoints
+                .iter_mut()
+                .find(|e| e.interface.ipv4_addr() == Some(*address)),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no smoltcp interface for {:?}", interface),
+            )
+        })?;
+
+        send_on(endpoint, buf, self.multicast_address, now)
+    }
+
+    /// Sends to the group out of every interface
+    pub fn broadcast(&self, buf: &[u8]) -> io::Result<()> {
+        let now = (self.clock)();
+        for endpoint in &mut self.state().endpoints {
+            send_on(endpoint, buf, self.multicast_address, now)?;
+        }
+        Ok(())
+    }
+
+    pub fn interfaces(&self) -> Vec<Ipv4Addr> {
+        self.state()
+            .endpoints
+            .iter()
+            .filter_map(|e| e.interface.ipv4_addr())
+            .collect()
+    }
+
+    pub fn multicast_address(&self) -> SocketAddrV4 {
+        self.multicast_address
+    }
+
+    /// Leaves the group and hands the interfaces and devices back
+    pub fn into_interfaces(self) -> Vec<(iface::Interface, D)> {
+        let now = (self.clock)();
+        let group = *self.multicast_address.ip();
+        let state = self.state.into_inner().unwrap_or_else(|e| e.into_inner());
+        state
+            .endpoints
+            .into_iter()
+            .map(|mut endpoint| {
+                // Sends the leave, a full group table only ever fails the join
+                let _ = endpoint.interface.leave_multicast_group(group);
+                endpoint.poll(now);
+                (endpoint.interface, endpoint.device)
+            })
+            .collect()
+    }
+}
+
+fn send_on<D: Device>(
+    endpoint: &mut Endpoint<D>,
+    buf: &[u8],
+    multicast_address: SocketAddrV4,
+    now: Instant,
+) -> io::Result<usize> {
+    let destination = IpEndpoint::new((*multicast_address.ip()).into(), multicast_address.port());
+    endpoint
+        .socket()
+        .send_slice(buf, destination)
+        .map_err(|e| match e {
+            udp::SendError::BufferFull => io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "send queue of the interface is full",
+            ),
+            udp::SendError::Unaddressable => io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "the interface has no IPv4 address to send from",
+            ),
+        })?;
+    endpoint.poll(now);
+    Ok(buf.len())
+}
+
+fn multicast_error(e: MulticastError) -> io::Error {
+    match e {
+        MulticastError::GroupTableFull => io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            "multicast group table of the interface is full, raise smoltcp's IFACE_MAX_MULTICAST_GROUP_COUNT",
+        ),
+        e => io::Error::new(io::ErrorKind::InvalidInput, e.to_string()),
+    }
+}
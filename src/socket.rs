@@ -0,0 +1,1078 @@
+//! Behaviour of `MulticastSocket` shared by all platforms, layered on top of the
+//! platform specific `receive_from_socket` and `send`.
+
+use std::cell::RefCell;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::ControlFlow;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use socket2::Socket;
+
+use crate::capture::{sent_from, Datagram, Direction};
+use crate::stats::{count, Counter};
+
+use crate::{
+    Ecn, Health, Interface, Message, MessageRef, MulticastOptions, MulticastSocket, ReceiveBuffer,
+    SendOptions, Stats,
+};
+
+/// IPv4 router alert option, RFC 2113, in the format of `IP_OPTIONS`
+pub(crate) const ROUTER_ALERT: [u8; 4] = [0x94, 0x04, 0x00, 0x00];
+
+/// Receive buffer size when none of the joined interfaces reports its MTU
+const FALLBACK_BUFFER_SIZE: usize = 1500;
+
+/// Port the socket is bound to, 0 while unbound
+pub(crate) fn local_port(socket: &Socket) -> io::Result<u16> {
+    Ok(socket
+        .local_addr()?
+        .as_inet()
+        .map(|address| address.port())
+        .unwrap_or(0))
+}
+
+/// Broadcast address of the subnet of the interface with `address`, for networks filtering
+/// multicast but forwarding directed broadcasts
+pub fn directed_broadcast(address: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    let netmask = crate::interface_netmask(address)?;
+    Ok(Ipv4Addr::from(u32::from(address) | !u32::from(netmask)))
+}
+
+/// Address of the interface carrying the default route, the one a UDP socket connected to an
+/// address off every local network sends from. Connecting sends nothing. Fails with
+/// `AddrNotAvailable` without a default route.
+pub fn default_route_interface() -> io::Result<Ipv4Addr> {
+    // TEST-NET-2, reserved for documentation, so no more specific route covers it
+    let off_link = SocketAddrV4::new(Ipv4Addr::new(198, 51, 100, 1), 9);
+    let socket = std::net::UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    let no_route = |e: io::Error| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("no default route: {}", e),
+        )
+    };
+    socket.connect(off_link).map_err(no_route)?;
+    match socket.local_addr()? {
+        std::net::SocketAddr::V4(local) if !local.ip().is_unspecified() => Ok(*local.ip()),
+        _ => Err(no_route(io::Error::other("no source address for it"))),
+    }
+}
+
+/// Subnet of an address of a joined interface, for `MulticastOptions::same_subnet_sources`
+pub(crate) struct Subnet {
+    interface_index: u32,
+    network: u32,
+    mask: u32,
+}
+
+/// Subnets of every address of the interfaces with `interfaces`, skipping the ones whose
+/// index or netmask can't be found
+pub(crate) fn subnets<'a>(interfaces: impl Iterator<Item = &'a Ipv4Addr>) -> Vec<Subnet> {
+    let mut subnets = Vec::new();
+    for interface in interfaces {
+        let interface_index = match crate::interface_index(*interface) {
+            Ok(index) => index,
+            Err(_) => continue,
+        };
+        if subnets
+            .iter()
+            .any(|s: &Subnet| s.interface_index == interface_index)
+        {
+            continue;
+        }
+        let addresses = crate::interface_ips(interface_index).unwrap_or_default();
+        for address in addresses {
+            if let Ok(netmask) = crate::interface_netmask(address) {
+                let mask = u32::from(netmask);
+                subnets.push(Subnet {
+                    interface_index,
+                    network: u32::from(address) & mask,
+                    mask,
+                });
+            }
+        }
+    }
+    subnets
+}
+
+thread_local! {
+    // Reused by the receives of each thread, so ancillary data takes no allocation per datagram
+    static CONTROL_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Runs `receive` with a control buffer of `len` bytes, the one of the calling thread
+pub(crate) fn with_control_buffer<R>(len: usize, receive: impl FnOnce(&mut [u8]) -> R) -> R {
+    CONTROL_BUFFER.with_borrow_mut(|buffer| {
+        if buffer.len() < len {
+            buffer.resize(len, 0);
+        }
+        receive(&mut buffer[..len])
+    })
+}
+
+/// Rejects groups outside of 224.0.0.0/4 and port 0 up front,
+/// as the OS only fails on them later with less telling errors
+pub(crate) fn validate_multicast_address(address: &SocketAddrV4) -> io::Result<()> {
+    if !address.ip().is_multicast() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not an IPv4 multicast address", address.ip()),
+        ));
+    }
+    if address.port() == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "multicast port must not be 0, for a local ephemeral port see `MulticastOptions::ephemeral_port`",
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects options needing syscalls after construction with `MulticastOptions::pre_configured`
+pub(crate) fn validate_pre_configured(options: &MulticastOptions) -> io::Result<()> {
+    #[cfg(feature = "enumerate")]
+    if options.pre_configured && options.rejoin_interval.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "`rejoin_interval` enumerates interfaces while receiving, which `pre_configured` rules out",
+        ));
+    }
+    if options.pre_configured && options.membership_refresh.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "`membership_refresh` joins the group again while receiving, which `pre_configured` rules out",
+        ));
+    }
+    Ok(())
+}
+
+/// Whether a send failed for lack of room in the buffers, and could succeed later
+fn is_transient_send_error(e: &io::Error) -> bool {
+    #[cfg(not(windows))]
+    const ENOBUFS: i32 = libc::ENOBUFS;
+    #[cfg(windows)]
+    const ENOBUFS: i32 = winapi::um::winsock2::WSAENOBUFS;
+    e.kind() == io::ErrorKind::WouldBlock || e.raw_os_error() == Some(ENOBUFS)
+}
+
+/// Interfaces of `all_ipv4_interfaces`, filtered as `options` say
+#[cfg(feature = "enumerate")]
+fn enumerate_interfaces(options: &MulticastOptions) -> io::Result<Vec<Ipv4Addr>> {
+    let interfaces = crate::all_ipv4_interfaces()?;
+    if !options.skip_link_local_interfaces {
+        return Ok(interfaces);
+    }
+    // Link-local addresses are kept on interfaces having a routable one as well
+    let routable: Vec<u32> = interfaces
+        .iter()
+        .filter(|address| !address.is_link_local())
+        .filter_map(|address| crate::interface_index(*address).ok())
+        .collect();
+    Ok(interfaces
+        .into_iter()
+        .filter(|address| {
+            !address.is_link_local()
+                || crate::interface_index(*address).is_ok_and(|i| routable.contains(&i))
+        })
+        .collect())
+}
+
+/// Whether a receive failed only because the read timeout elapsed,
+/// reported as `WouldBlock` on unix and `TimedOut` on Windows
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+impl MulticastSocket {
+    /// Joins on the interfaces with an address within any of `networks`, e.g. the storage
+    /// network of a deployment given as `10.20.0.0/16`. Fails with `AddrNotAvailable` when no
+    /// interface has one.
+    #[cfg(feature = "enumerate")]
+    pub fn in_networks(
+        multicast_address: SocketAddrV4,
+        networks: &[crate::Ipv4Cidr],
+        options: MulticastOptions,
+    ) -> io::Result<Self> {
+        let interfaces: Vec<_> = enumerate_interfaces(&options)?
+            .into_iter()
+            .filter(|interface| networks.iter().any(|n| n.contains(*interface)))
+            .collect();
+        if interfaces.is_empty() {
+            let networks: Vec<_> = networks.iter().map(|n| n.to_string()).collect();
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no interface within {}", networks.join(", ")),
+            ));
+        }
+        MulticastSocket::with_options(multicast_address, interfaces, options)
+    }
+
+    /// Same as `all_interfaces`, with `options`, which also pick the interfaces enumerated,
+    /// see `MulticastOptions::skip_link_local_interfaces`
+    #[cfg(feature = "enumerate")]
+    pub fn all_interfaces_with_options(
+        multicast_address: SocketAddrV4,
+        options: MulticastOptions,
+    ) -> io::Result<Self> {
+        let interfaces = enumerate_interfaces(&options)?;
+        MulticastSocket::with_options(multicast_address, interfaces, options)
+    }
+
+    /// Joins on the interface carrying the default route only, see `default_route_interface`,
+    /// leaving out VPN and virtual adapters that don't. Sends default to it as well.
+    pub fn on_default_interface(multicast_address: SocketAddrV4) -> io::Result<Self> {
+        let interface = default_route_interface()?;
+        let socket = MulticastSocket::with_options(
+            multicast_address,
+            vec![interface],
+            MulticastOptions::default(),
+        )?;
+        socket.set_default_send_interface(&Interface::Ip(interface))?;
+        Ok(socket)
+    }
+
+    /// Largest MTU of the joined interfaces, the default receive buffer size
+    pub(crate) fn largest_mtu(&self) -> usize {
+        self.interfaces()
+            .into_iter()
+            .filter_map(|interface| self.mtu(&Interface::Ip(interface)).ok())
+            .max()
+            .map(|mtu| mtu as usize)
+            .unwrap_or(FALLBACK_BUFFER_SIZE)
+    }
+
+    pub fn receive(&self) -> io::Result<Message> {
+        self.receive_owned(true)
+    }
+
+    /// Same as `receive`, without copying the data out of the receive buffer of the socket.
+    /// The datagram borrows the socket until it is dropped, which the next call requires.
+    pub fn receive_ref(&mut self) -> io::Result<MessageRef<'_>> {
+        let mut buffer = mem::take(&mut self.receive_buffer);
+        buffer.resize(self.buffer_size, 0);
+        let received = self
+            .receive_filtered(&mut buffer, true)
+            .map(|m| (m.data.len(), m.with_data(&[])));
+        self.receive_buffer = buffer;
+
+        let (len, message) = received?;
+        Ok(message.with_data(&self.receive_buffer[0..len]))
+    }
+
+    fn receive_owned(&self, blocking: bool) -> io::Result<Message> {
+        let mut buffer = vec![0; self.buffer_size];
+        Ok(self.receive_filtered(&mut buffer, blocking)?.into_owned())
+    }
+
+    /// Blocking iterator over received datagrams. Read timeouts are skipped,
+    /// other errors are yielded and iteration can go on after them.
+    pub fn incoming(&self) -> impl Iterator<Item = io::Result<Message>> + '_ {
+        std::iter::from_fn(move || loop {
+            match self.receive() {
+                Err(e) if is_timeout(&e) => continue,
+                result => return Some(result),
+            }
+        })
+    }
+
+    /// Receives and passes every datagram to `handler` until it returns `ControlFlow::Break`.
+    /// Read timeouts and interrupted calls are retried, other errors end the loop.
+    pub fn run<F>(&self, mut handler: F) -> io::Result<()>
+    where
+        F: FnMut(Message) -> ControlFlow<()>,
+    {
+        loop {
+            let message = match self.receive() {
+                Ok(message) => message,
+                Err(e) if is_timeout(&e) || e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if handler(message).is_break() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Returns a datagram if one is queued, or `None` instead of waiting. The socket stays
+    /// blocking for other calls, on unix the call passes `MSG_DONTWAIT`.
+    /// On Windows, a concurrent `receive` may fail with `WouldBlock` while this runs.
+    pub fn try_receive(&self) -> io::Result<Option<Message>> {
+        match self.receive_owned(false) {
+            Ok(message) => Ok(Some(message)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Polls for a datagram received into `buf`, for executors and hand-written futures
+    /// without the tokio wrappers. When none is queued, a helper thread waits for the socket
+    /// to turn readable and wakes the task, see `try_receive` for the calls on Windows.
+    pub fn poll_recv<'a>(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &'a mut [u8],
+    ) -> Poll<io::Result<MessageRef<'a>>> {
+        match self.receive_filtered(buf, false) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                match self
+                    .readiness
+                    .wake_when_readable(self.as_socket(), cx.waker())
+                {
+                    Ok(()) => Poll::Pending,
+                    Err(e) => Poll::Ready(Err(e)),
+                }
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    /// Same as `send` for executors other than tokio, returning `Pending` instead of sleeping
+    /// through the pacing delay. The datagram itself is still sent with a blocking call.
+    pub fn poll_send(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        interface: &Interface,
+    ) -> Poll<io::Result<usize>> {
+        if let Some(pacer) = &self.pacer {
+            if let Err(delay) = pacer.try_acquire() {
+                let deadline = Instant::now() + delay;
+                return match self
+                    .readiness
+                    .wake_at(deadline, self.as_socket(), cx.waker())
+                {
+                    Ok(()) => Poll::Pending,
+                    Err(e) => Poll::Ready(Err(e)),
+                };
+            }
+        }
+        Poll::Ready(self.send_retrying(buf, interface))
+    }
+
+    /// Returns the datagrams already queued, without waiting for more.
+    /// Meant for event loops servicing the socket once per tick.
+    /// On Windows, a concurrent `receive` may fail with `WouldBlock` while this runs.
+    ///
+    /// A receive failing after some datagrams were read doesn't lose them: they are returned,
+    /// and the error is returned by the next call.
+    pub fn drain(&self) -> io::Result<Vec<Message>> {
+        let held = || self.drain_error.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(e) = held().take() {
+            return Err(e);
+        }
+        let mut messages = Vec::new();
+        loop {
+            match self.try_receive() {
+                Ok(Some(message)) => messages.push(message),
+                Ok(None) => return Ok(messages),
+                Err(e) if messages.is_empty() => return Err(e),
+                Err(e) => {
+                    held().get_or_insert(e);
+                    return Ok(messages);
+                }
+            }
+        }
+    }
+
+    /// Receives, skipping own packets and duplicates as configured
+    fn receive_filtered<'a>(
+        &self,
+        buffer: &'a mut [u8],
+        blocking: bool,
+    ) -> io::Result<MessageRef<'a>> {
+        #[cfg(feature = "enumerate")]
+        if let Some(watch) = &self.address_watch {
+            if watch.lock().unwrap_or_else(|e| e.into_inner()).changed() {
+                // Interfaces whose address went away fail to join, the others recover
+                let _ = self.rejoin_all();
+            }
+        }
+        if let Some(refresh) = &self.membership_refresh {
+            if refresh.lock().unwrap_or_else(|e| e.into_inner()).due() {
+                let _ = self.rejoin_all();
+            }
+        }
+
+        // The datagram leaves the loop without its data, for the borrow checker to let `buffer` be reused
+        let (len, message) = loop {
+            let message = match self.receive_spinning(buffer, blocking) {
+                Err(e) if self.retry_interrupted && e.kind() == io::ErrorKind::Interrupted => {
+                    continue
+                }
+                Err(e) => {
+                    if !is_timeout(&e) {
+                        count(&self.counters.receive_errors, 1);
+                        #[cfg(feature = "metrics")]
+                        crate::telemetry::failed(&self.labels, "receive");
+                    }
+                    return Err(e);
+                }
+                Ok(message) => message,
+            };
+
+            if self.is_own_packet(&message.origin_address) {
+                self.count_drop(&self.counters.own_packets, &message.interface, "own");
+                continue;
+            }
+
+            if self.filter_destination
+                && message
+                    .packet_info
+                    .is_some_and(|i| !self.accepts_destination(i.destination))
+            {
+                self.count_drop(&self.counters.filtered, &message.interface, "filtered");
+                continue;
+            }
+
+            if let (Some(subnets), Some(info)) = (&self.source_subnets, message.packet_info) {
+                let source = u32::from(*message.origin_address.ip());
+                if !subnets.iter().any(|s| {
+                    s.interface_index == info.interface_index && source & s.mask == s.network
+                }) {
+                    self.count_drop(&self.counters.filtered, &message.interface, "filtered");
+                    continue;
+                }
+            }
+
+            if let Some(deduplicator) = &self.deduplicator {
+                let mut deduplicator = deduplicator.lock().unwrap_or_else(|e| e.into_inner());
+                if deduplicator.is_duplicate(&message.origin_address, message.data) {
+                    self.count_drop(&self.counters.duplicates, &message.interface, "duplicate");
+                    continue;
+                }
+            }
+
+            count(&self.counters.received, 1);
+            count(&self.counters.received_bytes, message.data.len());
+            #[cfg(feature = "metrics")]
+            crate::telemetry::received(&self.labels, &message.interface, message.data.len());
+            #[cfg(feature = "trace-payloads")]
+            crate::hexdump::trace(
+                Direction::Received,
+                &message.origin_address,
+                &message.interface,
+                message.data,
+            );
+            if let Some(capture) = &self.options.capture {
+                let destination = message
+                    .packet_info
+                    .map_or(*self.multicast_address().ip(), |i| i.destination);
+                capture.record(&Datagram {
+                    direction: Direction::Received,
+                    data: message.data,
+                    interface: message.interface,
+                    source: message.origin_address,
+                    destination: SocketAddrV4::new(destination, self.local_port),
+                    ttl: message.ttl,
+                    tos: message.tos,
+                    time: message.received_at,
+                });
+            }
+            break (message.data.len(), message.with_data(&[]));
+        };
+
+        Ok(message.with_data(&buffer[0..len]))
+    }
+
+    /// Receives from the socket, spinning on non-blocking calls for `receive_spin` first
+    fn receive_spinning<'a>(
+        &self,
+        buffer: &'a mut [u8],
+        blocking: bool,
+    ) -> io::Result<MessageRef<'a>> {
+        if let (true, Some(spin)) = (blocking, self.receive_spin) {
+            let deadline = Instant::now() + spin;
+            loop {
+                match self.receive_from_socket(buffer, false) {
+                    Ok(message) => {
+                        let len = message.data.len();
+                        let message = message.with_data(&[]);
+                        return Ok(message.with_data(&buffer[0..len]));
+                    }
+                    Err(e) if e.kind() != io::ErrorKind::WouldBlock => return Err(e),
+                    Err(_) if Instant::now() >= deadline => break,
+                    Err(_) => std::hint::spin_loop(),
+                }
+            }
+        }
+        self.receive_from_socket(buffer, blocking)
+    }
+
+    /// Counts a datagram dropped while receiving, for `stats` and the `metrics` feature
+    fn count_drop(&self, counter: &Counter, interface: &Interface, reason: &'static str) {
+        count(counter, 1);
+        #[cfg(feature = "metrics")]
+        crate::telemetry::dropped(&self.labels, Some(interface), reason);
+        #[cfg(not(feature = "metrics"))]
+        let _ = (interface, reason);
+    }
+
+    fn accepts_destination(&self, destination: Ipv4Addr) -> bool {
+        destination == *self.multicast_address().ip()
+            || (self.broadcast && destination == Ipv4Addr::BROADCAST)
+            || self.directed_broadcasts.contains(&destination)
+    }
+
+    /// Address the socket is bound to, the group or `0.0.0.0` depending on the platform
+    /// and options, with the bound port
+    pub fn local_addr(&self) -> io::Result<SocketAddrV4> {
+        self.as_socket()
+            .local_addr()?
+            .as_inet()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "socket is not IPv4"))
+    }
+
+    /// Leaves the group on every joined interface before closing the socket, so the IGMP
+    /// leave goes out right away. All interfaces are left even if some fail,
+    /// the first error is returned.
+    pub fn close(mut self) -> io::Result<()> {
+        self.memberships.leave()
+    }
+
+    /// Pairs of group and interface the socket joined. On Linux, memberships the kernel no longer
+    /// lists in `/proc/net/igmp`, e.g. after the interface went down, are left out. That table
+    /// belongs to the network namespace of the calling thread, which should be the one of the socket,
+    /// and holds the memberships of all its sockets, see `health`.
+    /// It isn't read on `pre_configured` sockets.
+    pub fn memberships(&self) -> Vec<(Ipv4Addr, Ipv4Addr)> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if !self.pre_configured {
+            if let Ok(health) = self.health() {
+                return health.held;
+            }
+        }
+        self.memberships.list()
+    }
+
+    /// Checks the memberships against the kernel, to notice the ones lost without any error,
+    /// e.g. when the address was removed or the device recreated, so daemons can alert instead
+    /// of receiving nothing. On Linux they are looked up in `/proc/net/igmp`, elsewhere only
+    /// the addresses of the interfaces are checked. `rejoin_all` restores them once possible.
+    ///
+    /// That table lists the groups joined by every socket of the network namespace, not by this
+    /// one, so a membership this socket lost is still reported as held while another socket,
+    /// of this process or another, holds the same group on the same interface.
+    pub fn health(&self) -> io::Result<Health> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let kernel = crate::membership::kernel_memberships()?;
+        let (held, lost) = self.memberships.list().into_iter().partition(|membership| {
+            match crate::interface_index(membership.1) {
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                Ok(index) => kernel.contains(&(index, membership.0)),
+                #[cfg(not(any(target_os = "linux", target_os = "android")))]
+                Ok(_) => true,
+                // The address is gone, and the membership with it
+                Err(_) => false,
+            }
+        });
+        Ok(Health { held, lost })
+    }
+
+    /// Leaves and joins the group again on every interface, for memberships lost by the kernel,
+    /// e.g. after a suspend. All interfaces are joined even if some fail, the first error is returned.
+    pub fn rejoin_all(&self) -> io::Result<()> {
+        let group = *self.multicast_address().ip();
+        let mut result = Ok(());
+        for interface in self.interfaces() {
+            if self
+                .as_socket()
+                .leave_multicast_v4(&group, &interface)
+                .is_ok()
+            {
+                self.memberships.remove(interface);
+            }
+            let joined = self.as_socket().join_multicast_v4(&group, &interface);
+            if joined.is_ok() {
+                self.memberships.add(interface);
+            }
+            if result.is_ok() {
+                result = joined;
+            }
+        }
+        result
+    }
+
+    /// Leaves the group and joins `group` on every interface instead, e.g. to scan a range of
+    /// groups without creating a socket for each. The socket isn't rebound, so it has to be
+    /// bound to `0.0.0.0`, see `MulticastOptions::bind_unspecified`, and `group` has to be on
+    /// the same port unless it is an ephemeral one. `InvalidInput` is returned otherwise.
+    /// Joining stops at the first failure, with the memberships joined so far kept.
+    ///
+    /// When leaving the current group fails on some interfaces, the switch still goes ahead and
+    /// the first leave error is returned once `group` was joined. The kernel may then hold the
+    /// old membership on those interfaces until the socket is closed, delivering datagrams sent
+    /// to the old group on the same port, while `memberships` only lists the new one.
+    pub fn set_group(&mut self, group: SocketAddrV4) -> io::Result<()> {
+        validate_multicast_address(&group)?;
+        let bound = self.local_addr()?;
+        let ephemeral = bound.port() != self.multicast_address.port();
+        if !(bound.ip().is_unspecified() || bound.ip() == group.ip())
+            || (!ephemeral && bound.port() != group.port())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "socket bound to {} doesn't receive {}, it needs `bind_unspecified` and the same port",
+                    bound, group
+                ),
+            ));
+        }
+
+        let left = self.memberships.leave();
+        self.memberships.set_group(*group.ip());
+        self.multicast_address = group;
+        #[cfg(feature = "metrics")]
+        self.labels.set_group(group);
+        let joined = (|| {
+            if let Some(send_sockets) = &mut self.send_sockets {
+                send_sockets.set_group(&self.socket, group)?;
+            }
+            for interface in self.interfaces() {
+                match self.as_socket().join_multicast_v4(group.ip(), &interface) {
+                    Err(e) if e.kind() == io::ErrorKind::AddrInUse => {}
+                    result => result?,
+                }
+                self.memberships.add(interface);
+            }
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            if self.routed_interface().is_some() {
+                self.refresh_routed_interface()?;
+            }
+            Ok(())
+        })();
+        left.and(joined)
+    }
+
+    /// Moves the socket to `port`, and the group with it, e.g. on a configuration reload.
+    /// A new socket is created with the options and interfaces of this one, taking over the
+    /// timeouts, TTL, loopback and default send interface changed since. Adopted sockets are
+    /// replaced by fresh ones as well, in the network namespace of the calling thread.
+    /// On failure this socket is kept as it was.
+    pub fn rebind(&mut self, port: u16) -> io::Result<()> {
+        let group = SocketAddrV4::new(*self.multicast_address.ip(), port);
+        let rebound =
+            crate::create_on_interfaces(self.options.clone(), self.interfaces(), group, self.join)?;
+
+        let (old, new) = (self.as_socket(), rebound.as_socket());
+        new.set_read_timeout(old.read_timeout()?)?;
+        rebound.set_write_timeout(old.write_timeout()?)?;
+        new.set_multicast_ttl_v4(old.multicast_ttl_v4()?)?;
+        new.set_multicast_loop_v4(old.multicast_loop_v4()?)?;
+        new.set_multicast_if_v4(&old.multicast_if_v4()?)?;
+
+        // Dropping the old socket leaves its memberships, as configured
+        *self = rebound;
+        Ok(())
+    }
+
+    /// Receive buffer of the socket next to the system limit, telling whether the size asked for
+    /// with `MulticastOptions::receive_buffer_size` was clamped and how to raise the limit
+    pub fn receive_buffer(&self) -> io::Result<ReceiveBuffer> {
+        let requested = self.options.receive_buffer_size;
+        let effective = self.as_socket().recv_buffer_size()?;
+        let granted = if cfg!(any(target_os = "linux", target_os = "android")) {
+            effective / 2
+        } else {
+            effective
+        };
+        let clamped = requested.is_some_and(|size| granted < size);
+        Ok(ReceiveBuffer {
+            requested,
+            effective,
+            max: crate::max_receive_buffer()?,
+            clamped,
+            raise_with: requested
+                .filter(|_| clamped)
+                .and_then(crate::raise_receive_buffer_command),
+        })
+    }
+
+    /// Changes `MulticastOptions::write_timeout`, `None` blocking until sent
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.as_socket().set_write_timeout(timeout)?;
+        if let Some(send_sockets) = &self.send_sockets {
+            send_sockets.set_write_timeout(timeout)?;
+        }
+        Ok(())
+    }
+
+    /// Pins the interface that sends with `Interface::Default` go out of, set once with
+    /// `IP_MULTICAST_IF` instead of leaving the choice to the kernel.
+    /// Passing `Interface::Default` gives the choice back to the kernel.
+    pub fn set_default_send_interface(&self, interface: &Interface) -> io::Result<()> {
+        let address =
+            match interface {
+                Interface::Default => Ipv4Addr::UNSPECIFIED,
+                Interface::Ip(address) => *address,
+                Interface::Index(index) => crate::interface_ips(*index)?
+                    .first()
+                    .copied()
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::AddrNotAvailable,
+                            format!("interface {} has no IPv4 address", index),
+                        )
+                    })?,
+            };
+        self.as_socket().set_multicast_if_v4(&address)
+    }
+
+    /// Datagrams and bytes received and sent since the socket was created, and the datagrams
+    /// dropped on the way. Each counter is read on its own, without stopping other threads.
+    pub fn stats(&self) -> Stats {
+        self.counters.snapshot()
+    }
+
+    /// Sends to the group out of `interface`, picked for this datagram alone with an
+    /// `IP_PKTINFO` control message, so no `setsockopt` runs and other sends aren't affected
+    pub fn send(&self, buf: &[u8], interface: &Interface) -> io::Result<usize> {
+        if let Some(pacer) = &self.pacer {
+            pacer.wait();
+        }
+        self.send_retrying(buf, interface)
+    }
+
+    /// Same as `send`, awaiting the pacing delay instead of sleeping.
+    /// The datagram itself is still sent with a blocking call, like `send`.
+    #[cfg(feature = "tokio")]
+    pub async fn send_async(&self, buf: &[u8], interface: &Interface) -> io::Result<usize> {
+        if let Some(pacer) = &self.pacer {
+            pacer.wait_async().await;
+        }
+        self.send_retrying(buf, interface)
+    }
+
+    /// Same as `send`, with multicast loopback set to `loopback` for this datagram only, e.g. to
+    /// let local consumers see one announcement, see `send_with`
+    pub fn send_with_loopback(
+        &self,
+        buf: &[u8],
+        interface: &Interface,
+        loopback: bool,
+    ) -> io::Result<usize> {
+        let options = SendOptions {
+            interface: *interface,
+            loopback: Some(loopback),
+            ..Default::default()
+        };
+        self.send_with(buf, &options)
+    }
+
+    /// Same as `send`, with the parameters of `options` for this datagram only. The ones sent
+    /// along with the datagram leave other sends alone. The ones switched on the socket around
+    /// the send, like `loopback`, make other sends wait meanwhile, are restored even when
+    /// switching them or the send fails, and fail with `InvalidInput` on `pre_configured`
+    /// sockets. It goes out through the socket itself, even with
+    /// `dedicated_send_sockets`.
+    pub fn send_with(&self, buf: &[u8], options: &SendOptions) -> io::Result<usize> {
+        let switched_ttl = options
+            .ttl
+            .filter(|_| !cfg!(any(target_os = "linux", target_os = "android")));
+        let switches = options.loopback.is_some() || switched_ttl.is_some();
+        if switches && self.pre_configured {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "these send options switch socket options, which `pre_configured` rules out",
+            ));
+        }
+        if let Some(pacer) = &self.pacer {
+            pacer.wait();
+        }
+
+        let destination = self.multicast_address();
+        let send = || {
+            self.retry_send(buf, &options.interface, destination, || {
+                self.send_to_address_with(buf, destination, options)
+            })
+        };
+        if !switches {
+            let _guard = self.loopback_lock.read().unwrap_or_else(|e| e.into_inner());
+            return send();
+        }
+
+        let _guard = self
+            .loopback_lock
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        let socket = self.as_socket();
+        let default_loopback = socket.multicast_loop_v4()?;
+        let default_ttl = socket.multicast_ttl_v4()?;
+        let loopback = options.loopback.unwrap_or(default_loopback);
+        let ttl = switched_ttl.map_or(default_ttl, u32::from);
+        let switched = (|| {
+            if loopback != default_loopback {
+                socket.set_multicast_loop_v4(loopback)?;
+            }
+            if ttl != default_ttl {
+                socket.set_multicast_ttl_v4(ttl)?;
+            }
+            send()
+        })();
+        // Both restored whatever failed, even an option that wasn't switched yet, which is a no-op
+        let restored_loopback = if loopback != default_loopback {
+            socket.set_multicast_loop_v4(default_loopback)
+        } else {
+            Ok(())
+        };
+        let restored_ttl = if ttl != default_ttl {
+            socket.set_multicast_ttl_v4(default_ttl)
+        } else {
+            Ok(())
+        };
+        let sent = switched?;
+        restored_loopback?;
+        restored_ttl?;
+        Ok(sent)
+    }
+
+    fn send_retrying(&self, buf: &[u8], interface: &Interface) -> io::Result<usize> {
+        let _guard = self.loopback_lock.read().unwrap_or_else(|e| e.into_inner());
+        let destination = self.multicast_address();
+        self.retry_send(buf, interface, destination, || {
+            match (&self.send_sockets, interface) {
+                (Some(send_sockets), Interface::Ip(address)) => {
+                    send_sockets.send(self.as_socket(), self.multicast_address(), *address, buf)
+                }
+                _ => self.send_to_socket(buf, interface),
+            }
+        })
+    }
+
+    /// Retries interrupted sends, and transient failures as `MulticastOptions::send_retry` says
+    fn retry_send<F>(
+        &self,
+        buf: &[u8],
+        interface: &Interface,
+        destination: SocketAddrV4,
+        send: F,
+    ) -> io::Result<usize>
+    where
+        F: FnMut() -> io::Result<usize>,
+    {
+        let result = self.retry_attempts(interface, send);
+        match &result {
+            Ok(sent) => self.count_sent(&buf[..*sent], interface, destination),
+            Err(_) => self.count_send_error(),
+        }
+        result
+    }
+
+    fn count_sent(&self, data: &[u8], interface: &Interface, destination: SocketAddrV4) {
+        count(&self.counters.sent, 1);
+        count(&self.counters.sent_bytes, data.len());
+        #[cfg(feature = "metrics")]
+        crate::telemetry::sent(&self.labels, interface, data.len());
+        #[cfg(feature = "trace-payloads")]
+        crate::hexdump::trace(Direction::Sent, &destination, interface, data);
+        if let Some(capture) = &self.options.capture {
+            capture.record(&Datagram {
+                direction: Direction::Sent,
+                data,
+                interface: *interface,
+                source: sent_from(interface, self.local_port),
+                destination,
+                ttl: None,
+                tos: None,
+                time: None,
+            });
+        }
+    }
+
+    fn count_send_error(&self) {
+        count(&self.counters.send_errors, 1);
+        #[cfg(feature = "metrics")]
+        crate::telemetry::failed(&self.labels, "send");
+    }
+
+    /// Sends `datagrams` to the group, each run of them out of the same interface with a single
+    /// `sendmmsg` call where there is one. A datagram that fails is skipped and the rest still go
+    /// out, failing with the first error afterwards. The pacing of the whole batch is waited
+    /// for up front.
+    pub(crate) fn send_batch(&self, datagrams: &[(Vec<u8>, Interface)]) -> io::Result<()> {
+        if let Some(pacer) = &self.pacer {
+            let delay = datagrams.iter().map(|_| pacer.reserve()).max();
+            if let Some(delay) = delay.filter(|delay| !delay.is_zero()) {
+                thread::sleep(delay);
+            }
+        }
+
+        let _guard = self.loopback_lock.read().unwrap_or_else(|e| e.into_inner());
+        let destination = self.multicast_address();
+        let mut first_error = None;
+        let mut rest = datagrams;
+        while let Some((_, interface)) = rest.first() {
+            let run = rest.iter().take_while(|(_, i)| i == interface).count();
+            match self.retry_attempts(interface, || {
+                self.send_many_to_socket(&rest[..run], interface)
+            }) {
+                Ok(sent) => {
+                    for (data, _) in &rest[..sent] {
+                        self.count_sent(data, interface, destination);
+                    }
+                    rest = &rest[sent..];
+                }
+                Err(e) => {
+                    self.count_send_error();
+                    first_error.get_or_insert(e);
+                    rest = &rest[1..];
+                }
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    fn retry_attempts<F>(&self, interface: &Interface, mut send: F) -> io::Result<usize>
+    where
+        F: FnMut() -> io::Result<usize>,
+    {
+        let mut retry = 0;
+        loop {
+            match send() {
+                Err(e) if self.retry_interrupted && e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if is_transient_send_error(&e) => match &self.send_retry {
+                    Some(policy) if retry < policy.retries => {
+                        thread::sleep(policy.delay(retry));
+                        retry += 1;
+                    }
+                    Some(_) => {
+                        return Err(io::Error::new(
+                            e.kind(),
+                            format!(
+                                "sending on {:?} failed after {} attempts: {}",
+                                interface,
+                                retry + 1,
+                                e
+                            ),
+                        ))
+                    }
+                    None => return Err(e),
+                },
+                result => return result,
+            }
+        }
+    }
+
+    /// Sends to the limited broadcast address `255.255.255.255` on the port of the group, out of
+    /// `interface`, paced like `send`. Needs `MulticastOptions::broadcast`.
+    pub fn send_broadcast(&self, buf: &[u8], interface: &Interface) -> io::Result<usize> {
+        if let Some(pacer) = &self.pacer {
+            pacer.wait();
+        }
+        let destination = SocketAddrV4::new(Ipv4Addr::BROADCAST, self.multicast_address().port());
+        self.retry_send(buf, interface, destination, || {
+            self.send_to_address(buf, interface, destination)
+        })
+    }
+
+    /// Sends to the broadcast address of the subnet of `interface`, see `directed_broadcast`,
+    /// on the port of the group. Needs `MulticastOptions::broadcast`.
+    pub fn send_directed_broadcast(&self, buf: &[u8], interface: Ipv4Addr) -> io::Result<usize> {
+        if let Some(pacer) = &self.pacer {
+            pacer.wait();
+        }
+        let destination = SocketAddrV4::new(
+            directed_broadcast(interface)?,
+            self.multicast_address().port(),
+        );
+        let interface = Interface::Ip(interface);
+        self.retry_send(buf, &interface, destination, || {
+            self.send_to_address(buf, &interface, destination)
+        })
+    }
+
+    /// Same as `broadcast`, with a directed broadcast on the subnet of each interface
+    pub fn send_directed_broadcast_all(&self, buf: &[u8]) -> io::Result<()> {
+        for interface in self.interfaces() {
+            self.send_directed_broadcast(buf, interface)?;
+        }
+        Ok(())
+    }
+
+    /// Same as `broadcast`, with a limited broadcast out of each interface
+    pub fn send_broadcast_all(&self, buf: &[u8]) -> io::Result<()> {
+        for interface in self.interfaces() {
+            self.send_broadcast(buf, &Interface::Ip(interface))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    pub async fn broadcast_async(&self, buf: &[u8]) -> io::Result<()> {
+        for interface in self.interfaces() {
+            self.send_async(buf, &Interface::Ip(interface)).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Message {
+    /// Moves the payload out, e.g. into a parser taking ownership
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Length of the payload
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Differentiated services codepoint, the upper 6 bits of `tos`
+    pub fn dscp(&self) -> Option<u8> {
+        self.tos.map(|tos| tos >> 2)
+    }
+
+    /// ECN codepoint, the lower 2 bits of `tos`
+    pub fn ecn(&self) -> Option<Ecn> {
+        self.tos.map(Ecn::from_tos)
+    }
+}
+
+impl AsRef<[u8]> for Message {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Multicast loopback delivers on the interface of the default route without any network
+    // behind it, so only hosts without a route have nothing to join on
+    #[test]
+    fn received_interface_index_sends_on_the_same_interface() {
+        let address = match default_route_interface() {
+            Ok(address) => address,
+            Err(_) => return,
+        };
+        let index = crate::interface_index(address).unwrap();
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 167, 1), 47167);
+        let options = MulticastOptions {
+            read_timeout: Duration::from_secs(2),
+            loopback: true,
+            ..Default::default()
+        };
+        let socket = MulticastSocket::with_options(group, vec![address], options).unwrap();
+
+        socket.send(b"request", &Interface::Ip(address)).unwrap();
+        let request = socket.receive().unwrap();
+        assert_eq!(request.interface, Interface::Index(index));
+        assert_eq!(
+            request.packet_info.map(|info| info.interface_index),
+            Some(index)
+        );
+
+        socket.send(b"reply", &request.interface).unwrap();
+        let reply = socket.receive().unwrap();
+        assert_eq!(reply.data, b"reply");
+        assert_eq!(reply.interface, request.interface);
+    }
+}
@@ -0,0 +1,87 @@
+//! Receiving from several sockets, e.g. of different groups or ports, on a single thread.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddrV4;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{poll_readable, Message, MulticastSocket};
+
+#[derive(Default)]
+pub struct MulticastSocketSet {
+    sockets: Vec<MulticastSocket>,
+    // Datagrams drained from ready sockets but not returned yet, with the index of their socket
+    pending: Mutex<VecDeque<(usize, Message)>>,
+    // First drain error, returned once the datagrams drained before it are
+    error: Mutex<Option<io::Error>>,
+}
+
+impl MulticastSocketSet {
+    pub fn new(sockets: Vec<MulticastSocket>) -> Self {
+        MulticastSocketSet {
+            sockets,
+            pending: Mutex::new(VecDeque::new()),
+            error: Mutex::new(None),
+        }
+    }
+
+    pub fn add(&mut self, socket: MulticastSocket) {
+        self.sockets.push(socket);
+    }
+
+    pub fn sockets(&self) -> &[MulticastSocket] {
+        &self.sockets
+    }
+
+    /// Waits up to `timeout` for a datagram on any of the sockets, returning it with the group
+    /// of its socket. Fails with `WouldBlock` if none arrives. A socket failing to receive
+    /// doesn't lose the datagrams of the others, its error is returned once they are.
+    pub fn receive_any(&self, timeout: Duration) -> io::Result<(SocketAddrV4, Message)> {
+        let deadline = Instant::now() + timeout;
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some((index, message)) = pending.pop_front() {
+                return Ok((self.sockets[index].multicast_address(), message));
+            }
+            if let Some(e) = self.error.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                return Err(e);
+            }
+
+            // Readable sockets may only hold datagrams their filters drop, so this goes on
+            // until the deadline
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let sockets: Vec<_> = self.sockets.iter().map(|s| s.as_socket()).collect();
+            let readable = poll_readable(&sockets, remaining)?;
+            for (index, readable) in readable.into_iter().enumerate() {
+                if !readable {
+                    continue;
+                }
+                // The other sockets are still drained, their datagrams returned first
+                match self.sockets[index].drain() {
+                    Ok(messages) => {
+                        pending.extend(messages.into_iter().map(|message| (index, message)))
+                    }
+                    Err(e) => {
+                        self.error
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .get_or_insert(e);
+                    }
+                }
+            }
+            if pending.is_empty() {
+                if let Some(e) = self.error.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                    return Err(e);
+                }
+            }
+
+            if pending.is_empty() && remaining.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "no datagram within the timeout",
+                ));
+            }
+        }
+    }
+}
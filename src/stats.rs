@@ -0,0 +1,70 @@
+//! Counters behind `MulticastSocket::stats`, relaxed atomics so the threads receiving and
+//! sending on a socket don't serialize on them.
+
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+
+use crate::Stats;
+
+#[cfg(target_has_atomic = "64")]
+pub(crate) type Counter = std::sync::atomic::AtomicU64;
+// Wraps around sooner, on targets without 64 bit atomics
+#[cfg(not(target_has_atomic = "64"))]
+pub(crate) type Counter = std::sync::atomic::AtomicUsize;
+
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub(crate) received: Counter,
+    pub(crate) received_bytes: Counter,
+    pub(crate) sent: Counter,
+    pub(crate) sent_bytes: Counter,
+    pub(crate) own_packets: Counter,
+    pub(crate) duplicates: Counter,
+    pub(crate) filtered: Counter,
+    pub(crate) receive_errors: Counter,
+    pub(crate) send_errors: Counter,
+    pub(crate) receive_queue_high_water: Counter,
+    pub(crate) receive_queue_full_micros: Counter,
+    pub(crate) receive_queue_dropped: Counter,
+    pub(crate) send_queue_high_water: Counter,
+    pub(crate) send_queue_full_micros: Counter,
+}
+
+pub(crate) fn count(counter: &Counter, n: usize) {
+    counter.fetch_add(n as _, Relaxed);
+}
+
+#[cfg(feature = "tokio")]
+pub(crate) fn count_max(counter: &Counter, n: usize) {
+    counter.fetch_max(n as _, Relaxed);
+}
+
+// In microseconds, so 32 bit counters last over an hour
+#[cfg(feature = "tokio")]
+pub(crate) fn count_duration(counter: &Counter, duration: Duration) {
+    counter.fetch_add(duration.as_micros() as _, Relaxed);
+}
+
+impl Counters {
+    pub(crate) fn snapshot(&self) -> Stats {
+        // A no-op cast where `Counter` is already 64 bit
+        #[allow(clippy::unnecessary_cast)]
+        let load = |counter: &Counter| counter.load(Relaxed) as u64;
+        Stats {
+            received: load(&self.received),
+            received_bytes: load(&self.received_bytes),
+            sent: load(&self.sent),
+            sent_bytes: load(&self.sent_bytes),
+            own_packets: load(&self.own_packets),
+            duplicates: load(&self.duplicates),
+            filtered: load(&self.filtered),
+            receive_errors: load(&self.receive_errors),
+            send_errors: load(&self.send_errors),
+            receive_queue_high_water: load(&self.receive_queue_high_water),
+            receive_queue_full: Duration::from_micros(load(&self.receive_queue_full_micros)),
+            receive_queue_dropped: load(&self.receive_queue_dropped),
+            send_queue_high_water: load(&self.send_queue_high_water),
+            send_queue_full: Duration::from_micros(load(&self.send_queue_full_micros)),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Counters and gauges of the sockets through the `metrics` facade, with the `metrics` feature,
+//! for whichever recorder the application installed, e.g. a Prometheus exporter.
+//!
+//! Every metric is labelled with the `group` of the socket, as `address:port`, and the ones of
+//! a datagram with the `interface` it came in on or went out of as well.
+
+use std::net::SocketAddrV4;
+use std::sync::{Arc, RwLock};
+
+use metrics::{Label, SharedString};
+
+use crate::Interface;
+
+/// Labels of a socket, formatted once and shared by its metrics, so datagrams don't allocate
+/// them again. The one of an interface is added when a datagram first goes through it.
+pub(crate) struct Labels {
+    group: Label,
+    interfaces: RwLock<Vec<(Interface, Label)>>,
+}
+
+impl Labels {
+    pub(crate) fn new(group: SocketAddrV4) -> Self {
+        Labels {
+            group: label("group", group.to_string()),
+            interfaces: RwLock::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn set_group(&mut self, group: SocketAddrV4) {
+        self.group = label("group", group.to_string());
+    }
+
+    fn interface(&self, interface: &Interface) -> Label {
+        let known = |interfaces: &[(Interface, Label)]| {
+            interfaces
+                .iter()
+                .find(|(i, _)| i == interface)
+                .map(|(_, label)| label.clone())
+        };
+        if let Some(label) = known(&self.interfaces.read().unwrap_or_else(|e| e.into_inner())) {
+            return label;
+        }
+        let mut interfaces = self.interfaces.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(label) = known(&interfaces) {
+            return label;
+        }
+        let value = match interface {
+            Interface::Default => "default".to_owned(),
+            Interface::Ip(address) => address.to_string(),
+            Interface::Index(index) => index.to_string(),
+        };
+        let label = label("interface", value);
+        interfaces.push((*interface, label.clone()));
+        label
+    }
+}
+
+// Behind an `Arc`, so clones of the label don't copy the value
+fn label(key: &'static str, value: String) -> Label {
+    Label::new(key, SharedString::from_shared(Arc::from(value)))
+}
+
+pub(crate) fn received(labels: &Labels, interface: &Interface, bytes: usize) {
+    let labels = vec![labels.group.clone(), labels.interface(interface)];
+    metrics::counter!("multicast_socket_received_packets", labels.clone()).increment(1);
+    metrics::counter!("multicast_socket_received_bytes", labels).increment(bytes as u64);
+}
+
+pub(crate) fn sent(labels: &Labels, interface: &Interface, bytes: usize) {
+    let labels = vec![labels.group.clone(), labels.interface(interface)];
+    metrics::counter!("multicast_socket_sent_packets", labels.clone()).increment(1);
+    metrics::counter!("multicast_socket_sent_bytes", labels).increment(bytes as u64);
+}
+
+/// Datagram dropped for `reason`: `own`, `duplicate`, `filtered` or `queue_full`
+pub(crate) fn dropped(labels: &Labels, interface: Option<&Interface>, reason: &'static str) {
+    let mut labels_of_drop = vec![labels.group.clone(), Label::new("reason", reason)];
+    if let Some(interface) = interface {
+        labels_of_drop.push(labels.interface(interface));
+    }
+    metrics::counter!("multicast_socket_dropped_packets", labels_of_drop).increment(1);
+}
+
+/// Failed call, `receive` or `send`
+pub(crate) fn failed(labels: &Labels, operation: &'static str) {
+    let labels = vec![labels.group.clone(), Label::new("operation", operation)];
+    metrics::counter!("multicast_socket_errors", labels).increment(1);
+}
+
+/// Datagrams waiting in the channel of `spawn_tokio_receiver`
+#[cfg(feature = "tokio")]
+pub(crate) fn receive_queue_depth(labels: &Labels, depth: usize) {
+    metrics::gauge!(
+        "multicast_socket_receive_queue_depth",
+        vec![labels.group.clone()]
+    )
+    .set(depth as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn builds_the_label_of_an_interface_once() {
+        let mut labels = Labels::new(SocketAddrV4::new(Ipv4Addr::new(239, 255, 0, 1), 5000));
+        let address = Interface::Ip(Ipv4Addr::new(192, 0, 2, 2));
+        for _ in 0..3 {
+            assert_eq!(labels.interface(&address).value(), "192.0.2.2");
+            assert_eq!(labels.interface(&Interface::Index(4)).value(), "4");
+        }
+        assert_eq!(labels.interfaces.read().unwrap().len(), 2);
+
+        assert_eq!(labels.group.value(), "239.255.0.1:5000");
+        labels.set_group(SocketAddrV4::new(Ipv4Addr::new(239, 255, 0, 2), 5000));
+        assert_eq!(labels.group.value(), "239.255.0.2:5000");
+    }
+}
@@ -1,58 +1,297 @@
+use std::ffi::CStr;
 use std::io;
+use std::io::{IoSlice, IoSliceMut};
 use std::mem;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::os::unix::io::AsRawFd;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, RawFd};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
 
 use socket2::{Domain, Protocol, Socket, Type};
 
 use nix::sys::socket as sock;
-use nix::sys::uio::IoVec;
 
-fn create_on_interfaces(
+use crate::dedup::Deduplicator;
+use crate::egress::SendSockets;
+#[cfg(feature = "enumerate")]
+use crate::membership::AddressWatch;
+use crate::membership::MembershipRefresh;
+use crate::membership::Memberships;
+use crate::pacing::Pacer;
+use crate::readiness::Readiness;
+use crate::socket::{
+    local_port, subnets, validate_multicast_address, validate_pre_configured, with_control_buffer,
+    Subnet, ROUTER_ALERT,
+};
+use crate::stats::Counters;
+use crate::MessageRef;
+
+pub(crate) fn create_on_interfaces(
     options: crate::MulticastOptions,
     interfaces: Vec<Ipv4Addr>,
     multicast_address: SocketAddrV4,
+    join: bool,
 ) -> io::Result<MulticastSocket> {
     let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+    socket.set_reuse_address(options.reuse_address)?;
+    socket.set_reuse_port(options.reuse_address)?;
+
+    let bind_unspecified = options.bind_unspecified || options.broadcast || options.ephemeral_port;
+    let port = if options.ephemeral_port {
+        0
+    } else {
+        multicast_address.port()
+    };
+    let mut multicast_socket =
+        join_on_interfaces(socket, options, interfaces, multicast_address, join)?;
+
+    // On Linux we bind to the multicast address, which causes multicast packets to be filtered
+    // Otherwhise we bind to 0.0.0.0
+    let bind_address = if cfg!(any(target_os = "linux", target_os = "android")) && !bind_unspecified
+    {
+        *multicast_address.ip()
+    } else {
+        Ipv4Addr::UNSPECIFIED
+    };
+    multicast_socket
+        .socket
+        .bind(&SocketAddr::new(bind_address.into(), port).into())?;
+
+    multicast_socket.local_port = local_port(&multicast_socket.socket)?;
+
+    Ok(multicast_socket)
+}
+
+/// Applies the options, packet info and memberships to a socket, without binding it.
+/// Shared by the constructors creating a fresh socket and the ones adopting a bound one.
+/// Without `join`, packet info and memberships are skipped, for sockets only sending.
+fn join_on_interfaces(
+    socket: Socket,
+    options: crate::MulticastOptions,
+    interfaces: Vec<Ipv4Addr>,
+    multicast_address: SocketAddrV4,
+    join: bool,
+) -> io::Result<MulticastSocket> {
+    validate_multicast_address(&multicast_address)?;
+    validate_pre_configured(&options)?;
     socket.set_read_timeout(Some(options.read_timeout))?;
-    socket.set_multicast_loop_v4(options.loopback)?;
-    socket.set_reuse_address(true)?;
-    socket.set_reuse_port(true)?;
+    socket.set_write_timeout(options.write_timeout)?;
+    if let Some(size) = options.receive_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if options.broadcast {
+        socket.set_broadcast(true)?;
+    }
+    socket.set_multicast_loop_v4(options.loopback || options.host_local)?;
+    if options.host_local {
+        socket.set_multicast_ttl_v4(0)?;
+    }
 
-    sock::setsockopt(socket.as_raw_fd(), sock::sockopt::Ipv4PacketInfo, &true)
-        .map_err(nix_to_io_error)?;
+    // socket2 predates `AsFd`, but the descriptor outlives this borrow
+    let fd = unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) };
+    if join {
+        sock::setsockopt(&fd, sock::sockopt::Ipv4PacketInfo, &true).map_err(nix_to_io_error)?;
+    }
 
-    for interface in &interfaces {
-        socket.join_multicast_v4(multicast_address.ip(), &interface)?;
+    #[cfg(target_os = "linux")]
+    if let Some(steering) = &options.reuseport_steering {
+        attach_reuseport_steering(&socket, steering)?;
     }
 
-    // On Linux we bind to the multicast address, which causes multicast packets to be filtered
     #[cfg(any(target_os = "linux", target_os = "android"))]
-    socket.bind(&SocketAddr::from(multicast_address).into())?;
-    // Otherwhise we bind to 0.0.0.0
-    #[cfg(not(any(target_os = "linux", target_os = "android")))]
-    socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), multicast_address.port()).into())?;
+    if options.receive_errors {
+        sock::setsockopt(&fd, sock::sockopt::Ipv4RecvErr, &true).map_err(nix_to_io_error)?;
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if options.original_destination {
+        sock::setsockopt(&fd, sock::sockopt::Ipv4OrigDstAddr, &true).map_err(nix_to_io_error)?;
+    }
 
-    Ok(MulticastSocket {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if options.receive_ttl {
+        sock::setsockopt(&fd, sock::sockopt::Ipv4RecvTtl, &true).map_err(nix_to_io_error)?;
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if options.receive_tos {
+        sock::setsockopt(&fd, sock::sockopt::IpRecvTos, &true).map_err(nix_to_io_error)?;
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if options.receive_timestamps.is_some() {
+        sock::setsockopt(&fd, sock::sockopt::ReceiveTimestampns, &true).map_err(nix_to_io_error)?;
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if options.freebind {
+        sock::setsockopt(&fd, sock::sockopt::IpFreebind, &true).map_err(nix_to_io_error)?;
+    }
+
+    set_send_options(&socket, &options)?;
+
+    if let Some(configure) = &options.configure {
+        configure(&socket)?;
+    }
+
+    let mut joined = Vec::new();
+    for interface in interfaces.iter().filter(|_| join) {
+        match socket.join_multicast_v4(multicast_address.ip(), interface) {
+            // Already a member, e.g. on an adopted socket
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {}
+            // No interface has this address yet
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Err(e) if options.freebind && e.raw_os_error() == Some(libc::ENODEV) => continue,
+            result => result?,
+        }
+        joined.push(*interface);
+    }
+
+    let memberships = Memberships::new(
+        &socket,
+        *multicast_address.ip(),
+        joined,
+        // Leaving on drop is a setsockopt as well, closing makes the kernel leave anyway
+        options.leave_on_drop && !options.pre_configured,
+    );
+    let local_port = local_port(&socket)?;
+    // Their interfaces are known by now, unlike when receiving
+    let directed_broadcasts = if options.broadcast {
+        interfaces
+            .iter()
+            .filter_map(|i| crate::directed_broadcast(*i).ok())
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let source_subnets = if options.same_subnet_sources {
+        Some(subnets(interfaces.iter()))
+    } else {
+        None
+    };
+    // Kept for `rebind`, as the fields below take parts of them
+    let kept_options = options.clone();
+    let mut multicast_socket = MulticastSocket {
+        memberships,
         socket,
         interfaces,
         multicast_address,
-        buffer_size: options.buffer_size,
-    })
+        buffer_size: options.buffer_size.unwrap_or(0),
+        receive_buffer: Vec::new(),
+        control_buffer_size: options
+            .control_buffer_size
+            .map_or(control_space(&options), |size| {
+                size.max(control_space(&options))
+            }),
+        local_port,
+        ignore_own_packets: options.ignore_own_packets,
+        filter_destination: options.bind_unspecified || options.broadcast,
+        broadcast: options.broadcast,
+        directed_broadcasts,
+        source_subnets,
+        retry_interrupted: options.retry_interrupted,
+        receive_spin: options.receive_spin,
+        deduplicator: options
+            .dedup_window
+            .map(|w| Mutex::new(Deduplicator::new(w))),
+        pacer: options.pacing.map(Pacer::new),
+        send_retry: options.send_retry,
+        pre_configured: options.pre_configured,
+        options: kept_options,
+        join,
+        send_sockets: if options.dedicated_send_sockets {
+            Some(SendSockets::new(&options))
+        } else {
+            None
+        },
+        loopback_lock: RwLock::new(()),
+        #[cfg(feature = "enumerate")]
+        address_watch: options
+            .rejoin_interval
+            .map(|i| Mutex::new(AddressWatch::new(i))),
+        membership_refresh: options
+            .membership_refresh
+            .map(|i| Mutex::new(MembershipRefresh::new(i))),
+        counters: Counters::default(),
+        drain_error: Mutex::new(None),
+        #[cfg(feature = "metrics")]
+        labels: crate::telemetry::Labels::new(multicast_address),
+        readiness: Readiness::default(),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        routed_interface: AtomicU32::new(0),
+    };
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if options.route_default_interface {
+        multicast_socket.refresh_routed_interface()?;
+    }
+    if options.buffer_size.is_none() {
+        multicast_socket.buffer_size = multicast_socket.largest_mtu();
+    }
+    if options.pre_configured {
+        if let Some(send_sockets) = &mut multicast_socket.send_sockets {
+            send_sockets.open(
+                &multicast_socket.socket,
+                multicast_address,
+                &multicast_socket.interfaces,
+            )?;
+        }
+    }
+    Ok(multicast_socket)
 }
 
 pub struct MulticastSocket {
-    socket: socket2::Socket,
+    // First, to leave the groups before the socket closes
+    pub(crate) memberships: Memberships,
+    pub(crate) socket: socket2::Socket,
     interfaces: Vec<Ipv4Addr>,
-    multicast_address: SocketAddrV4,
-    buffer_size: usize,
+    pub(crate) multicast_address: SocketAddrV4,
+    pub(crate) buffer_size: usize,
+    // Reused by `receive_ref`
+    pub(crate) receive_buffer: Vec<u8>,
+    control_buffer_size: usize,
+    pub(crate) local_port: u16,
+    ignore_own_packets: bool,
+    // Drops datagrams not addressed to the group
+    pub(crate) filter_destination: bool,
+    // Keeps limited broadcasts while filtering
+    pub(crate) broadcast: bool,
+    pub(crate) directed_broadcasts: Vec<Ipv4Addr>,
+    pub(crate) source_subnets: Option<Vec<Subnet>>,
+    pub(crate) retry_interrupted: bool,
+    pub(crate) receive_spin: Option<Duration>,
+    pub(crate) deduplicator: Option<Mutex<Deduplicator>>,
+    pub(crate) pacer: Option<Pacer>,
+    pub(crate) send_retry: Option<crate::SendRetry>,
+    pub(crate) pre_configured: bool,
+    // Construction arguments, to create the socket again
+    pub(crate) options: crate::MulticastOptions,
+    pub(crate) join: bool,
+    pub(crate) send_sockets: Option<SendSockets>,
+    // Held for writing while `send_with_loopback` switches the loopback option
+    pub(crate) loopback_lock: RwLock<()>,
+    #[cfg(feature = "enumerate")]
+    pub(crate) address_watch: Option<Mutex<AddressWatch>>,
+    pub(crate) membership_refresh: Option<Mutex<MembershipRefresh>>,
+    pub(crate) counters: Counters,
+    // Error of a receive `drain` stopped at, returned by its next call
+    pub(crate) drain_error: Mutex<Option<io::Error>>,
+    #[cfg(feature = "metrics")]
+    pub(crate) labels: crate::telemetry::Labels,
+    pub(crate) readiness: Readiness,
+    // Index `Interface::Default` sends go out of, 0 to leave it to the kernel
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    routed_interface: AtomicU32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Interface {
     Default,
     Ip(Ipv4Addr),
-    Index(i32),
+    /// Index of `if_nametoindex`, the same as in `PacketInfo::interface_index` and
+    /// `Message::interface`, so a reply can go out of the interface a datagram came in on
+    Index(u32),
 }
 
 #[derive(Debug)]
@@ -60,13 +299,56 @@ pub struct Message {
     pub data: Vec<u8>,
     pub origin_address: SocketAddrV4,
     pub interface: Interface,
+    /// Missing when the OS didn't deliver the packet info
+    pub packet_info: Option<crate::PacketInfo>,
+    /// Destination address and port before any redirection, with
+    /// `MulticastOptions::original_destination`
+    pub original_destination: Option<SocketAddrV4>,
+    /// TTL of the IP header, with `MulticastOptions::receive_ttl`
+    pub ttl: Option<u8>,
+    /// TOS byte of the IP header, with `MulticastOptions::receive_tos`
+    pub tos: Option<u8>,
+    /// Wall clock time the kernel received the datagram, with
+    /// `MulticastOptions::receive_timestamps`
+    pub received_at: Option<std::time::SystemTime>,
+    /// The same as an `Instant`, with `TimestampClock::Monotonic`
+    pub received_instant: Option<std::time::Instant>,
+    pub flags: crate::MessageFlags,
+}
+
+/// Notification about a sent datagram, read from the error queue with `receive_error`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[derive(Debug, Clone)]
+pub struct ErrorReport {
+    /// OS error code, such as `EHOSTUNREACH` or `EMSGSIZE`
+    pub error: i32,
+    /// Where the error comes from, one of the `SO_EE_ORIGIN_*` constants
+    pub origin: u8,
+    /// ICMP type and code, for ICMP errors
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+    /// Extra information, such as the path MTU when fragmentation is needed
+    pub info: u32,
+    /// Node that reported the error, e.g. a router
+    pub offender: Option<Ipv4Addr>,
+    /// Destination of the datagram
+    pub destination: Option<SocketAddrV4>,
+    /// Start of the datagram
+    pub data: Vec<u8>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl ErrorReport {
+    pub fn to_io_error(&self) -> io::Error {
+        io::Error::from_raw_os_error(self.error)
+    }
 }
 
 /// The crate `get_if_addrs` is reading the bytes of sockets on the wrong endianess on MIPS
 /// So the adresses are reversed...
 /// The crate `get_if_addrs` is archived and I don't have bandwidth to fork it
 /// So this is a hotfix
-#[cfg(target_arch = "mips")]
+#[cfg(all(feature = "enumerate", target_arch = "mips"))]
 fn reverse_interface(interface: get_if_addrs::Interface) -> get_if_addrs::Interface {
     get_if_addrs::Interface {
         name: interface.name,
@@ -84,14 +366,27 @@ fn reverse_interface(interface: get_if_addrs::Interface) -> get_if_addrs::Interf
     }
 }
 
-#[cfg(target_arch = "mips")]
+#[cfg(all(feature = "enumerate", target_arch = "mips"))]
 fn reverse_address(v4: Ipv4Addr) -> Ipv4Addr {
     let mut octets = v4.octets();
     octets.reverse();
     octets.into()
 }
 
+/// Addresses of the interfaces able to join groups, besides loopback. Interfaces without
+/// `IFF_MULTICAST`, like WireGuard and other point to point links, are left out, as they
+/// reject memberships.
+#[cfg(feature = "enumerate")]
 pub fn all_ipv4_interfaces() -> io::Result<Vec<Ipv4Addr>> {
+    let multicast: std::collections::HashSet<String> = nix::ifaddrs::getifaddrs()
+        .map_err(nix_to_io_error)?
+        .filter(|i| {
+            i.flags
+                .contains(nix::net::if_::InterfaceFlags::IFF_MULTICAST)
+        })
+        .map(|i| i.interface_name)
+        .collect();
+
     #[cfg(not(target_arch = "mips"))]
     let interfaces = get_if_addrs::get_if_addrs()?.into_iter();
     #[cfg(target_arch = "mips")]
@@ -100,6 +395,7 @@ pub fn all_ipv4_interfaces() -> io::Result<Vec<Ipv4Addr>> {
         .map(reverse_interface);
 
     let ipv4_interfaces = interfaces
+        .filter(|i| multicast.contains(&i.name))
         .filter_map(|i| match i.ip() {
             std::net::IpAddr::V4(v4) if !i.is_loopback() => Some(v4),
             _ => None,
@@ -108,10 +404,28 @@ pub fn all_ipv4_interfaces() -> io::Result<Vec<Ipv4Addr>> {
     Ok(ipv4_interfaces)
 }
 
+/// Addresses of the interfaces enslaved to the Linux VRF device `vrf`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn vrf_ipv4_interfaces(vrf: &str) -> io::Result<Vec<Ipv4Addr>> {
+    let enslaved = |name: &str| {
+        std::fs::read_link(format!("/sys/class/net/{}/master", name))
+            .map(|master| master.file_name() == Some(std::ffi::OsStr::new(vrf)))
+            .unwrap_or(false)
+    };
+
+    let interfaces = nix::ifaddrs::getifaddrs()
+        .map_err(nix_to_io_error)?
+        .filter(|i| enslaved(&i.interface_name))
+        .filter_map(|i| i.address.as_ref()?.as_sockaddr_in().map(|a| a.ip()))
+        .collect();
+    Ok(interfaces)
+}
+
 impl MulticastSocket {
+    #[cfg(feature = "enumerate")]
     pub fn all_interfaces(multicast_address: SocketAddrV4) -> io::Result<Self> {
         let interfaces = all_ipv4_interfaces()?;
-        create_on_interfaces(Default::default(), interfaces, multicast_address)
+        create_on_interfaces(Default::default(), interfaces, multicast_address, true)
     }
 
     pub fn with_options(
@@ -119,76 +433,1133 @@ impl MulticastSocket {
         interfaces: Vec<Ipv4Addr>,
         options: crate::MulticastOptions,
     ) -> io::Result<Self> {
-        create_on_interfaces(options, interfaces, multicast_address)
+        create_on_interfaces(options, interfaces, multicast_address, true)
+    }
+
+    /// Joins on every interface enslaved to the Linux VRF device `vrf`, bound to it
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn on_vrf(
+        multicast_address: SocketAddrV4,
+        vrf: &str,
+        options: crate::MulticastOptions,
+    ) -> io::Result<Self> {
+        let interfaces = vrf_ipv4_interfaces(vrf)?;
+        let options = crate::MulticastOptions {
+            vrf: Some(vrf.to_owned()),
+            ..options
+        };
+        create_on_interfaces(options, interfaces, multicast_address, true)
+    }
+
+    /// Same as `with_options`, creating the socket inside the network namespace `netns`,
+    /// an open namespace file like `/proc/<pid>/ns/net`. The interfaces are the ones of that
+    /// namespace. The construction runs on a helper thread, as `setns` applies to the calling
+    /// thread only, and the socket keeps its namespace afterwards.
+    /// `mtu` looks up interface addresses in the namespace of the calling thread.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn with_options_in_netns(
+        netns: &impl AsRawFd,
+        multicast_address: SocketAddrV4,
+        interfaces: Vec<Ipv4Addr>,
+        options: crate::MulticastOptions,
+    ) -> io::Result<Self> {
+        let netns = netns.as_raw_fd();
+        // Joined before returning, so the borrowed descriptor stays open meanwhile
+        std::thread::spawn(move || {
+            if unsafe { libc::setns(netns, libc::CLONE_NEWNET) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            create_on_interfaces(options, interfaces, multicast_address, true)
+        })
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("network namespace thread panicked")))
+    }
+
+    /// Same as `with_options_in_netns`, for a namespace named by `ip netns`,
+    /// found in `/var/run/netns`
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn with_options_in_named_netns(
+        name: &str,
+        multicast_address: SocketAddrV4,
+        interfaces: Vec<Ipv4Addr>,
+        options: crate::MulticastOptions,
+    ) -> io::Result<Self> {
+        let netns = std::fs::File::open(std::path::Path::new("/var/run/netns").join(name))?;
+        Self::with_options_in_netns(&netns, multicast_address, interfaces, options)
+    }
+
+    /// Socket only receiving, with default options. Sending is shut down where the OS allows it
+    /// for unconnected sockets, like Linux.
+    pub fn receiver(
+        multicast_address: SocketAddrV4,
+        interfaces: Vec<Ipv4Addr>,
+    ) -> io::Result<Self> {
+        let socket = create_on_interfaces(Default::default(), interfaces, multicast_address, true)?;
+        // Linux reports ENOTCONN for unconnected sockets, yet shuts down
+        let _ = socket.socket.shutdown(std::net::Shutdown::Write);
+        Ok(socket)
+    }
+
+    /// Socket only sending to the group out of `interfaces`, with default options. Neither
+    /// memberships nor packet info are set up, and it is bound to an ephemeral port.
+    pub fn sender(multicast_address: SocketAddrV4, interfaces: Vec<Ipv4Addr>) -> io::Result<Self> {
+        let options = crate::MulticastOptions {
+            ephemeral_port: true,
+            ..Default::default()
+        };
+        create_on_interfaces(options, interfaces, multicast_address, false)
+    }
+
+    /// Adopts an already bound socket, e.g. one inherited from a supervisor.
+    /// The options, packet info and memberships are applied, but the socket is not rebound,
+    /// so it should be bound to the port (and on Linux the address) of `multicast_address`.
+    pub fn from_socket(
+        socket: UdpSocket,
+        multicast_address: SocketAddrV4,
+        interfaces: Vec<Ipv4Addr>,
+        options: crate::MulticastOptions,
+    ) -> io::Result<Self> {
+        join_on_interfaces(socket.into(), options, interfaces, multicast_address, true)
+    }
+
+    /// Same as `from_socket`, taking ownership of a raw descriptor.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open UDP socket, not owned by anything else.
+    pub unsafe fn from_raw_fd(
+        fd: RawFd,
+        multicast_address: SocketAddrV4,
+        interfaces: Vec<Ipv4Addr>,
+        options: crate::MulticastOptions,
+    ) -> io::Result<Self> {
+        join_on_interfaces(
+            Socket::from_raw_fd(fd),
+            options,
+            interfaces,
+            multicast_address,
+            true,
+        )
+    }
+
+    /// Adopts the socket passed by systemd socket activation through `LISTEN_FDS`, the first
+    /// IPv4 datagram socket bound to the port of `multicast_address`, and applies the options like
+    /// `from_socket`. The unit binds it with `ListenDatagram=`, to the group address on Linux,
+    /// so the daemon needs no privileges for low ports. Fails with `NotFound` without such a
+    /// socket, and each descriptor is adopted once.
+    pub fn from_systemd(
+        multicast_address: SocketAddrV4,
+        interfaces: Vec<Ipv4Addr>,
+        options: crate::MulticastOptions,
+    ) -> io::Result<Self> {
+        let fd = activated_socket(multicast_address.port())?;
+        unsafe { Self::from_raw_fd(fd, multicast_address, interfaces, options) }
     }
 }
 
+/// Descriptors of socket activation handed out by `from_systemd`
+static ACTIVATED: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+
+/// Takes the first descriptor passed by socket activation that is an IPv4 datagram socket bound
+/// to `port`, as `sd_listen_fds` would list it
+fn activated_socket(port: u16) -> io::Result<RawFd> {
+    // SD_LISTEN_FDS_START
+    const FIRST: RawFd = 3;
+
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse().ok())
+        == Some(std::process::id());
+    let count: RawFd = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .filter(|_| for_us)
+        .unwrap_or(0);
+
+    let mut activated = ACTIVATED.lock().unwrap_or_else(|e| e.into_inner());
+    for fd in FIRST..FIRST + count {
+        if activated.contains(&fd) {
+            continue;
+        }
+        let mut socket_type: libc::c_int = 0;
+        let mut len = mem::size_of_val(&socket_type) as libc::socklen_t;
+        let r = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_TYPE,
+                &mut socket_type as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if r != 0 || socket_type != libc::SOCK_DGRAM {
+            continue;
+        }
+        // Borrowed, the descriptor is only owned once it matches
+        let socket = mem::ManuallyDrop::new(unsafe { Socket::from_raw_fd(fd) });
+        let bound = socket.local_addr().ok().and_then(|a| a.as_inet());
+        if bound.map(|a| a.port()) != Some(port) {
+            continue;
+        }
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        activated.push(fd);
+        return Ok(fd);
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "no IPv4 datagram socket on port {} passed by socket activation",
+            port
+        ),
+    ))
+}
+
+/// Name of the interface with `index`, as reported by `if_indextoname`
+pub fn interface_name(index: u32) -> io::Result<String> {
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    if unsafe { libc::if_indextoname(index, name.as_mut_ptr()) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+fn interface_name_by_address(address: Ipv4Addr) -> io::Result<String> {
+    nix::ifaddrs::getifaddrs()
+        .map_err(nix_to_io_error)?
+        .find(|i| {
+            i.address
+                .as_ref()
+                .and_then(|a| a.as_sockaddr_in())
+                .map(|a| a.ip())
+                == Some(address)
+        })
+        .map(|i| i.interface_name)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no interface with address {}", address),
+            )
+        })
+}
+
+/// Index of the interface having `address`, to compare with `Interface::Index`
+pub fn interface_index(address: Ipv4Addr) -> io::Result<u32> {
+    let name = std::ffi::CString::new(interface_name_by_address(address)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+/// IPv4 addresses of the interface with `index`
+pub fn interface_ips(index: u32) -> io::Result<Vec<Ipv4Addr>> {
+    let name = interface_name(index)?;
+    let addresses = nix::ifaddrs::getifaddrs()
+        .map_err(nix_to_io_error)?
+        .filter(|i| i.interface_name == name)
+        .filter_map(|i| i.address.as_ref()?.as_sockaddr_in().map(|a| a.ip()))
+        .collect();
+    Ok(addresses)
+}
+
+/// System-wide maximum of `SO_RCVBUF` for unprivileged sockets, `net.core.rmem_max` on Linux
+/// and `kern.ipc.maxsockbuf` on macOS and FreeBSD, which also counts the bookkeeping overhead.
+/// `None` where it isn't known.
+pub fn max_receive_buffer() -> io::Result<Option<usize>> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let max = std::fs::read_to_string("/proc/sys/net/core/rmem_max")?;
+        max.trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+    {
+        // An unsigned int on some releases and a long on others
+        let mut max: u64 = 0;
+        let mut len = mem::size_of_val(&max);
+        let name = b"kern.ipc.maxsockbuf\0";
+        let r = unsafe {
+            libc::sysctlbyname(
+                name.as_ptr() as *const _,
+                &mut max as *mut u64 as *mut _,
+                &mut len,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        if r != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if len == mem::size_of::<u32>() {
+            max = u64::from(max as u32);
+        }
+        Ok(Some(max as usize))
+    }
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd"
+    )))]
+    Ok(None)
+}
+
+/// Command raising `max_receive_buffer` so a receive buffer of `size` is granted
+pub(crate) fn raise_receive_buffer_command(size: usize) -> Option<String> {
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        Some(format!("sysctl -w net.core.rmem_max={}", size))
+    } else if cfg!(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd"
+    )) {
+        // The maximum covers the mbufs, 256 bytes of them for every 2048 bytes of clusters
+        Some(format!("sysctl -w kern.ipc.maxsockbuf={}", size + size / 8))
+    } else {
+        None
+    }
+}
+
+/// Netmask of the subnet of `address`
+pub fn interface_netmask(address: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    nix::ifaddrs::getifaddrs()
+        .map_err(nix_to_io_error)?
+        .find(|i| {
+            i.address
+                .as_ref()
+                .and_then(|a| a.as_sockaddr_in())
+                .map(|a| a.ip())
+                == Some(address)
+        })
+        .and_then(|i| Some(i.netmask?.as_sockaddr_in()?.ip()))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no netmask for address {}", address),
+            )
+        })
+}
+
+/// Index of the interface the main routing table sends `destination` out of, asked from the
+/// kernel with an `RTM_GETROUTE` netlink request, e.g. for a group through its multicast route
+/// or the default route
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn route_interface(destination: Ipv4Addr) -> io::Result<u32> {
+    const HEADER_LEN: usize = 16;
+    const RTMSG_LEN: usize = 12;
+    const ATTRIBUTE_LEN: usize = 8;
+    let request_len = HEADER_LEN + RTMSG_LEN + ATTRIBUTE_LEN;
+
+    let mut request = Vec::with_capacity(request_len);
+    request.extend_from_slice(&(request_len as u32).to_ne_bytes());
+    request.extend_from_slice(&libc::RTM_GETROUTE.to_ne_bytes());
+    request.extend_from_slice(&(libc::NLM_F_REQUEST as u16).to_ne_bytes());
+    request.extend_from_slice(&[0; 8]); // sequence and port id
+    request.extend_from_slice(&[libc::AF_INET as u8, 32, 0, 0, 0, 0, 0, 0]);
+    request.extend_from_slice(&[0; 4]); // flags
+    request.extend_from_slice(&(ATTRIBUTE_LEN as u16).to_ne_bytes());
+    request.extend_from_slice(&libc::RTA_DST.to_ne_bytes());
+    request.extend_from_slice(&destination.octets());
+
+    let socket = Socket::new(
+        Domain::from(libc::AF_NETLINK),
+        Type::dgram(),
+        Some(Protocol::from(libc::NETLINK_ROUTE)),
+    )?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    // Unaddressed, netlink messages go to the kernel
+    socket.send(&request)?;
+    let mut response = [0; 4096];
+    let len = socket.recv(&mut response)?;
+    route_response_interface(&response[..len], destination)
+}
+
+/// Output interface of the `RTM_NEWROUTE` answering a `route_interface` request, or the error
+/// of an `NLMSG_ERROR` one
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn route_response_interface(response: &[u8], destination: Ipv4Addr) -> io::Result<u32> {
+    const HEADER_LEN: usize = 16;
+    const RTMSG_LEN: usize = 12;
+    let len = response.len();
+
+    let u16_at = |at: usize| u16::from_ne_bytes([response[at], response[at + 1]]);
+    let u32_at = |at: usize| {
+        u32::from_ne_bytes([
+            response[at],
+            response[at + 1],
+            response[at + 2],
+            response[at + 3],
+        ])
+    };
+    let unanswered = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no interface in the route to {}", destination),
+        )
+    };
+
+    if len < HEADER_LEN + 4 {
+        return Err(unanswered());
+    }
+    let message_len = (u32_at(0) as usize).min(len);
+    match u16_at(4) {
+        t if t == libc::NLMSG_ERROR as u16 => {
+            // A negative errno, e.g. ENETUNREACH without any route
+            let errno = u32_at(HEADER_LEN) as i32;
+            Err(io::Error::from_raw_os_error(-errno))
+        }
+        libc::RTM_NEWROUTE => {
+            let mut at = HEADER_LEN + RTMSG_LEN;
+            while at + 4 <= message_len {
+                let attribute_len = usize::from(u16_at(at));
+                if attribute_len < 4 {
+                    break;
+                }
+                if u16_at(at + 2) == libc::RTA_OIF && at + 8 <= message_len {
+                    return Ok(u32_at(at + 4));
+                }
+                at += (attribute_len + 3) & !3;
+            }
+            Err(unanswered())
+        }
+        _ => Err(unanswered()),
+    }
+}
+
+/// Waits for at most `timeout` until one of `sockets` is readable, returning which are
+pub(crate) fn poll_readable(sockets: &[&Socket], timeout: Duration) -> io::Result<Vec<bool>> {
+    let mut fds: Vec<libc::pollfd> = sockets
+        .iter()
+        .map(|socket| libc::pollfd {
+            fd: socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fds.iter().map(|fd| fd.revents != 0).collect())
+}
+
+// Missing from libc on Apple targets
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const IP_OPTIONS: libc::c_int = 1;
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+use libc::IP_OPTIONS;
+
+/// Applies the options shaping outgoing datagrams, on the socket and the dedicated send sockets
+pub(crate) fn set_send_options(
+    socket: &Socket,
+    options: &crate::MulticastOptions,
+) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let fd = unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) };
+        if let Some(mode) = options.mtu_discovery {
+            set_mtu_discovery(socket, mode)?;
+        }
+        if let Some(mark) = options.mark {
+            sock::setsockopt(&fd, sock::sockopt::Mark, &mark).map_err(nix_to_io_error)?;
+        }
+        if let Some(vrf) = &options.vrf {
+            sock::setsockopt(&fd, sock::sockopt::BindToDevice, &vrf.into())
+                .map_err(nix_to_io_error)?;
+        }
+    }
+    if options.router_alert {
+        set_ip_options(socket, &ROUTER_ALERT)?;
+    }
+    if options.dont_route {
+        set_int_option(socket, libc::SOL_SOCKET, libc::SO_DONTROUTE, 1)?;
+    }
+    Ok(())
+}
+
+fn set_ip_options(socket: &Socket, ip_options: &[u8]) -> io::Result<()> {
+    let r = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            IP_OPTIONS,
+            ip_options.as_ptr() as *const libc::c_void,
+            ip_options.len() as libc::socklen_t,
+        )
+    };
+    if r == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_mtu_discovery(socket: &Socket, mode: crate::MtuDiscovery) -> io::Result<()> {
+    let mode = match mode {
+        crate::MtuDiscovery::Dont => libc::IP_PMTUDISC_DONT,
+        crate::MtuDiscovery::Want => libc::IP_PMTUDISC_WANT,
+        crate::MtuDiscovery::Do => libc::IP_PMTUDISC_DO,
+        crate::MtuDiscovery::Probe => libc::IP_PMTUDISC_PROBE,
+    };
+    set_int_option(socket, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, mode)
+}
+
+fn set_int_option(
+    socket: &Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let r = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if r == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// Missing from libc, asm-generic values
+#[cfg(target_os = "linux")]
+const SO_ATTACH_REUSEPORT_CBPF: libc::c_int = 51;
+#[cfg(target_os = "linux")]
+const SO_ATTACH_REUSEPORT_EBPF: libc::c_int = 52;
+
+#[cfg(target_os = "linux")]
+fn attach_reuseport_steering(
+    socket: &Socket,
+    steering: &crate::ReuseportSteering,
+) -> io::Result<()> {
+    use crate::ReuseportSteering;
+
+    if matches!(
+        steering,
+        ReuseportSteering::SourceAddress { sockets: 0 } | ReuseportSteering::Cpu { sockets: 0 }
+    ) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "reuseport steering needs at least one socket",
+        ));
+    }
+
+    let statement = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let program = match steering {
+        ReuseportSteering::Ebpf(fd) => {
+            return set_int_option(socket, libc::SOL_SOCKET, SO_ATTACH_REUSEPORT_EBPF, *fd)
+        }
+        ReuseportSteering::Program(program) => program.clone(),
+        // A = source address of the IP header, A %= sockets, return A
+        ReuseportSteering::SourceAddress { sockets } => vec![
+            statement(
+                libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+                (libc::SKF_NET_OFF + 12) as u32,
+            ),
+            statement(libc::BPF_ALU | libc::BPF_MOD | libc::BPF_K, *sockets),
+            statement(libc::BPF_RET | libc::BPF_A, 0),
+        ],
+        ReuseportSteering::Cpu { sockets } => vec![
+            statement(
+                libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+                (libc::SKF_AD_OFF + libc::SKF_AD_CPU) as u32,
+            ),
+            statement(libc::BPF_ALU | libc::BPF_MOD | libc::BPF_K, *sockets),
+            statement(libc::BPF_RET | libc::BPF_A, 0),
+        ],
+    };
+    let program = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_ptr() as *mut libc::sock_filter,
+    };
+    let r = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            SO_ATTACH_REUSEPORT_CBPF,
+            &program as *const libc::sock_fprog as *const libc::c_void,
+            mem::size_of_val(&program) as libc::socklen_t,
+        )
+    };
+    if r == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// Missing from libc on Apple targets: _IOWR('i', 51, struct ifreq)
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const SIOCGIFMTU: libc::c_ulong = 0xc020_6933;
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+use libc::SIOCGIFMTU;
+
+fn interface_mtu(socket: &Socket, name: &str) -> io::Result<u32> {
+    let mut request: libc::ifreq = unsafe { mem::zeroed() };
+    if name.len() >= request.ifr_name.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "interface name too long",
+        ));
+    }
+    for (destination, byte) in request.ifr_name.iter_mut().zip(name.bytes()) {
+        *destination = byte as libc::c_char;
+    }
+
+    if unsafe { libc::ioctl(socket.as_raw_fd(), SIOCGIFMTU as _, &mut request) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { request.ifr_ifru.ifru_mtu } as u32)
+}
+
+/// Index of the interface a packet info names, the same `interface_index` and
+/// `Interface::Index` use. The field is an `int` on Linux and unsigned elsewhere.
+pub(crate) fn packet_info_index(pktinfo: &libc::in_pktinfo) -> u32 {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return pktinfo.ipi_ifindex as u32;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    return pktinfo.ipi_ifindex;
+}
+
+/// Keeps the errno as the OS error, so `raw_os_error` tells `EADDRINUSE` from `ENODEV` and
+/// std derives the kind, e.g. `WouldBlock` for the `EAGAIN` of the read timeout
 fn nix_to_io_error(e: nix::Error) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, e)
+    io::Error::from_raw_os_error(e as i32)
+}
+
+/// Room for the control messages the options enable
+fn control_space(options: &crate::MulticastOptions) -> usize {
+    let space = |len: usize| unsafe { libc::CMSG_SPACE(len as _) as usize };
+    #[cfg_attr(
+        not(any(target_os = "linux", target_os = "android")),
+        allow(unused_mut)
+    )]
+    let mut total = space(mem::size_of::<libc::in_pktinfo>());
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        if options.original_destination {
+            total += space(mem::size_of::<libc::sockaddr_in>());
+        }
+        if options.receive_ttl {
+            total += space(mem::size_of::<libc::c_int>());
+        }
+        if options.receive_tos {
+            total += space(mem::size_of::<u8>());
+        }
+        if options.receive_timestamps.is_some() {
+            total += space(mem::size_of::<libc::timespec>());
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = options;
+    total
+}
+
+/// `Instant` of a wall clock time in the past, how long ago it was taken off now
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn instant_of(time: std::time::SystemTime) -> std::time::Instant {
+    let now = std::time::Instant::now();
+    let age = std::time::SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_default();
+    now.checked_sub(age).unwrap_or(now)
 }
 
 impl MulticastSocket {
-    pub fn receive(&self) -> io::Result<Message> {
-        let mut data_buffer = vec![0; self.buffer_size];
-        let mut control_buffer = nix::cmsg_space!(libc::in_pktinfo);
+    /// Receives into `buffer`, truncating longer datagrams.
+    /// Without `blocking`, fails with `WouldBlock` instead of waiting when nothing is queued.
+    pub(crate) fn receive_from_socket<'a>(
+        &self,
+        buffer: &'a mut [u8],
+        blocking: bool,
+    ) -> io::Result<MessageRef<'a>> {
+        with_control_buffer(self.control_buffer_size, move |control_buffer| {
+            self.receive_with_control(buffer, blocking, control_buffer)
+        })
+    }
+
+    fn receive_with_control<'a>(
+        &self,
+        buffer: &'a mut [u8],
+        blocking: bool,
+        control_buffer: &mut [u8],
+    ) -> io::Result<MessageRef<'a>> {
+        let flags = if blocking {
+            sock::MsgFlags::empty()
+        } else {
+            sock::MsgFlags::MSG_DONTWAIT
+        };
 
-        let message = sock::recvmsg(
+        let mut iov = [IoSliceMut::new(&mut *buffer)];
+        let message = sock::recvmsg::<sock::SockaddrIn>(
             self.socket.as_raw_fd(),
-            &[IoVec::from_mut_slice(&mut data_buffer)],
-            Some(&mut control_buffer),
-            sock::MsgFlags::empty(),
+            &mut iov,
+            Some(control_buffer),
+            flags,
         )
         .map_err(nix_to_io_error)?;
+        let mut flags = crate::MessageFlags::empty();
+        if message.flags.contains(sock::MsgFlags::MSG_TRUNC) {
+            flags |= crate::MessageFlags::TRUNCATED;
+        }
+        if message.flags.contains(sock::MsgFlags::MSG_CTRUNC) {
+            flags |= crate::MessageFlags::CONTROL_TRUNCATED;
+        }
 
         let origin_address = match message.address {
-            Some(sock::SockAddr::Inet(v4)) => Some(v4.to_std()),
-            _ => None,
-        };
-        let origin_address = match origin_address {
-            Some(SocketAddr::V4(v4)) => v4,
+            Some(v4) => SocketAddrV4::from(v4),
             _ => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
         };
 
         let mut interface = Interface::Default;
+        let mut packet_info = None;
+        #[cfg_attr(
+            not(any(target_os = "linux", target_os = "android")),
+            allow(unused_mut)
+        )]
+        let mut original_destination = None;
+        #[cfg_attr(
+            not(any(target_os = "linux", target_os = "android")),
+            allow(unused_mut)
+        )]
+        let (mut ttl, mut tos) = (None, None);
+        #[cfg_attr(
+            not(any(target_os = "linux", target_os = "android")),
+            allow(unused_mut)
+        )]
+        let (mut received_at, mut received_instant) = (None, None);
 
-        for cmsg in message.cmsgs() {
+        for cmsg in message.cmsgs().map_err(nix_to_io_error)? {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            match cmsg {
+                sock::ControlMessageOwned::Ipv4OrigDstAddr(address) => {
+                    original_destination = Some(SocketAddrV4::new(
+                        Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)),
+                        u16::from_be(address.sin_port),
+                    ));
+                    continue;
+                }
+                sock::ControlMessageOwned::Ipv4Ttl(value) => {
+                    ttl = Some(value as u8);
+                    continue;
+                }
+                sock::ControlMessageOwned::Ipv4Tos(value) => {
+                    tos = Some(value);
+                    continue;
+                }
+                sock::ControlMessageOwned::ScmTimestampns(time) => {
+                    let time = std::time::UNIX_EPOCH
+                        + Duration::new(time.tv_sec() as u64, time.tv_nsec() as u32);
+                    received_at = Some(time);
+                    if self.options.receive_timestamps == Some(crate::TimestampClock::Monotonic) {
+                        received_instant = Some(instant_of(time));
+                    }
+                    continue;
+                }
+                _ => {}
+            }
             if let sock::ControlMessageOwned::Ipv4PacketInfo(pktinfo) = cmsg {
-                interface = Interface::Index(pktinfo.ipi_ifindex as _);
+                interface = Interface::Index(packet_info_index(&pktinfo));
+                packet_info = Some(crate::PacketInfo {
+                    interface_index: packet_info_index(&pktinfo),
+                    local_address: Some(Ipv4Addr::from(u32::from_be(pktinfo.ipi_spec_dst.s_addr))),
+                    destination: Ipv4Addr::from(u32::from_be(pktinfo.ipi_addr.s_addr)),
+                });
             }
         }
 
-        Ok(Message {
-            data: data_buffer[0..message.bytes].to_vec(),
+        // Unix doesn't flag the destination kind, it's the one of the packet info
+        if let Some(info) = &packet_info {
+            if info.destination.is_multicast() {
+                flags |= crate::MessageFlags::MULTICAST;
+            } else if info.destination == Ipv4Addr::BROADCAST
+                || self.directed_broadcasts.contains(&info.destination)
+            {
+                flags |= crate::MessageFlags::BROADCAST;
+            }
+        }
+
+        let bytes = message.bytes;
+        Ok(MessageRef {
+            data: &buffer[0..bytes],
             origin_address,
             interface,
+            packet_info,
+            original_destination,
+            ttl,
+            tos,
+            received_at,
+            received_instant,
+            flags,
         })
     }
 
-    pub fn send(&self, buf: &[u8], interface: &Interface) -> io::Result<usize> {
-        let mut pkt_info: libc::in_pktinfo = unsafe { mem::zeroed() };
+    /// Takes the oldest notification off the error queue, enabled with
+    /// `MulticastOptions::receive_errors`. Returns `None` when the queue is empty.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn receive_error(&self) -> io::Result<Option<ErrorReport>> {
+        let mut buffer = vec![0; self.buffer_size];
+        let mut control_buffer = nix::cmsg_space!(libc::sock_extended_err, libc::sockaddr_in);
+        let mut iov = [IoSliceMut::new(&mut buffer)];
+        let message = match sock::recvmsg::<sock::SockaddrIn>(
+            self.socket.as_raw_fd(),
+            &mut iov,
+            Some(&mut control_buffer),
+            sock::MsgFlags::MSG_ERRQUEUE | sock::MsgFlags::MSG_DONTWAIT,
+        ) {
+            Err(nix::Error::EAGAIN) => return Ok(None),
+            result => result.map_err(nix_to_io_error)?,
+        };
 
-        match interface {
-            Interface::Default => {}
-            Interface::Ip(address) => pkt_info.ipi_spec_dst = sock::Ipv4Addr::from_std(address).0,
-            Interface::Index(index) => pkt_info.ipi_ifindex = *index as _,
+        let destination = message.address.map(SocketAddrV4::from);
+        let bytes = message.bytes;
+        let mut report = None;
+        for cmsg in message.cmsgs().map_err(nix_to_io_error)? {
+            if let sock::ControlMessageOwned::Ipv4RecvErr(error, offender) = cmsg {
+                report = Some(ErrorReport {
+                    error: error.ee_errno as i32,
+                    origin: error.ee_origin,
+                    icmp_type: error.ee_type,
+                    icmp_code: error.ee_code,
+                    info: error.ee_info,
+                    offender: offender
+                        .map(|o| Ipv4Addr::from(u32::from_be(o.sin_addr.s_addr)))
+                        .filter(|o| !o.is_unspecified()),
+                    destination,
+                    data: Vec::new(),
+                });
+            }
+        }
+        Ok(report.map(|report| ErrorReport {
+            data: buffer[0..bytes].to_vec(),
+            ..report
+        }))
+    }
+
+    pub(crate) fn send_to_socket(&self, buf: &[u8], interface: &Interface) -> io::Result<usize> {
+        self.send_to_address(buf, interface, self.multicast_address)
+    }
+
+    /// Sends to `destination` instead of the group, e.g. a broadcast address
+    pub(crate) fn send_to_address(
+        &self,
+        buf: &[u8],
+        interface: &Interface,
+        destination: SocketAddrV4,
+    ) -> io::Result<usize> {
+        let options = crate::SendOptions {
+            interface: *interface,
+            ..Default::default()
         };
+        self.send_to_address_with(buf, destination, &options)
+    }
 
-        let destination = sock::InetAddr::from_std(&self.multicast_address.into());
+    /// Sends with the parameters of `options` that go along with the datagram, control
+    /// messages and flags, leaving the ones switching socket options to `send_with`
+    pub(crate) fn send_to_address_with(
+        &self,
+        buf: &[u8],
+        destination: SocketAddrV4,
+        options: &crate::SendOptions,
+    ) -> io::Result<usize> {
+        let pkt_info = self.packet_info(&options.interface);
+
+        #[cfg_attr(
+            not(any(target_os = "linux", target_os = "android")),
+            allow(unused_mut)
+        )]
+        let mut control = vec![sock::ControlMessage::Ipv4PacketInfo(&pkt_info)];
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let ttl = options.ttl.map(libc::c_int::from);
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let tos = match (options.tos, options.ecn) {
+            (tos, Some(ecn)) => Some(tos.unwrap_or(0) & !0b11 | ecn as u8),
+            (tos, None) => tos,
+        };
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            if let Some(ttl) = &ttl {
+                control.push(sock::ControlMessage::Ipv4Ttl(ttl));
+            }
+            if let Some(tos) = &tos {
+                control.push(sock::ControlMessage::Ipv4Tos(tos));
+            }
+        }
+
+        let flags = if options.dont_route {
+            sock::MsgFlags::from_bits_retain(libc::MSG_DONTROUTE)
+        } else {
+            sock::MsgFlags::empty()
+        };
+        let destination = sock::SockaddrIn::from(destination);
 
         sock::sendmsg(
             self.socket.as_raw_fd(),
-            &[IoVec::from_slice(&buf)],
-            &[sock::ControlMessage::Ipv4PacketInfo(&pkt_info)],
-            sock::MsgFlags::empty(),
-            Some(&sock::SockAddr::new_inet(destination)),
+            &[IoSlice::new(buf)],
+            &control,
+            flags,
+            Some(&destination),
         )
         .map_err(nix_to_io_error)
     }
 
+    /// Picks the interface a datagram goes out of
+    fn packet_info(&self, interface: &Interface) -> libc::in_pktinfo {
+        let mut pkt_info: libc::in_pktinfo = unsafe { mem::zeroed() };
+        match *interface {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Interface::Default => {
+                pkt_info.ipi_ifindex = self.routed_interface.load(Ordering::Relaxed) as _
+            }
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            Interface::Default => {}
+            Interface::Ip(address) => {
+                pkt_info.ipi_spec_dst = libc::in_addr {
+                    s_addr: u32::from(address).to_be(),
+                }
+            }
+            Interface::Index(index) => pkt_info.ipi_ifindex = index as _,
+        };
+        pkt_info
+    }
+
+    /// Sends a run of datagrams to the group out of `interface` with one `sendmmsg` call,
+    /// returning how many of them went out
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) fn send_many_to_socket(
+        &self,
+        datagrams: &[(Vec<u8>, Interface)],
+        interface: &Interface,
+    ) -> io::Result<usize> {
+        let pkt_info = self.packet_info(interface);
+        let control = [sock::ControlMessage::Ipv4PacketInfo(&pkt_info)];
+        let slices: Vec<[IoSlice<'_>; 1]> = datagrams
+            .iter()
+            .map(|(data, _)| [IoSlice::new(data)])
+            .collect();
+        let destinations = vec![Some(sock::SockaddrIn::from(self.multicast_address)); slices.len()];
+        let mut headers = sock::MultiHeaders::<sock::SockaddrIn>::preallocate(
+            slices.len(),
+            Some(nix::cmsg_space!(libc::in_pktinfo)),
+        );
+        let sent = sock::sendmmsg(
+            self.socket.as_raw_fd(),
+            &mut headers,
+            &slices,
+            &destinations,
+            control,
+            sock::MsgFlags::empty(),
+        )
+        .map_err(nix_to_io_error)?;
+        Ok(sent.count())
+    }
+
+    /// Without `sendmmsg`, sends the first datagram of the run alone
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub(crate) fn send_many_to_socket(
+        &self,
+        datagrams: &[(Vec<u8>, Interface)],
+        interface: &Interface,
+    ) -> io::Result<usize> {
+        self.send_to_socket(&datagrams[0].0, interface).map(|_| 1)
+    }
+
+    /// Interface `Interface::Default` sends go out of, with
+    /// `MulticastOptions::route_default_interface`
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn routed_interface(&self) -> Option<Interface> {
+        match self.routed_interface.load(Ordering::Relaxed) {
+            0 => None,
+            index => Some(Interface::Index(index)),
+        }
+    }
+
+    /// Looks the route to the group up again, e.g. after the routing table changed, and sends
+    /// `Interface::Default` datagrams out of the interface it names. Returns that interface.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn refresh_routed_interface(&self) -> io::Result<Interface> {
+        let index = route_interface(*self.multicast_address.ip())?;
+        self.routed_interface.store(index, Ordering::Relaxed);
+        Ok(Interface::Index(index))
+    }
+
+    /// Sends to the group out of every interface, a single `sendmsg` for each
     pub fn broadcast(&self, buf: &[u8]) -> io::Result<()> {
         for interface in &self.interfaces {
             self.send(buf, &Interface::Ip(*interface))?;
         }
         Ok(())
     }
+
+    /// Whether the datagram was sent by this socket, seen through multicast loopback. Also true
+    /// for other local sockets sending from the port of the group, see `ignore_own_packets`.
+    pub(crate) fn is_own_packet(&self, origin_address: &SocketAddrV4) -> bool {
+        self.ignore_own_packets
+            && (origin_address.port() == self.local_port
+                || self
+                    .send_sockets
+                    .as_ref()
+                    .is_some_and(|s| s.owns_port(origin_address.port())))
+            && self.interfaces.contains(origin_address.ip())
+    }
+
+    /// MTU of an interface, to size datagrams and avoid fragmentation.
+    /// For `Interface::Default` the smallest MTU of the joined interfaces is returned,
+    /// as the kernel may pick any of them.
+    pub fn mtu(&self, interface: &Interface) -> io::Result<u32> {
+        match interface {
+            Interface::Default => {
+                let mut smallest: Option<u32> = None;
+                for address in &self.interfaces {
+                    let mtu = self.mtu(&Interface::Ip(*address))?;
+                    smallest = Some(smallest.map_or(mtu, |s| s.min(mtu)));
+                }
+                smallest.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "socket joined no interface")
+                })
+            }
+            Interface::Ip(address) => {
+                interface_mtu(&self.socket, &interface_name_by_address(*address)?)
+            }
+            Interface::Index(index) => interface_mtu(&self.socket, &interface_name(*index)?),
+        }
+    }
+
+    /// Addresses of the interfaces the socket joined on
+    pub fn interfaces(&self) -> Vec<Ipv4Addr> {
+        self.interfaces.clone()
+    }
+
+    /// Group and port the socket listens to
+    pub fn multicast_address(&self) -> SocketAddrV4 {
+        self.multicast_address
+    }
+
+    /// The underlying socket, to apply options this crate doesn't model.
+    /// Changing options the crate relies on, like packet info, breaks `receive` and `send`.
+    pub fn as_socket(&self) -> &socket2::Socket {
+        &self.socket
+    }
+
+    /// Gives up the multicast handling and returns the underlying socket.
+    /// Memberships and socket options stay in place, as they belong to the descriptor.
+    pub fn into_inner(mut self) -> socket2::Socket {
+        self.memberships.forget();
+        self.socket
+    }
+}
+
+/// Registers the socket with event loops, such as `Poller::add` of the `polling` crate.
+/// Datagrams dropped by the filters of this crate, like own packets or duplicates, still wake up
+/// the loop, so read with `try_receive` or `drain` instead of blocking. With edge triggered
+/// interest only new datagrams wake up, so drain the socket before waiting again. Level
+/// triggered interest wakes up as long as any datagram is queued.
+impl AsFd for MulticastSocket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.socket.as_raw_fd()) }
+    }
+}
+
+impl AsRawFd for MulticastSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for MulticastSocket {
+    fn into_raw_fd(self) -> RawFd {
+        self.into_inner().into_raw_fd()
+    }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::*;
+
+    // Netlink header without sequence and port id, then the body
+    fn message(message_type: u16, body: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.extend_from_slice(&((16 + body.len()) as u32).to_ne_bytes());
+        message.extend_from_slice(&message_type.to_ne_bytes());
+        message.extend_from_slice(&[0; 10]);
+        message.extend_from_slice(body);
+        message
+    }
+
+    fn attribute(body: &mut Vec<u8>, attribute_type: u16, value: &[u8]) {
+        body.extend_from_slice(&((4 + value.len()) as u16).to_ne_bytes());
+        body.extend_from_slice(&attribute_type.to_ne_bytes());
+        body.extend_from_slice(value);
+        body.resize((body.len() + 3) & !3, 0);
+    }
+
+    fn route(attributes: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut body = vec![libc::AF_INET as u8, 32, 0, 0, 254, 0, 1, 0, 0, 0, 0, 0];
+        for (attribute_type, value) in attributes {
+            attribute(&mut body, *attribute_type, value);
+        }
+        message(libc::RTM_NEWROUTE, &body)
+    }
+
+    const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 0, 1);
+
+    #[test]
+    fn finds_the_output_interface_among_the_attributes() {
+        let response = route(&[
+            (libc::RTA_TABLE, &254u32.to_ne_bytes()),
+            (libc::RTA_DST, &GROUP.octets()),
+            (libc::RTA_PREFSRC, &[192, 0, 2, 2]),
+            (libc::RTA_OIF, &4u32.to_ne_bytes()),
+        ]);
+        assert_eq!(route_response_interface(&response, GROUP).unwrap(), 4);
+    }
+
+    #[test]
+    fn reports_the_errno_of_an_error_message() {
+        let response = message(
+            libc::NLMSG_ERROR as u16,
+            &(-libc::ENETUNREACH).to_ne_bytes(),
+        );
+        let e = route_response_interface(&response, GROUP).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENETUNREACH));
+    }
+
+    #[test]
+    fn fails_without_an_output_interface() {
+        let without_oif = route(&[(libc::RTA_DST, &GROUP.octets())]);
+        // An attribute shorter than its header ends the walk
+        let mut malformed = route(&[]);
+        malformed.extend_from_slice(&2u16.to_ne_bytes());
+        malformed.extend_from_slice(&libc::RTA_DST.to_ne_bytes());
+        attribute(&mut malformed, libc::RTA_OIF, &4u32.to_ne_bytes());
+        let len = malformed.len() as u32;
+        malformed[..4].copy_from_slice(&len.to_ne_bytes());
+        // Cut within the attribute
+        let mut truncated = route(&[(libc::RTA_OIF, &4u32.to_ne_bytes())]);
+        truncated.truncate(truncated.len() - 2);
+
+        for response in [without_oif, malformed, truncated, vec![0; 8]] {
+            let e = route_response_interface(&response, GROUP).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
 }
@@ -3,17 +3,21 @@ use std::ffi::CStr;
 use std::io;
 use std::iter::FromIterator;
 use std::mem;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
 use std::os::windows::prelude::*;
 use std::ptr;
 use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
 
 use socket2::{Domain, Protocol, Socket, Type};
 
 use winapi::ctypes::{c_char, c_int};
+use winapi::shared::ifmib::MIB_IFROW;
 use winapi::shared::inaddr::*;
 use winapi::shared::minwindef::DWORD;
 use winapi::shared::minwindef::{INT, LPDWORD};
+use winapi::shared::winerror::WSAEMSGSIZE;
 use winapi::shared::ws2def::LPWSAMSG;
 use winapi::shared::ws2def::*;
 use winapi::shared::ws2ipdef::*;
@@ -22,6 +26,21 @@ use winapi::um::mswsock::{LPFN_WSARECVMSG, LPFN_WSASENDMSG, WSAID_WSARECVMSG, WS
 use winapi::um::winsock2 as sock;
 use winapi::um::winsock2::{LPWSAOVERLAPPED, LPWSAOVERLAPPED_COMPLETION_ROUTINE, SOCKET};
 
+use crate::dedup::Deduplicator;
+use crate::egress::SendSockets;
+#[cfg(feature = "enumerate")]
+use crate::membership::AddressWatch;
+use crate::membership::MembershipRefresh;
+use crate::membership::Memberships;
+use crate::pacing::Pacer;
+use crate::readiness::Readiness;
+use crate::socket::{
+    local_port, subnets, validate_multicast_address, validate_pre_configured, with_control_buffer,
+    Subnet, ROUTER_ALERT,
+};
+use crate::stats::Counters;
+use crate::MessageRef;
+
 fn last_error() -> io::Error {
     io::Error::from_raw_os_error(unsafe { sock::WSAGetLastError() })
 }
@@ -131,44 +150,255 @@ fn locate_wsasendmsg(socket: RawSocket) -> io::Result<WSASendMsgExtension> {
     }
 }
 
+/// Applies the options shaping outgoing datagrams, on the socket and the dedicated send sockets
+pub(crate) fn set_send_options(
+    socket: &Socket,
+    options: &crate::MulticastOptions,
+) -> io::Result<()> {
+    if options.router_alert {
+        unsafe { setsockopt(socket.as_raw_socket(), IPPROTO_IP, IP_OPTIONS, ROUTER_ALERT)? };
+    }
+    if options.dont_route {
+        unsafe { setsockopt(socket.as_raw_socket(), SOL_SOCKET, SO_DONTROUTE, 1 as c_int)? };
+    }
+    Ok(())
+}
+
 fn set_pktinfo(socket: RawSocket, payload: bool) -> io::Result<()> {
     unsafe { setsockopt(socket, IPPROTO_IP, IP_PKTINFO, payload as c_int) }
 }
 
-fn create_on_interfaces(
+pub(crate) fn create_on_interfaces(
     options: crate::MulticastOptions,
     interfaces: Vec<Ipv4Addr>,
     multicast_address: SocketAddrV4,
+    join: bool,
 ) -> io::Result<MulticastSocket> {
     let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+    if options.reuse_address {
+        socket.set_reuse_address(true)?;
+    } else {
+        unsafe {
+            setsockopt(
+                socket.as_raw_socket(),
+                SOL_SOCKET,
+                SO_EXCLUSIVEADDRUSE,
+                1 as c_int,
+            )?
+        };
+    }
+
+    let port = if options.ephemeral_port {
+        0
+    } else {
+        multicast_address.port()
+    };
+    let mut multicast_socket =
+        join_on_interfaces(socket, options, interfaces, multicast_address, join)?;
+
+    // On Windows, unlike all Unix variants, it is improper to bind to the multicast address
+    // see https://msdn.microsoft.com/en-us/library/windows/desktop/ms737550(v=vs.85).aspx
+    multicast_socket
+        .socket
+        .bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port).into())?;
+
+    multicast_socket.local_port = local_port(&multicast_socket.socket)?;
+
+    Ok(multicast_socket)
+}
+
+/// Applies the options, packet info and memberships to a socket, without binding it.
+/// Shared by the constructors creating a fresh socket and the ones adopting a bound one.
+/// Without `join`, packet info and memberships are skipped, for sockets only sending.
+fn join_on_interfaces(
+    socket: Socket,
+    options: crate::MulticastOptions,
+    interfaces: Vec<Ipv4Addr>,
+    multicast_address: SocketAddrV4,
+    join: bool,
+) -> io::Result<MulticastSocket> {
+    validate_multicast_address(&multicast_address)?;
+    validate_pre_configured(&options)?;
     socket.set_read_timeout(Some(options.read_timeout))?;
-    socket.set_multicast_loop_v4(options.loopback)?;
-    socket.set_reuse_address(true)?;
+    socket.set_write_timeout(options.write_timeout)?;
+    if let Some(size) = options.receive_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if options.broadcast {
+        socket.set_broadcast(true)?;
+    }
+    socket.set_multicast_loop_v4(options.loopback || options.host_local)?;
+    if options.host_local {
+        socket.set_multicast_ttl_v4(0)?;
+    }
 
     // enable fetching interface information and locate the extension function
-    set_pktinfo(socket.as_raw_socket(), true)?;
+    set_pktinfo(socket.as_raw_socket(), join)?;
     let wsarecvmsg: WSARecvMsgExtension = locate_wsarecvmsg(socket.as_raw_socket())?;
     let wsasendmsg: WSASendMsgExtension = locate_wsasendmsg(socket.as_raw_socket())?;
 
-    // Join multicast listeners on every interface passed
-    for interface in &interfaces {
-        socket.join_multicast_v4(multicast_address.ip(), &interface)?;
+    set_send_options(&socket, &options)?;
+
+    if options.receive_ttl {
+        unsafe { setsockopt(socket.as_raw_socket(), IPPROTO_IP, IP_RECVTTL, 1 as c_int)? };
     }
 
-    // On Windows, unlike all Unix variants, it is improper to bind to the multicast address
-    // see https://msdn.microsoft.com/en-us/library/windows/desktop/ms737550(v=vs.85).aspx
-    socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), multicast_address.port()).into())?;
+    if options.receive_tos {
+        unsafe { setsockopt(socket.as_raw_socket(), IPPROTO_IP, IP_RECVTOS, 1 as c_int)? };
+    }
+
+    if let Some(configure) = &options.configure {
+        configure(&socket)?;
+    }
+
+    // Join multicast listeners on every interface passed
+    let mut joined = Vec::new();
+    for interface in interfaces.iter().filter(|_| join) {
+        match socket.join_multicast_v4(multicast_address.ip(), interface) {
+            // Already a member, e.g. on an adopted socket
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {}
+            result => result?,
+        }
+        joined.push(*interface);
+    }
 
+    let memberships = Memberships::new(
+        &socket,
+        *multicast_address.ip(),
+        joined,
+        // Leaving on drop is a setsockopt as well, closing makes the kernel leave anyway
+        options.leave_on_drop && !options.pre_configured,
+    );
     let interfaces = build_address_table(HashSet::from_iter(interfaces))?;
 
-    Ok(MulticastSocket {
+    let local_port = local_port(&socket)?;
+    // Their interfaces are known by now, unlike when receiving
+    let directed_broadcasts = if options.broadcast {
+        interfaces
+            .values()
+            .filter_map(|i| crate::directed_broadcast(*i).ok())
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let source_subnets = if options.same_subnet_sources {
+        Some(subnets(interfaces.values()))
+    } else {
+        None
+    };
+    // Kept for `rebind`, as the fields below take parts of them
+    let kept_options = options.clone();
+    let mut multicast_socket = MulticastSocket {
+        memberships,
         socket,
         wsarecvmsg,
         wsasendmsg,
         interfaces,
         multicast_address,
-        buffer_size: options.buffer_size,
-    })
+        buffer_size: options.buffer_size.unwrap_or(0),
+        receive_buffer: Vec::new(),
+        control_buffer_size: options
+            .control_buffer_size
+            .map_or(control_space(&options), |size| {
+                size.max(control_space(&options))
+            }),
+        local_port,
+        ignore_own_packets: options.ignore_own_packets,
+        filter_destination: options.bind_unspecified || options.broadcast,
+        broadcast: options.broadcast,
+        directed_broadcasts,
+        source_subnets,
+        retry_interrupted: options.retry_interrupted,
+        receive_spin: options.receive_spin,
+        deduplicator: options
+            .dedup_window
+            .map(|w| Mutex::new(Deduplicator::new(w))),
+        pacer: options.pacing.map(Pacer::new),
+        send_retry: options.send_retry,
+        pre_configured: options.pre_configured,
+        options: kept_options,
+        join,
+        send_sockets: if options.dedicated_send_sockets {
+            Some(SendSockets::new(&options))
+        } else {
+            None
+        },
+        loopback_lock: RwLock::new(()),
+        #[cfg(feature = "enumerate")]
+        address_watch: options
+            .rejoin_interval
+            .map(|i| Mutex::new(AddressWatch::new(i))),
+        membership_refresh: options
+            .membership_refresh
+            .map(|i| Mutex::new(MembershipRefresh::new(i))),
+        counters: Counters::default(),
+        drain_error: Mutex::new(None),
+        #[cfg(feature = "metrics")]
+        labels: crate::telemetry::Labels::new(multicast_address),
+        readiness: Readiness::default(),
+    };
+    if options.buffer_size.is_none() {
+        multicast_socket.buffer_size = multicast_socket.largest_mtu();
+    }
+    if options.pre_configured {
+        let interfaces = multicast_socket.interfaces();
+        if let Some(send_sockets) = &mut multicast_socket.send_sockets {
+            send_sockets.open(&multicast_socket.socket, multicast_address, &interfaces)?;
+        }
+    }
+    Ok(multicast_socket)
+}
+
+/// IPv4 addresses of the adapters flagged `IP_ADAPTER_NO_MULTICAST`
+#[cfg(feature = "enumerate")]
+fn no_multicast_addresses() -> io::Result<HashSet<Ipv4Addr>> {
+    use winapi::shared::winerror::{ERROR_BUFFER_OVERFLOW, ERROR_SUCCESS};
+    use winapi::um::iphlpapi::GetAdaptersAddresses;
+
+    let flags = iptypes::GAA_FLAG_SKIP_ANYCAST
+        | iptypes::GAA_FLAG_SKIP_MULTICAST
+        | iptypes::GAA_FLAG_SKIP_DNS_SERVER;
+    // 15KB is what the documentation suggests to start with, it grows as asked otherwise
+    let mut buffer: Vec<u64> = vec![0; 15 * 1024 / 8];
+    loop {
+        let mut size = (buffer.len() * 8) as u32;
+        let first = buffer.as_mut_ptr() as iptypes::PIP_ADAPTER_ADDRESSES;
+        let r = unsafe {
+            GetAdaptersAddresses(AF_INET as u32, flags, ptr::null_mut(), first, &mut size)
+        };
+        if r == ERROR_BUFFER_OVERFLOW {
+            buffer.resize((size as usize).div_ceil(8), 0);
+            continue;
+        }
+        if r != ERROR_SUCCESS {
+            return Err(io::Error::from_raw_os_error(r as i32));
+        }
+
+        let mut addresses = HashSet::new();
+        let mut adapter = first as *const iptypes::IP_ADAPTER_ADDRESSES;
+        while let Some(current) = unsafe { adapter.as_ref() } {
+            if current.NoMulticast() != 0 {
+                let mut unicast =
+                    current.FirstUnicastAddress as *const iptypes::IP_ADAPTER_UNICAST_ADDRESS;
+                while let Some(entry) = unsafe { unicast.as_ref() } {
+                    let address = entry.Address.lpSockaddr;
+                    if !address.is_null() && unsafe { (*address).sa_family } == AF_INET as u16 {
+                        let address = unsafe { &*(address as *const SOCKADDR_IN) };
+                        let octets = unsafe { address.sin_addr.S_un.S_un_b() };
+                        addresses.insert(Ipv4Addr::new(
+                            octets.s_b1,
+                            octets.s_b2,
+                            octets.s_b3,
+                            octets.s_b4,
+                        ));
+                    }
+                    unicast = entry.Next;
+                }
+            }
+            adapter = current.Next;
+        }
+        return Ok(addresses);
+    }
 }
 
 /// Defines a allocation size for the buffer
@@ -176,7 +406,8 @@ fn create_on_interfaces(
 /// If things break, we can allocate the buffer a vec and try to double on error
 const MAX_AMOUNT_OF_INTERFACES: usize = 16;
 
-fn build_address_table(interfaces: HashSet<Ipv4Addr>) -> io::Result<HashMap<u32, Ipv4Addr>> {
+/// Index, address and netmask of every IPv4 address of every adapter
+fn adapter_addresses() -> io::Result<Vec<(u32, Ipv4Addr, Ipv4Addr)>> {
     let mut buffer = [0; mem::size_of::<iptypes::IP_ADAPTER_INFO>() * MAX_AMOUNT_OF_INTERFACES];
     let mut adapter_info = buffer.as_mut_ptr() as iptypes::PIP_ADAPTER_INFO;
     let mut size = buffer.len() as u32;
@@ -186,51 +417,175 @@ fn build_address_table(interfaces: HashSet<Ipv4Addr>) -> io::Result<HashMap<u32,
         return Err(io::Error::last_os_error());
     }
 
-    let mut table = HashMap::with_capacity(interfaces.len());
-    loop {
-        if adapter_info.is_null() {
-            break;
-        }
+    let mut addresses = Vec::new();
+    while !adapter_info.is_null() {
+        let current: &iptypes::IP_ADAPTER_INFO = unsafe { &*adapter_info };
 
-        let current: iptypes::IP_ADAPTER_INFO = unsafe { *adapter_info };
-        let ip_address =
-            unsafe { CStr::from_ptr(current.IpAddressList.IpAddress.String.as_ptr()) }.to_str();
-        let ip_address = match ip_address {
-            Ok(i) => Ipv4Addr::from_str(&i),
-            _ => {
-                continue;
-            }
-        };
-        let ip_address = match ip_address {
-            Ok(i) => i,
-            _ => {
-                continue;
+        let mut address_string: *const iptypes::IP_ADDR_STRING = &current.IpAddressList;
+        while !address_string.is_null() {
+            let entry = unsafe { &*address_string };
+            let parse = |string: &iptypes::IP_ADDRESS_STRING| {
+                unsafe { CStr::from_ptr(string.String.as_ptr()) }
+                    .to_str()
+                    .ok()
+                    .and_then(|i| Ipv4Addr::from_str(i).ok())
+            };
+            if let (Some(ip_address), Some(netmask)) =
+                (parse(&entry.IpAddress), parse(&entry.IpMask))
+            {
+                addresses.push((current.Index, ip_address, netmask));
             }
-        };
-
-        if interfaces.contains(&ip_address) {
-            table.insert(current.Index, ip_address);
+            address_string = entry.Next;
         }
 
         adapter_info = current.Next;
     }
 
-    Ok(table)
+    Ok(addresses)
+}
+
+fn build_address_table(interfaces: HashSet<Ipv4Addr>) -> io::Result<HashMap<u32, Ipv4Addr>> {
+    Ok(adapter_addresses()?
+        .into_iter()
+        .filter(|(_, address, _)| interfaces.contains(address))
+        .map(|(index, address, _)| (index, address))
+        .collect())
+}
+
+/// Name of the interface with `index`, as reported by `if_indextoname`
+pub fn interface_name(index: u32) -> io::Result<String> {
+    // NDIS_IF_MAX_STRING_SIZE + 1
+    let mut name = [0 as c_char; 257];
+    if unsafe { winapi::shared::netioapi::if_indextoname(index, name.as_mut_ptr()) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+/// Index of the interface having `address`, to compare with `Interface::Index`
+pub fn interface_index(address: Ipv4Addr) -> io::Result<u32> {
+    adapter_addresses()?
+        .into_iter()
+        .find(|(_, a, _)| *a == address)
+        .map(|(index, _, _)| index)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no interface with address {}", address),
+            )
+        })
+}
+
+/// IPv4 addresses of the interface with `index`
+pub fn interface_ips(index: u32) -> io::Result<Vec<Ipv4Addr>> {
+    Ok(adapter_addresses()?
+        .into_iter()
+        .filter(|(i, _, _)| *i == index)
+        .map(|(_, address, _)| address)
+        .collect())
+}
+
+/// Windows grants the `SO_RCVBUF` asked for, without a system-wide maximum to tune
+pub fn max_receive_buffer() -> io::Result<Option<usize>> {
+    Ok(None)
+}
+
+pub(crate) fn raise_receive_buffer_command(_size: usize) -> Option<String> {
+    None
+}
+
+/// Netmask of the subnet of `address`
+pub fn interface_netmask(address: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    adapter_addresses()?
+        .into_iter()
+        .find(|(_, a, _)| *a == address)
+        .map(|(_, _, netmask)| netmask)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no interface with address {}", address),
+            )
+        })
+}
+
+/// Waits for at most `timeout` until one of `sockets` is readable, returning which are
+pub(crate) fn poll_readable(sockets: &[&Socket], timeout: Duration) -> io::Result<Vec<bool>> {
+    let mut fds: Vec<sock::WSAPOLLFD> = sockets
+        .iter()
+        .map(|socket| sock::WSAPOLLFD {
+            fd: socket.as_raw_socket() as _,
+            events: sock::POLLRDNORM,
+            revents: 0,
+        })
+        .collect();
+    let timeout = timeout.as_millis().min(c_int::MAX as u128) as c_int;
+    if unsafe { sock::WSAPoll(fds.as_mut_ptr(), fds.len() as _, timeout) } < 0 {
+        return Err(last_error());
+    }
+    Ok(fds.iter().map(|fd| fd.revents != 0).collect())
+}
+
+fn interface_mtu(index: u32) -> io::Result<u32> {
+    let mut row: MIB_IFROW = unsafe { mem::zeroed() };
+    row.dwIndex = index;
+    let r = unsafe { winapi::um::iphlpapi::GetIfEntry(&mut row) };
+    if r != 0 {
+        return Err(io::Error::from_raw_os_error(r as i32));
+    }
+    Ok(row.dwMtu)
 }
 
 pub struct MulticastSocket {
-    socket: socket2::Socket,
+    // First, to leave the groups before the socket closes
+    pub(crate) memberships: Memberships,
+    pub(crate) socket: socket2::Socket,
     wsarecvmsg: WSARecvMsgExtension,
     wsasendmsg: WSASendMsgExtension,
     interfaces: HashMap<u32, Ipv4Addr>,
-    multicast_address: SocketAddrV4,
-    buffer_size: usize,
+    pub(crate) multicast_address: SocketAddrV4,
+    pub(crate) buffer_size: usize,
+    // Reused by `receive_ref`
+    pub(crate) receive_buffer: Vec<u8>,
+    control_buffer_size: usize,
+    pub(crate) local_port: u16,
+    ignore_own_packets: bool,
+    // Drops datagrams not addressed to the group
+    pub(crate) filter_destination: bool,
+    // Keeps limited broadcasts while filtering
+    pub(crate) broadcast: bool,
+    pub(crate) directed_broadcasts: Vec<Ipv4Addr>,
+    pub(crate) source_subnets: Option<Vec<Subnet>>,
+    pub(crate) retry_interrupted: bool,
+    pub(crate) receive_spin: Option<Duration>,
+    pub(crate) deduplicator: Option<Mutex<Deduplicator>>,
+    pub(crate) pacer: Option<Pacer>,
+    pub(crate) send_retry: Option<crate::SendRetry>,
+    pub(crate) pre_configured: bool,
+    // Construction arguments, to create the socket again
+    pub(crate) options: crate::MulticastOptions,
+    pub(crate) join: bool,
+    pub(crate) send_sockets: Option<SendSockets>,
+    // Held for writing while `send_with_loopback` switches the loopback option
+    pub(crate) loopback_lock: RwLock<()>,
+    #[cfg(feature = "enumerate")]
+    pub(crate) address_watch: Option<Mutex<AddressWatch>>,
+    pub(crate) membership_refresh: Option<Mutex<MembershipRefresh>>,
+    pub(crate) counters: Counters,
+    // Error of a receive `drain` stopped at, returned by its next call
+    pub(crate) drain_error: Mutex<Option<io::Error>>,
+    #[cfg(feature = "metrics")]
+    pub(crate) labels: crate::telemetry::Labels,
+    pub(crate) readiness: Readiness,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Interface {
     Default,
     Ip(Ipv4Addr),
+    /// IPv4 interface index, as `GetAdaptersInfo` and `IP_PKTINFO` report it, the same as in
+    /// `PacketInfo::interface_index` and `Message::interface`, so a reply can go out of the
+    /// interface a datagram came in on
     Index(u32),
 }
 
@@ -239,17 +594,48 @@ pub struct Message {
     pub data: Vec<u8>,
     pub origin_address: SocketAddrV4,
     pub interface: Interface,
+    /// Missing when the OS didn't deliver the packet info
+    pub packet_info: Option<crate::PacketInfo>,
+    /// Destination address and port before any redirection, with
+    /// `MulticastOptions::original_destination`
+    pub original_destination: Option<SocketAddrV4>,
+    /// TTL of the IP header, with `MulticastOptions::receive_ttl`
+    pub ttl: Option<u8>,
+    /// TOS byte of the IP header, with `MulticastOptions::receive_tos`
+    pub tos: Option<u8>,
+    /// Wall clock time the kernel received the datagram, with
+    /// `MulticastOptions::receive_timestamps`
+    pub received_at: Option<std::time::SystemTime>,
+    /// The same as an `Instant`, with `TimestampClock::Monotonic`
+    pub received_instant: Option<std::time::Instant>,
+    pub flags: crate::MessageFlags,
 }
 
 const CMSG_HEADER_SIZE: usize = mem::size_of::<WSACMSGHDR>();
 const PKTINFO_DATA_SIZE: usize = mem::size_of::<IN_PKTINFO>();
 const CONTROL_PKTINFO_BUFFER_SIZE: usize = CMSG_HEADER_SIZE + PKTINFO_DATA_SIZE;
+// Room for a control message carrying an `INT`, padded to the alignment of the next one
+const CONTROL_INT_BUFFER_SIZE: usize = CMSG_HEADER_SIZE + mem::size_of::<usize>();
 
+// Missing from winapi, see ws2ipdef.h
+const IP_RECVTTL: c_int = 21;
+const IP_RECVTOS: c_int = 40;
+
+/// Room for the control messages the options enable
+fn control_space(options: &crate::MulticastOptions) -> usize {
+    let ints = usize::from(options.receive_ttl) + usize::from(options.receive_tos);
+    CONTROL_PKTINFO_BUFFER_SIZE + ints * CONTROL_INT_BUFFER_SIZE
+}
+
+/// Addresses of the interfaces able to join groups. Adapters flagged
+/// `IP_ADAPTER_NO_MULTICAST` are left out, as they reject memberships.
+#[cfg(feature = "enumerate")]
 pub fn all_ipv4_interfaces() -> io::Result<Vec<Ipv4Addr>> {
+    let no_multicast = no_multicast_addresses()?;
     let interfaces = get_if_addrs::get_if_addrs()?
         .into_iter()
         .filter_map(|i| match i.ip() {
-            std::net::IpAddr::V4(v4) => Some(v4),
+            std::net::IpAddr::V4(v4) if !no_multicast.contains(&v4) => Some(v4),
             _ => None,
         })
         .collect();
@@ -257,9 +643,10 @@ pub fn all_ipv4_interfaces() -> io::Result<Vec<Ipv4Addr>> {
 }
 
 impl MulticastSocket {
+    #[cfg(feature = "enumerate")]
     pub fn all_interfaces(multicast_address: SocketAddrV4) -> io::Result<Self> {
         let interfaces = all_ipv4_interfaces()?;
-        create_on_interfaces(Default::default(), interfaces, multicast_address)
+        create_on_interfaces(Default::default(), interfaces, multicast_address, true)
     }
 
     pub fn with_options(
@@ -267,21 +654,91 @@ impl MulticastSocket {
         interfaces: Vec<Ipv4Addr>,
         options: crate::MulticastOptions,
     ) -> io::Result<Self> {
-        create_on_interfaces(options, interfaces, multicast_address)
+        create_on_interfaces(options, interfaces, multicast_address, true)
+    }
+
+    /// Socket only receiving, with default options. Sending is shut down where the OS allows it
+    /// for unconnected sockets.
+    pub fn receiver(
+        multicast_address: SocketAddrV4,
+        interfaces: Vec<Ipv4Addr>,
+    ) -> io::Result<Self> {
+        let socket = create_on_interfaces(Default::default(), interfaces, multicast_address, true)?;
+        // Unconnected sockets may refuse it, receiving works either way
+        let _ = socket.socket.shutdown(std::net::Shutdown::Write);
+        Ok(socket)
+    }
+
+    /// Socket only sending to the group out of `interfaces`, with default options. Neither
+    /// memberships nor packet info are set up, and it is bound to an ephemeral port.
+    pub fn sender(multicast_address: SocketAddrV4, interfaces: Vec<Ipv4Addr>) -> io::Result<Self> {
+        let options = crate::MulticastOptions {
+            ephemeral_port: true,
+            ..Default::default()
+        };
+        create_on_interfaces(options, interfaces, multicast_address, false)
+    }
+
+    /// Adopts an already bound socket, e.g. one inherited from a supervisor.
+    /// The options, packet info and memberships are applied, but the socket is not rebound,
+    /// so it should be bound to `0.0.0.0` on the port of `multicast_address`.
+    pub fn from_socket(
+        socket: UdpSocket,
+        multicast_address: SocketAddrV4,
+        interfaces: Vec<Ipv4Addr>,
+        options: crate::MulticastOptions,
+    ) -> io::Result<Self> {
+        join_on_interfaces(socket.into(), options, interfaces, multicast_address, true)
+    }
+
+    /// Same as `from_socket`, taking ownership of a raw socket.
+    ///
+    /// # Safety
+    ///
+    /// `socket` must be an open UDP socket, not owned by anything else.
+    pub unsafe fn from_raw_socket(
+        socket: RawSocket,
+        multicast_address: SocketAddrV4,
+        interfaces: Vec<Ipv4Addr>,
+        options: crate::MulticastOptions,
+    ) -> io::Result<Self> {
+        join_on_interfaces(
+            Socket::from_raw_socket(socket),
+            options,
+            interfaces,
+            multicast_address,
+            true,
+        )
     }
 }
 
 impl MulticastSocket {
-    pub fn receive(&self) -> io::Result<Message> {
-        let mut data_buffer = vec![0; self.buffer_size];
+    /// Receives into `buffer`, truncating longer datagrams.
+    /// Without `blocking`, fails with `WouldBlock` instead of waiting when nothing is queued.
+    /// Windows has no per call flag for it, so the socket is switched to non blocking meanwhile.
+    pub(crate) fn receive_from_socket<'a>(
+        &self,
+        buffer: &'a mut [u8],
+        blocking: bool,
+    ) -> io::Result<MessageRef<'a>> {
+        with_control_buffer(self.control_buffer_size, move |control_buffer| {
+            self.receive_with_control(buffer, blocking, control_buffer)
+        })
+    }
+
+    fn receive_with_control<'a>(
+        &self,
+        buffer: &'a mut [u8],
+        blocking: bool,
+        control_buffer: &mut [u8],
+    ) -> io::Result<MessageRef<'a>> {
         let mut data = WSABUF {
-            buf: data_buffer.as_mut_ptr(),
-            len: data_buffer.len() as u32,
+            buf: buffer.as_mut_ptr() as *mut c_char,
+            len: buffer.len() as u32,
         };
 
-        let mut control_buffer = [0; CONTROL_PKTINFO_BUFFER_SIZE];
         let control = WSABUF {
-            buf: control_buffer.as_mut_ptr(),
+            buf: control_buffer.as_mut_ptr() as *mut c_char,
             len: control_buffer.len() as u32,
         };
 
@@ -295,6 +752,10 @@ impl MulticastSocket {
             dwFlags: 0,
         };
 
+        if !blocking {
+            self.socket.set_nonblocking(true)?;
+        }
+
         let mut read_bytes = 0;
         let r = {
             unsafe {
@@ -308,8 +769,29 @@ impl MulticastSocket {
             }
         };
 
+        // Taken before restoring the blocking mode, which would overwrite it
+        let error = io::Error::last_os_error();
+        if !blocking {
+            self.socket.set_nonblocking(false)?;
+        }
+        // Longer datagrams fail with `WSAEMSGSIZE`, after filling the buffer
+        if r != 0 && error.raw_os_error() != Some(WSAEMSGSIZE as i32) {
+            return Err(error);
+        }
+        let mut flags = crate::MessageFlags::empty();
+        for (flag, reported) in [
+            (MSG_TRUNC, crate::MessageFlags::TRUNCATED),
+            (MSG_CTRUNC, crate::MessageFlags::CONTROL_TRUNCATED),
+            (MSG_BCAST, crate::MessageFlags::BROADCAST),
+            (MSG_MCAST, crate::MessageFlags::MULTICAST),
+        ] {
+            if wsa_msg.dwFlags & flag as DWORD != 0 {
+                flags |= reported;
+            }
+        }
         if r != 0 {
-            return Err(io::Error::last_os_error());
+            flags |= crate::MessageFlags::TRUNCATED;
+            read_bytes = buffer.len() as _;
         }
 
         let origin_address = unsafe {
@@ -326,27 +808,102 @@ impl MulticastSocket {
         };
 
         let mut interface = Interface::Default;
-        // Ensures that the control buffer is the size of the CSMG_HEADER + the pkinto data
-        if control.len as usize == CONTROL_PKTINFO_BUFFER_SIZE {
-            let cmsg_header: WSACMSGHDR = unsafe { ptr::read_unaligned(control.buf as *const _) }; // TODO fix clippy warning without breaking the code
-            if cmsg_header.cmsg_level == IPPROTO_IP && cmsg_header.cmsg_type == IP_PKTINFO {
-                let interface_info: IN_PKTINFO =
-                    unsafe { ptr::read_unaligned(control.buf.add(CMSG_HEADER_SIZE) as *const _) }; // TODO fix clippy warning without breaking the code
+        let mut packet_info = None;
+        let (mut ttl, mut tos) = (None, None);
+        // Walks the control messages the call filled in, looking for the packet info
+        let control_len = wsa_msg.Control.len as usize;
+        let mut offset = 0;
+        while offset + CMSG_HEADER_SIZE <= control_len {
+            let cmsg_header: WSACMSGHDR =
+                unsafe { ptr::read_unaligned(control.buf.add(offset) as *const _) };
+            if cmsg_header.cmsg_len < CMSG_HEADER_SIZE {
+                break;
+            }
+            if cmsg_header.cmsg_level == IPPROTO_IP
+                && cmsg_header.cmsg_type == IP_PKTINFO
+                && offset + CONTROL_PKTINFO_BUFFER_SIZE <= control_len
+            {
+                let interface_info: IN_PKTINFO = unsafe {
+                    ptr::read_unaligned(control.buf.add(offset + CMSG_HEADER_SIZE) as *const _)
+                };
                 interface = Interface::Index(interface_info.ipi_ifindex);
-            };
-        };
+                packet_info = Some(crate::PacketInfo {
+                    interface_index: interface_info.ipi_ifindex,
+                    local_address: None,
+                    destination: Ipv4Addr::from(u32::from_be(unsafe {
+                        *interface_info.ipi_addr.S_un.S_addr()
+                    })),
+                });
+            }
+            // An `INT`, whose first byte holds the value on little endian Windows. The type is
+            // the one of the option setting the value or the one enabling it, depending on the
+            // Windows version.
+            if cmsg_header.cmsg_level == IPPROTO_IP
+                && offset + CMSG_HEADER_SIZE < control_len
+                && cmsg_header.cmsg_len > CMSG_HEADER_SIZE
+            {
+                let value = unsafe { *control.buf.add(offset + CMSG_HEADER_SIZE) } as u8;
+                match cmsg_header.cmsg_type {
+                    IP_TTL | IP_RECVTTL => ttl = Some(value),
+                    IP_TOS | IP_RECVTOS => tos = Some(value),
+                    _ => {}
+                }
+            }
+            // Control messages are aligned like pointers
+            let align = mem::size_of::<usize>();
+            offset += (cmsg_header.cmsg_len + align - 1) & !(align - 1);
+        }
 
-        Ok(Message {
-            data: data_buffer[0..read_bytes as _]
-                .iter()
-                .map(|i| *i as u8)
-                .collect(),
+        Ok(MessageRef {
+            data: &buffer[0..read_bytes as _],
             origin_address,
             interface,
+            packet_info,
+            original_destination: None,
+            ttl,
+            tos,
+            received_at: None,
+            received_instant: None,
+            flags,
         })
     }
 
-    pub fn send(&self, buf: &[u8], interface: &Interface) -> io::Result<usize> {
+    pub(crate) fn send_to_socket(&self, buf: &[u8], interface: &Interface) -> io::Result<usize> {
+        self.send_to_address(buf, interface, self.multicast_address)
+    }
+
+    /// Without `sendmmsg`, sends the first datagram of the run alone
+    pub(crate) fn send_many_to_socket(
+        &self,
+        datagrams: &[(Vec<u8>, Interface)],
+        interface: &Interface,
+    ) -> io::Result<usize> {
+        self.send_to_socket(&datagrams[0].0, interface).map(|_| 1)
+    }
+
+    /// Sends to `destination` instead of the group, e.g. a broadcast address
+    pub(crate) fn send_to_address(
+        &self,
+        buf: &[u8],
+        interface: &Interface,
+        destination: SocketAddrV4,
+    ) -> io::Result<usize> {
+        let options = crate::SendOptions {
+            interface: *interface,
+            ..Default::default()
+        };
+        self.send_to_address_with(buf, destination, &options)
+    }
+
+    /// Sends with the parameters of `options` that go along with the datagram, the packet info
+    /// and flags, leaving the ones switching socket options to `send_with`
+    pub(crate) fn send_to_address_with(
+        &self,
+        buf: &[u8],
+        destination: SocketAddrV4,
+        options: &crate::SendOptions,
+    ) -> io::Result<usize> {
+        let interface = &options.interface;
         let pkt_info = match interface {
             Interface::Default => None,
             Interface::Ip(address) => Some(IN_PKTINFO {
@@ -355,12 +912,24 @@ impl MulticastSocket {
                 },
                 ipi_ifindex: 0,
             }),
-            Interface::Index(index) => self.interfaces.get(index).map(|address| IN_PKTINFO {
-                ipi_addr: IN_ADDR {
-                    S_un: to_s_addr(address),
-                },
-                ipi_ifindex: *index,
-            }),
+            // The source address picks the interface, looked up when the socket didn't join it
+            Interface::Index(index) => {
+                let address = match self.interfaces.get(index) {
+                    Some(address) => *address,
+                    None => interface_ips(*index)?.first().copied().ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::AddrNotAvailable,
+                            format!("interface {} has no IPv4 address", index),
+                        )
+                    })?,
+                };
+                Some(IN_PKTINFO {
+                    ipi_addr: IN_ADDR {
+                        S_un: to_s_addr(&address),
+                    },
+                    ipi_ifindex: *index,
+                })
+            }
         };
 
         let mut data = WSABUF {
@@ -398,7 +967,7 @@ impl MulticastSocket {
             }
         };
 
-        let destination = socket2::SockAddr::from(self.multicast_address);
+        let destination = socket2::SockAddr::from(destination);
         let destination_address = destination.as_ptr();
         let mut wsa_msg = WSAMSG {
             name: destination_address as *mut _,
@@ -409,12 +978,17 @@ impl MulticastSocket {
             dwFlags: 0,
         };
 
+        let flags = if options.dont_route {
+            sock::MSG_DONTROUTE as DWORD
+        } else {
+            0
+        };
         let mut sent_bytes = 0;
         let r = unsafe {
             (self.wsasendmsg)(
                 self.socket.as_raw_socket() as _,
                 &mut wsa_msg,
-                0,
+                flags,
                 &mut sent_bytes,
                 ptr::null_mut(),
                 None,
@@ -427,12 +1001,97 @@ impl MulticastSocket {
         Ok(sent_bytes as _)
     }
 
+    /// Sends to the group out of every interface, a single `WSASendMsg` for each
     pub fn broadcast(&self, buf: &[u8]) -> io::Result<()> {
         for interface in self.interfaces.values() {
             self.send(buf, &Interface::Ip(*interface))?;
         }
         Ok(())
     }
+
+    /// Whether the datagram was sent by this socket, seen through multicast loopback. Also true
+    /// for other local sockets sending from the port of the group, see `ignore_own_packets`.
+    pub(crate) fn is_own_packet(&self, origin_address: &SocketAddrV4) -> bool {
+        self.ignore_own_packets
+            && (origin_address.port() == self.local_port
+                || self
+                    .send_sockets
+                    .as_ref()
+                    .is_some_and(|s| s.owns_port(origin_address.port())))
+            && self.interfaces.values().any(|i| i == origin_address.ip())
+    }
+
+    /// MTU of an interface, to size datagrams and avoid fragmentation.
+    /// For `Interface::Default` the smallest MTU of the joined interfaces is returned,
+    /// as the kernel may pick any of them. Addresses must belong to a joined interface.
+    pub fn mtu(&self, interface: &Interface) -> io::Result<u32> {
+        match interface {
+            Interface::Default => {
+                let mut smallest: Option<u32> = None;
+                for index in self.interfaces.keys() {
+                    let mtu = interface_mtu(*index)?;
+                    smallest = Some(smallest.map_or(mtu, |s| s.min(mtu)));
+                }
+                smallest.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "socket joined no interface")
+                })
+            }
+            Interface::Ip(address) => match self.interfaces.iter().find(|(_, a)| *a == address) {
+                Some((index, _)) => interface_mtu(*index),
+                None => Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("{} is not a joined interface", address),
+                )),
+            },
+            Interface::Index(index) => interface_mtu(*index),
+        }
+    }
+
+    /// Addresses of the interfaces the socket joined on
+    pub fn interfaces(&self) -> Vec<Ipv4Addr> {
+        self.interfaces.values().copied().collect()
+    }
+
+    /// Group and port the socket listens to
+    pub fn multicast_address(&self) -> SocketAddrV4 {
+        self.multicast_address
+    }
+
+    /// The underlying socket, to apply options this crate doesn't model.
+    /// Changing options the crate relies on, like packet info, breaks `receive` and `send`.
+    pub fn as_socket(&self) -> &socket2::Socket {
+        &self.socket
+    }
+
+    /// Gives up the multicast handling and returns the underlying socket.
+    /// Memberships and socket options stay in place, as they belong to the descriptor.
+    pub fn into_inner(mut self) -> socket2::Socket {
+        self.memberships.forget();
+        self.socket
+    }
+}
+
+/// Registers the socket with event loops, such as `Poller::add` of the `polling` crate.
+/// Datagrams dropped by the filters of this crate, like own packets or duplicates, still wake up
+/// the loop, so read with `try_receive` or `drain` instead of blocking. With edge triggered
+/// interest only new datagrams wake up, so drain the socket before waiting again. Level
+/// triggered interest wakes up as long as any datagram is queued.
+impl AsSocket for MulticastSocket {
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        unsafe { BorrowedSocket::borrow_raw(self.socket.as_raw_socket()) }
+    }
+}
+
+impl AsRawSocket for MulticastSocket {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.socket.as_raw_socket()
+    }
+}
+
+impl IntoRawSocket for MulticastSocket {
+    fn into_raw_socket(self) -> RawSocket {
+        self.into_inner().into_raw_socket()
+    }
 }
 
 fn to_s_addr(addr: &Ipv4Addr) -> in_addr_S_un {